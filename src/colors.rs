@@ -0,0 +1,225 @@
+use std::collections::HashSet;
+
+/// Named colours seen in OSM `*:colour` tags: the CSS basics plus common material-ish words.
+const NAMED_COLORS: &[(&str, u32)] = &[
+    ("black", 0x000000),
+    ("white", 0xFFFFFF),
+    ("gray", 0x808080),
+    ("grey", 0x808080),
+    ("lightgray", 0xD3D3D3),
+    ("lightgrey", 0xD3D3D3),
+    ("darkgray", 0xA9A9A9),
+    ("darkgrey", 0xA9A9A9),
+    ("silver", 0xC0C0C0),
+    ("red", 0xFF0000),
+    ("darkred", 0x8B0000),
+    ("maroon", 0x800000),
+    ("brown", 0xA52A2A),
+    ("orange", 0xFFA500),
+    ("yellow", 0xFFFF00),
+    ("gold", 0xFFD700),
+    ("green", 0x008000),
+    ("darkgreen", 0x006400),
+    ("olive", 0x808000),
+    ("blue", 0x0000FF),
+    ("navy", 0x000080),
+    ("teal", 0x008080),
+    ("purple", 0x800080),
+    ("pink", 0xFFC0CB),
+    ("beige", 0xF5F5DC),
+    ("tan", 0xD2B48C),
+    ("ivory", 0xFFFFF0),
+    ("khaki", 0xF0E68C),
+    ("salmon", 0xFA8072),
+    ("wheat", 0xF5DEB3),
+    // not CSS, but common in OSM
+    ("sandstone", 0xC8A670),
+    ("terracotta", 0xE2725B),
+    ("cream", 0xFFFDD0),
+    ("brick", 0xA0522D),
+    ("ochre", 0xCC7722),
+    ("sand", 0xC2B280),
+    ("anthracite", 0x383E42),
+    ("copper", 0xB87333),
+    ("rust", 0xB7410E),
+    ("limestone", 0xD8D3C3),
+    ("lightyellow", 0xFFFFE0),
+    ("lightbrown", 0xB5651D),
+    ("darkbrown", 0x5C4033),
+];
+
+/// Approximate RGB for frequently tagged RAL classic codes.
+const RAL_COLORS: &[(u16, u32)] = &[
+    (1013, 0xE3D9C6), // oyster white
+    (1015, 0xE6D2B5), // light ivory
+    (3000, 0xAF2B1E), // flame red
+    (3009, 0x642424), // oxide red
+    (5010, 0x0E294B), // gentian blue
+    (6005, 0x2F4538), // moss green
+    (7015, 0x434750), // slate grey
+    (7016, 0x293133), // anthracite grey
+    (7024, 0x474A51), // graphite grey
+    (7035, 0xD7D7D7), // light grey
+    (7040, 0x9DA1AA), // window grey
+    (8017, 0x45322E), // chocolate brown
+    (9001, 0xFDF4E3), // cream
+    (9002, 0xE7EBDA), // grey white
+    (9005, 0x0A0A0A), // jet black
+    (9006, 0xA5A5A5), // white aluminium
+    (9007, 0x8F8F8F), // grey aluminium
+    (9010, 0xFFFFFF), // pure white
+    (9016, 0xF6F6F6), // traffic white
+];
+
+/// Typical colour of a `building:material` value.
+const MATERIAL_COLORS: &[(&str, u32)] = &[
+    ("brick", 0xA0522D),
+    ("concrete", 0xB4B4AA),
+    ("stone", 0xA8A496),
+    ("sandstone", 0xC8A670),
+    ("limestone", 0xD8D3C3),
+    ("wood", 0x8B6A45),
+    ("timber_framing", 0xE8E0D0),
+    ("glass", 0x7FA8C0),
+    ("metal", 0x9AA0A6),
+    ("steel", 0x9AA0A6),
+    ("plaster", 0xECE6D8),
+    ("cement_block", 0xA9A9A0),
+];
+
+/// Colour used when nothing recognizable is tagged.
+pub const DEFAULT_COLOR: u32 = 0xD0CCC4;
+
+/// Parses an OSM colour value: `#rrggbb`, `#rgb`, a colour name or a RAL code (`RAL 9010`).
+pub fn parse_color(value: &str) -> Option<u32> {
+    let value = value.trim().to_ascii_lowercase();
+    if let Some(hex) = value.strip_prefix('#') {
+        return match hex.len() {
+            6 => u32::from_str_radix(hex, 16).ok(),
+            3 => {
+                let short = u32::from_str_radix(hex, 16).ok()?;
+                let (r,g,b) = ((short >> 8) & 0xF, (short >> 4) & 0xF, short & 0xF);
+                Some((r * 0x11) << 16 | (g * 0x11) << 8 | (b * 0x11))
+            }
+            _ => None
+        };
+    }
+    if let Some(code) = value.strip_prefix("ral") {
+        let code: u16 = code.trim().parse().ok()?;
+        return RAL_COLORS.iter().find(|(ral,_)| *ral == code).map(|(_,rgb)| *rgb);
+    }
+    // "light grey", "dark-brown"
+    let compact: String = value.chars().filter(|c| !matches!(c, ' ' | '-' | '_')).collect();
+    NAMED_COLORS.iter().find(|(name,_)| *name == compact).map(|(_,rgb)| *rgb)
+}
+
+pub fn material_color(value: &str) -> Option<u32> {
+    let value = value.trim().to_ascii_lowercase();
+    MATERIAL_COLORS.iter().find(|(name,_)| *name == value).map(|(_,rgb)| *rgb)
+}
+
+/// Picks a wall colour from the colour tag, falling back to the material and then a default.
+/// Unrecognized colour values are reported once each, through `unknown`.
+pub fn resolve_color(color: Option<&str>, material: Option<&str>, unknown: &mut HashSet<String>) -> u32 {
+    if let Some(color) = color {
+        if let Some(rgb) = parse_color(color) {
+            return rgb;
+        }
+        if unknown.insert(color.to_owned()) {
+            println!("> unrecognized colour {:?}",color);
+        }
+    }
+    material.and_then(material_color).unwrap_or(DEFAULT_COLOR)
+}
+
+/// Palette used for --palette-index when no --palette file is given.
+const DEFAULT_PALETTE: &[u32] = &[
+    0xFFFFFF, // white
+    0xD0CCC4, // light grey / default
+    0x808080, // grey
+    0x383E42, // anthracite
+    0x000000, // black
+    0xFF0000, // red
+    0xA0522D, // brick
+    0xE2725B, // terracotta
+    0xFFA500, // orange
+    0xFFFF00, // yellow
+    0xFFFDD0, // cream
+    0xC8A670, // sandstone
+    0x8B6A45, // wood
+    0x008000, // green
+    0x0000FF, // blue
+    0x7FA8C0, // glass
+];
+
+/// Loads a palette of up to 256 colours, one per line in any form `parse_color` accepts,
+/// or the built-in one without a file.
+pub fn load_palette(path: Option<&str>) -> Vec<u32> {
+    let Some(path) = path else {
+        return DEFAULT_PALETTE.to_vec();
+    };
+    let text = std::fs::read_to_string(path).unwrap_or_else(|err| panic!("failed to read palette {}: {}",path,err));
+    let palette: Vec<u32> = text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("//"))
+        .map(|line| parse_color(line).unwrap_or_else(|| panic!("bad colour {:?} in palette {}",line,path)))
+        .collect();
+    assert!(!palette.is_empty() && palette.len() <= 256, "palette {} must have 1 to 256 colours",path);
+    palette
+}
+
+/// Index of the palette entry closest to `rgb`, by squared distance in RGB.
+pub fn nearest_palette_index(rgb: u32, palette: &[u32]) -> u8 {
+    let channels = |c: u32| [((c >> 16) & 0xFF) as i32, ((c >> 8) & 0xFF) as i32, (c & 0xFF) as i32];
+    let target = channels(rgb);
+    let distance = |c: u32| channels(c).iter().zip(target.iter()).map(|(a,b)| (a - b) * (a - b)).sum::<i32>();
+    palette.iter().enumerate().min_by_key(|(_,c)| distance(**c)).map(|(i,_)| i as u8).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_forms() {
+        assert_eq!(parse_color("#1a2B3c"), Some(0x1A2B3C));
+        assert_eq!(parse_color("#f80"), Some(0xFF8800));
+        assert_eq!(parse_color(" Red "), Some(0xFF0000));
+        assert_eq!(parse_color("light grey"), Some(0xD3D3D3));
+        assert_eq!(parse_color("#12345"), None);
+    }
+
+    #[test]
+    fn osm_words_ral_codes_and_material_fallback() {
+        let mut unknown = HashSet::new();
+        assert_eq!(resolve_color(Some("sandstone"), None, &mut unknown), 0xC8A670);
+        assert_eq!(resolve_color(Some("RAL 9010"), None, &mut unknown), 0xFFFFFF);
+        assert_eq!(resolve_color(Some("ral9005"), Some("brick"), &mut unknown), 0x0A0A0A);
+        assert!(unknown.is_empty());
+
+        // an unknown colour falls back to the material, and is only noted once
+        assert_eq!(resolve_color(Some("mauve-ish"), Some("brick"), &mut unknown), 0xA0522D);
+        assert_eq!(resolve_color(Some("mauve-ish"), Some("Concrete"), &mut unknown), 0xB4B4AA);
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(resolve_color(Some("mauve-ish"), None, &mut unknown), DEFAULT_COLOR);
+        assert_eq!(resolve_color(None, Some("unobtainium"), &mut unknown), DEFAULT_COLOR);
+    }
+
+    #[test]
+    fn nearest_palette_entry() {
+        let palette = load_palette(None);
+        assert_eq!(palette[nearest_palette_index(0xFF0000, &palette) as usize], 0xFF0000);
+        // a darker red is still nearest red
+        assert_eq!(palette[nearest_palette_index(0xE01010, &palette) as usize], 0xFF0000);
+        assert_eq!(nearest_palette_index(0x123456, &[0x000000, 0xFFFFFF]), 0);
+    }
+
+    #[test]
+    fn palette_from_a_file() {
+        let path = std::env::temp_dir().join(format!("cartographer-palette-{}.txt",std::process::id()));
+        std::fs::write(&path, "// roofs\nwhite\n\n#ff0000\nRAL 7016\n").unwrap();
+        let palette = load_palette(path.to_str());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(palette, [0xFFFFFF, 0xFF0000, 0x293133]);
+    }
+}
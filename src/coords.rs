@@ -0,0 +1,70 @@
+use crate::region::Region;
+
+// Local map coordinates are meters from the elevation map's top-left corner, in the region's
+// UTM zone, with x pointing east and y pointing south.
+
+/// Converts (lat, lon) to local map coordinates. The point is projected into the region's zone
+/// even if it lies in a neighboring one, so features near a zone boundary still line up with the map.
+pub fn latlon_to_local(region: &Region, lat: f64, lon: f64) -> (f32,f32) {
+    // across the date line, 179.9° is next to zone 1 rather than 357° away from it
+    let central = region.coord.zone_number as f64 * 6.0 - 183.0;
+    let lon = central + (lon - central + 180.0).rem_euclid(360.0) - 180.0;
+    let (northing,easting,_) = utm::to_utm_wgs84(lat, lon, region.coord.zone_number);
+    region.utm_to_local(easting, northing)
+}
+
+/// Converts local map coordinates back to (lat, lon).
+pub fn local_to_latlon(region: &Region, x: f32, y: f32) -> (f64,f64) {
+    // only the hemisphere matters to the conversion, so any band on the right side of the equator will do
+    let zone_letter = if region.coord.northern { 'N' } else { 'M' };
+
+    let easting = region.coord.easting + x as f64;
+    let northing = region.coord.northing - y as f64;
+    utm::wsg84_utm_to_lat_lon(easting, northing, region.coord.zone_number, zone_letter).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::region::UTMCoord;
+
+    fn round_trip(lat: f64, lon: f64, zone_number: u8) {
+        let (northing,easting,_) = utm::to_utm_wgs84(lat, if lon > 179.0 { lon - 360.0 } else { lon }, zone_number);
+        let northern = lat >= 0.0;
+        // map corner a little north-west of the point
+        let coord = UTMCoord { zone_number, northern, easting: (easting - 300.0).floor(), northing: (northing + 200.0).floor() };
+        let region = Region::from_fn("coords", coord, (1024, 1024), |_,_| 0.0);
+
+        let (x,y) = latlon_to_local(&region, lat, lon);
+        assert!(region.contains(x, y), "({x},{y}) outside the region");
+        let (back_lat,back_lon) = local_to_latlon(&region, x, y);
+        assert!((back_lat - lat).abs() < 1e-5, "latitude {lat} came back as {back_lat}");
+        assert!(((back_lon - lon + 180.0).rem_euclid(360.0) - 180.0).abs() < 1e-5, "longitude {lon} came back as {back_lon}");
+    }
+
+    #[test]
+    fn round_trips_in_the_northern_hemisphere() {
+        round_trip(47.6062, -122.3321, 10);
+        round_trip(45.1, 15.2, 33);
+    }
+
+    #[test]
+    fn round_trips_in_the_southern_hemisphere() {
+        round_trip(-33.8688, 151.2093, 56);
+        round_trip(-0.01, 151.2093, 56);
+    }
+
+    #[test]
+    fn point_over_the_zone_boundary_uses_the_region_zone() {
+        // 12.5E is in zone 33, but a zone 32 map still places it east of its corner, not half a world away
+        round_trip(45.1, 12.5, 32);
+    }
+
+    #[test]
+    fn round_trips_across_the_date_line() {
+        // zone 1 starts at 180°, so points just west of it are given as +179.x
+        round_trip(-16.5, 179.999, 1);
+        round_trip(-16.5, -179.999, 1);
+        round_trip(65.0, 179.999, 1);
+    }
+}
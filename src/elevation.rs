@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use baby_shark::{decimation::{edge_decimation::ConstantErrorDecimationCriteria, prelude::EdgeDecimator}, exports::nalgebra::Vector3, mesh::{corner_table::table::CornerTable, traits::Mesh}};
 
-use crate::{region::TileNeighbors, Buffer};
+use crate::{region::TileNeighbors, Buffer, Endian};
 
 fn make_grid(width: usize, height: usize, scale: f64, mut f: impl FnMut(usize,usize)->f64) -> CornerTable<f64> {
     let mut vertices = Vec::new();
@@ -14,7 +14,7 @@ fn make_grid(width: usize, height: usize, scale: f64, mut f: impl FnMut(usize,us
             vertices.push(Vector3::new(x as f64, y as f64, z) * scale);
             if x < width - 1 && y < height - 1 {
                 let index = y*width + x;
-                indices.push(index+0);
+                indices.push(index);
                 indices.push(index+1);
                 indices.push(index+width);
 
@@ -28,50 +28,313 @@ fn make_grid(width: usize, height: usize, scale: f64, mut f: impl FnMut(usize,us
     CornerTable::from_vertices_and_indices(&vertices, &indices)
 }
 
-pub fn build_terrain_mesh(tile: &[f32], width: usize, height: usize, neighbors: TileNeighbors) -> Buffer {
+/// Indices of the points a Douglas-Peucker pass keeps along a height profile (one sample per meter),
+/// measuring vertical error. Both ends are always kept. Only depends on the profile, so two tiles
+/// sharing an edge keep the same points along it.
+pub fn simplify_profile(z: &[f64], max_error: f64) -> Vec<usize> {
+    let mut keep = vec![false; z.len()];
+    keep[0] = true;
+    keep[z.len()-1] = true;
+    let mut spans = vec![(0, z.len()-1)];
+    while let Some((start,end)) = spans.pop() {
+        let mut worst = None;
+        let mut worst_error = max_error;
+        for i in start+1..end {
+            let t = (i - start) as f64 / (end - start) as f64;
+            let error = (z[i] - (z[start] + (z[end] - z[start]) * t)).abs();
+            if error > worst_error {
+                worst = Some(i);
+                worst_error = error;
+            }
+        }
+        if let Some(i) = worst {
+            keep[i] = true;
+            spans.push((start,i));
+            spans.push((i,end));
+        }
+    }
+    (0..z.len()).filter(|i| keep[*i]).collect()
+}
+
+/// Like `make_grid`, but the outer ring only has the points `simplify_profile` keeps along each side,
+/// zipped to the full grid one row/column in. Falls back to `make_grid` for grids too small to have an inside.
+fn make_grid_simplified_boundary(width: usize, height: usize, scale: f64, max_error: f64, mut f: impl FnMut(usize,usize)->f64) -> CornerTable<f64> {
+    if width < 4 || height < 4 {
+        return make_grid(width, height, scale, f);
+    }
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+
+    // the inside, full resolution
+    let inner_width = width - 2;
+    let inner = |x: usize, y: usize| (y - 1) * inner_width + (x - 1);
+    for y in 1..height-1 {
+        for x in 1..width-1 {
+            vertices.push(Vector3::new(x as f64, y as f64, f(x,y)) * scale);
+            if x < width - 2 && y < height - 2 {
+                let index = inner(x,y);
+                indices.push(index);
+                indices.push(index+1);
+                indices.push(index+inner_width);
+
+                indices.push(index+1);
+                indices.push(index+inner_width+1);
+                indices.push(index+inner_width);
+            }
+        }
+    }
+
+    // sides are simplified in increasing x/y, the same direction the neighbor sees them in
+    let mut side = |points: Vec<(usize,usize)>| {
+        let z: Vec<f64> = points.iter().map(|(x,y)| f(*x,*y)).collect();
+        simplify_profile(&z, max_error).into_iter().map(|i| (points[i], z[i])).collect::<Vec<_>>()
+    };
+    let top = side((0..width).map(|x| (x,0)).collect());
+    let right = side((0..height).map(|y| (width-1,y)).collect());
+    let bottom = side((0..width).map(|x| (x,height-1)).collect());
+    let left = side((0..height).map(|y| (0,y)).collect());
+
+    // both rings go round from the top left corner, with a position along them of side + fraction,
+    // so the sides of one line up with the sides of the other
+    let along = |x: usize, y: usize, min: usize, max_x: usize, max_y: usize| {
+        let (span_x, span_y) = ((max_x - min) as f64, (max_y - min) as f64);
+        if y == min && x < max_x {
+            (x - min) as f64 / span_x
+        } else if x == max_x && y < max_y {
+            1.0 + (y - min) as f64 / span_y
+        } else if y == max_y && x > min {
+            2.0 + (max_x - x) as f64 / span_x
+        } else {
+            3.0 + (max_y - y) as f64 / span_y
+        }
+    };
+    let mut outer = Vec::new();
+    let sides = [top, right, bottom.into_iter().rev().collect(), left.into_iter().rev().collect::<Vec<_>>()];
+    for side in sides {
+        // the last point is the next side's first
+        for ((x,y),z) in &side[..side.len()-1] {
+            outer.push((vertices.len(), along(*x, *y, 0, width-1, height-1)));
+            vertices.push(Vector3::new(*x as f64, *y as f64, *z) * scale);
+        }
+    }
+    let mut ring = Vec::new();
+    ring.extend((1..width-2).map(|x| (x,1)));
+    ring.extend((1..height-2).map(|y| (width-2,y)));
+    ring.extend((2..width-1).rev().map(|x| (x,height-2)));
+    ring.extend((2..height-1).rev().map(|y| (1,y)));
+    let inner_ring: Vec<(usize,f64)> = ring.into_iter().map(|(x,y)| (inner(x,y), along(x, y, 1, width-2, height-2))).collect();
+
+    let (mut i, mut j) = (0, 0);
+    let next = |ring: &[(usize,f64)], i: usize| if i + 1 >= ring.len() { (ring[0].0, 4.0) } else { ring[i+1] };
+    while i < outer.len() || j < inner_ring.len() {
+        let (next_outer, outer_pos) = next(&outer, i);
+        let (next_inner, inner_pos) = next(&inner_ring, j);
+        if j == inner_ring.len() || (i < outer.len() && outer_pos <= inner_pos) {
+            indices.extend([outer[i].0, next_outer, inner_ring[j % inner_ring.len()].0]);
+            i += 1;
+        } else {
+            indices.extend([outer[i % outer.len()].0, next_inner, inner_ring[j].0]);
+            j += 1;
+        }
+    }
+
+    CornerTable::from_vertices_and_indices(&vertices, &indices)
+}
+
+/// How terrain tiles are written out.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ElevationFormat {
+    /// Decimated triangle mesh
+    Mesh,
+    /// Downsampled, quantized heightmap
+    Raster
+}
+
+/// Samples the tile, reaching into the neighbors for the extra row/column past the edge.
+/// A missing neighbor, or one that's smaller than this tile (the edge of another region), repeats this tile's edge sample.
+fn sample_with_neighbors(tile: &[f32], width: usize, height: usize, neighbors: &TileNeighbors, x: usize, y: usize) -> f32 {
+    let own = |x: usize, y: usize| tile[y.min(height - 1) * width + x.min(width - 1)];
+    if x >= width && y >= height {
+        neighbors.corner.as_ref().map(|neighbor| neighbor.get_checked(0,0))
+            // merged regions can border on two sides without a region diagonally across
+            .or_else(|| neighbors.next_x.as_ref().map(|neighbor| neighbor.get_checked(0,height-1)))
+            .or_else(|| neighbors.next_y.as_ref().map(|neighbor| neighbor.get_checked(width-1,0)))
+            .flatten()
+            .unwrap_or_else(|| own(x, y))
+    } else if x >= width {
+        neighbors.next_x.as_ref().and_then(|neighbor| neighbor.get_checked(0,y)).unwrap_or_else(|| own(x, y))
+    } else if y >= height {
+        neighbors.next_y.as_ref().and_then(|neighbor| neighbor.get_checked(x,0)).unwrap_or_else(|| own(x, y))
+    } else {
+        tile[y * width + x]
+    }
+}
+
+/// Interleaves the bits of x and y, so sorting by it keeps nearby points close together.
+pub fn morton_code(x: u16, y: u16) -> u32 {
+    fn spread(v: u16) -> u32 {
+        let mut v = v as u32;
+        v = (v | (v << 8)) & 0x00FF00FF;
+        v = (v | (v << 4)) & 0x0F0F0F0F;
+        v = (v | (v << 2)) & 0x33333333;
+        v = (v | (v << 1)) & 0x55555555;
+        v
+    }
+    spread(x) | (spread(y) << 1)
+}
+
+/// Vertex and face counts of a tile before and after decimation.
+#[derive(Clone, Copy, Debug)]
+pub struct MeshStats {
+    pub initial_vertices: usize,
+    pub initial_faces: usize,
+    pub vertices: usize,
+    pub faces: usize,
+    /// Largest vertical distance between the decimated mesh and a grid sample
+    pub max_error: f64,
+}
+
+/// Tiles with more faces than this are getting close to the 16 bit index limit.
+const FACE_WARNING: usize = 55_000;
+
+/// Prints the spread of face counts across tiles, and which tiles came close to the limit.
+pub fn print_mesh_summary(stats: &[(usize, MeshStats)]) {
+    if stats.is_empty() {
+        return;
+    }
+    let min_faces = stats.iter().map(|(_,s)| s.faces).min().unwrap();
+    let max_faces = stats.iter().map(|(_,s)| s.faces).max().unwrap();
+    let mean_faces = stats.iter().map(|(_,s)| s.faces).sum::<usize>() as f64 / stats.len() as f64;
+    let max_error = stats.iter().map(|(_,s)| s.max_error).fold(0.0, f64::max);
+    let initial_vertices = stats.iter().map(|(_,s)| s.initial_vertices).sum::<usize>();
+    let vertices = stats.iter().map(|(_,s)| s.vertices).sum::<usize>();
+    let initial_faces = stats.iter().map(|(_,s)| s.initial_faces).sum::<usize>();
+    let faces = stats.iter().map(|(_,s)| s.faces).sum::<usize>();
+    println!("> decimated {} tiles: {} -> {} vertices, {} -> {} faces",stats.len(),initial_vertices,vertices,initial_faces,faces);
+    println!("> faces per tile: min {} / max {} / mean {:.0}, worst error {:.2}m",min_faces,max_faces,mean_faces,max_error);
+
+    let mut crowded: Vec<_> = stats.iter().filter(|(_,s)| s.faces > FACE_WARNING).collect();
+    crowded.sort_by_key(|(index,_)| *index);
+    for (index,s) in crowded {
+        println!("> tile {} is near the face limit: {} faces",index,s.faces);
+    }
+}
+
+/// Largest vertical distance between the mesh and `sample` at the grid points it covers.
+fn max_vertical_error(mesh: &CornerTable<f64>, sample: impl Fn(usize,usize)->f64) -> f64 {
+    let mut max_error = 0.0f64;
+    for face in mesh.faces() {
+        let (a,b,c) = mesh.face_vertices(&face);
+        let (a,b,c) = (mesh.vertex_position(&a), mesh.vertex_position(&b), mesh.vertex_position(&c));
+        let denom = (b.y - c.y) * (a.x - c.x) + (c.x - b.x) * (a.y - c.y);
+        if denom.abs() < 1e-12 {
+            continue;
+        }
+        let min_x = a.x.min(b.x).min(c.x).ceil().max(0.0) as usize;
+        let max_x = a.x.max(b.x).max(c.x).floor() as usize;
+        let min_y = a.y.min(b.y).min(c.y).ceil().max(0.0) as usize;
+        let max_y = a.y.max(b.y).max(c.y).floor() as usize;
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                let (px,py) = (x as f64, y as f64);
+                let wa = ((b.y - c.y) * (px - c.x) + (c.x - b.x) * (py - c.y)) / denom;
+                let wb = ((c.y - a.y) * (px - c.x) + (a.x - c.x) * (py - c.y)) / denom;
+                let wc = 1.0 - wa - wb;
+                if wa < -1e-9 || wb < -1e-9 || wc < -1e-9 {
+                    continue;
+                }
+                let z = wa * a.z + wb * b.z + wc * c.z;
+                max_error = max_error.max((z - sample(x,y)).abs());
+            }
+        }
+    }
+    max_error
+}
+
+/// How build_terrain_mesh meshes and writes a tile.
+#[derive(Clone, Copy, Debug)]
+pub struct MeshOptions {
+    /// Vertical error (meters) the decimator may introduce
+    pub max_error: f64,
+    /// (min, range) to quantize z against instead of the tile's own extents
+    pub z_range: Option<(f32,f32)>,
+    /// Elevation the written min_z is measured from, rather than sea level
+    pub datum: f32,
+    /// Write vertices in Z-order of their quantized x/y rather than mesh order
+    pub morton_order: bool,
+    /// Give a side with no neighbor the extra row/column anyway, repeating the edge samples
+    pub extend_edges: bool,
+    /// Thin out the tile's edges too, the same way on both tiles sharing one
+    pub decimate_boundary: bool,
+    /// Output tile size in samples, which the decimator's face target is scaled by
+    pub tile_size: usize,
+    /// Byte order of the written buffer
+    pub endian: Endian,
+}
+
+/// How build_terrain_raster samples and writes a tile.
+#[derive(Clone, Copy, Debug)]
+pub struct RasterOptions {
+    /// Keep every Nth sample
+    pub downsample: usize,
+    /// (min, range) to quantize z against instead of the tile's own extents
+    pub z_range: Option<(f32,f32)>,
+    /// Elevation the written min_z is measured from, rather than sea level
+    pub datum: f32,
+    /// Give a side with no neighbor the extra row/column anyway, repeating the edge samples
+    pub extend_edges: bool,
+    /// Byte order of the written buffer
+    pub endian: Endian,
+}
+
+/// Meshes and decimates the tile, returning the written buffer and how far it was decimated.
+/// Layout: min_z, range_z, extent x, extent y, vertex count, quantized vertices and normals, face count, indices.
+/// Positions are quantized across the tile's own extent (its largest x/y), so short edge tiles use the full u16 range too.
+pub fn build_terrain_mesh(tile: &[f32], width: usize, height: usize, neighbors: TileNeighbors, options: &MeshOptions) -> (Buffer, MeshStats) {
     if tile.len() != width*height {
         panic!("tile sized wrongly")
     }
+    let MeshOptions { max_error, z_range, datum, morton_order, extend_edges, decimate_boundary, tile_size, endian } = *options;
 
     let scale = 1.0;
-    let max_error = 1.0;
 
     let criteria = ConstantErrorDecimationCriteria::new(scale * max_error);
     let mut decimator = EdgeDecimator::new()
         .decimation_criteria(criteria)
-        .min_faces_count(Some(10_000))
+        // scaled down for tiles smaller than 512
+        .min_faces_count(Some(10_000 * tile_size * tile_size / (512 * 512)))
         .keep_boundary(true);
 
-    let fixed_width = if width == 512 { width + 1 } else { width };
-    let fixed_height = if height == 512 { height + 1 } else { height };
-
-    let mut mesh = make_grid(fixed_width, fixed_height, scale, |x,y| {
-        if x >= width && y >= width {
-            let neighbor = neighbors.corner.as_ref().unwrap();
-            neighbor.get(0,0) as f64
-        } else if x >= width {
-            let neighbor = neighbors.next_x.as_ref().unwrap();
-            neighbor.get(0,y) as f64
-        } else if y >= height {
-            let neighbor = neighbors.next_y.as_ref().unwrap();
-            neighbor.get(x,0) as f64
-        } else {
-            let e = tile[y * width + x];
-            e as f64
-        }
-    });
+    let fixed_width = if neighbors.next_x.is_some() || extend_edges { width + 1 } else { width };
+    let fixed_height = if neighbors.next_y.is_some() || extend_edges { height + 1 } else { height };
+
+    let sample = |x,y| sample_with_neighbors(tile, width, height, &neighbors, x, y) as f64;
+    // the decimator never touches the boundary, so it's simplified up front where both neighbors agree on it
+    let mut mesh = if decimate_boundary {
+        make_grid_simplified_boundary(fixed_width, fixed_height, scale, scale * max_error, sample)
+    } else {
+        make_grid(fixed_width, fixed_height, scale, sample)
+    };
 
-    println!("initial: {} / {}",mesh.vertices().count(),mesh.faces().count());
+    let initial_vertices = mesh.vertices().count();
+    let initial_faces = mesh.faces().count();
     //StlWriter::new().write_stl_to_file(&mesh, Path::new("C:\\Users\\cogg\\Documents\\init.stl")).unwrap();
     decimator.decimate(&mut mesh);
-    println!("decimated: {} / {}",mesh.vertices().count(),mesh.faces().count());
+
+    let stats = MeshStats {
+        initial_vertices,
+        initial_faces,
+        vertices: mesh.vertices().count(),
+        faces: mesh.faces().count(),
+        max_error: max_vertical_error(&mesh, sample),
+    };
 
     assert!(mesh.vertices().count() < 60_000);
     assert!(mesh.faces().count() < 60_000);
 
     //StlWriter::new().write_stl_to_file(&mesh, Path::new("C:\\Users\\cogg\\Documents\\dec1.stl")).unwrap();
 
-    let mut buffer = Buffer::default();
+    let mut buffer = Buffer::new(endian);
 
     let mut min_z = 1.0f64/0.0;
     let mut max_z = -1.0f64/0.0;
@@ -81,35 +344,39 @@ pub fn build_terrain_mesh(tile: &[f32], width: usize, height: usize, neighbors:
         min_z = min_z.min(pos.z);
         max_z = max_z.max(pos.z);
     }
-    let range_z = max_z - min_z;
+    let mut range_z = max_z - min_z;
+    if let Some((global_min,global_range)) = z_range {
+        min_z = global_min as f64;
+        range_z = global_range as f64;
+    }
+
+    let extent_x = (fixed_width - 1).max(1);
+    let extent_y = (fixed_height - 1).max(1);
 
-    buffer.write_float(min_z as f32);
+    buffer.write_float(min_z as f32 - datum);
     buffer.write_float(range_z as f32);
+    buffer.write_short(extent_x as u16);
+    buffer.write_short(extent_y as u16);
     buffer.write_short(mesh.vertices().count() as u16);
 
+    let mut vertices = mesh.vertices().map(|i| {
+        let pos = mesh.vertex_position(&i);
+        let x = pos.x / extent_x as f64 * 65535.0;
+        let y = pos.y / extent_y as f64 * 65535.0;
+        let z = (pos.z - min_z) / range_z * 65535.0;
+        (i, x as u16, y as u16, z as u16)
+    }).collect::<Vec<_>>();
+    if morton_order {
+        vertices.sort_by_key(|(_,x,y,_)| morton_code(*x, *y));
+    }
+
     let mut map = HashMap::<usize,u16>::new();
-    let mut next_vert_index = 0;
-    for i in mesh.vertices() {
-        map.insert(i, next_vert_index);
-        next_vert_index += 1;
-        {
-            let pos = mesh.vertex_position(&i);
-            let x = pos.x / 512.0 * 65535.0;
-            let y = pos.y / 512.0 * 65535.0;
-            let z = (pos.z - min_z) / range_z * 65535.0;
-            buffer.write_short(x as u16);
-            buffer.write_short(y as u16);
-            buffer.write_short(z as u16);
-        }
-        {
-            let normal = mesh.vertex_normal(&i).unwrap();
-            let x = normal.x * 127.0;
-            let y = normal.y * 127.0;
-            let z = normal.z * 127.0;
-            buffer.write_byte(x as i8 as u8);
-            buffer.write_byte(y as i8 as u8);
-            buffer.write_byte(z as i8 as u8);
-        }
+    for (next_vert_index,(i,x,y,z)) in vertices.into_iter().enumerate() {
+        map.insert(i, next_vert_index as u16);
+        buffer.write_short(x);
+        buffer.write_short(y);
+        buffer.write_short(z);
+        buffer.write_unit_vec3_i8(mesh.vertex_normal(&i).unwrap().cast());
     }
 
     buffer.write_short(mesh.faces().count() as u16);
@@ -122,5 +389,224 @@ pub fn build_terrain_mesh(tile: &[f32], width: usize, height: usize, neighbors:
         buffer.write_short(a);
         buffer.write_short(c);
     }
+    (buffer, stats)
+}
+
+/// Writes the tile as a raster, keeping every `downsample`th sample.
+/// Layout: min_z, range_z, sample width, sample height, then row-major u16 heights.
+pub fn build_terrain_raster(tile: &[f32], width: usize, height: usize, neighbors: TileNeighbors, options: &RasterOptions) -> Buffer {
+    if tile.len() != width*height {
+        panic!("tile sized wrongly")
+    }
+    let RasterOptions { downsample, z_range, datum, extend_edges, endian } = *options;
+    let downsample = downsample.max(1);
+
+    let fixed_width = if neighbors.next_x.is_some() || extend_edges { width + 1 } else { width };
+    let fixed_height = if neighbors.next_y.is_some() || extend_edges { height + 1 } else { height };
+
+    let out_width = (fixed_width - 1) / downsample + 1;
+    let out_height = (fixed_height - 1) / downsample + 1;
+
+    let mut samples = Vec::with_capacity(out_width * out_height);
+    for y in 0..out_height {
+        for x in 0..out_width {
+            samples.push(sample_with_neighbors(tile, width, height, &neighbors, x * downsample, y * downsample));
+        }
+    }
+
+    let mut min_z = 1.0f32/0.0;
+    let mut max_z = -1.0f32/0.0;
+    for z in &samples {
+        min_z = min_z.min(*z);
+        max_z = max_z.max(*z);
+    }
+    let mut range_z = max_z - min_z;
+    if let Some((global_min,global_range)) = z_range {
+        min_z = global_min;
+        range_z = global_range;
+    }
+
+    let mut buffer = Buffer::new(endian);
+    buffer.write_float(min_z - datum);
+    buffer.write_float(range_z);
+    buffer.write_short(out_width as u16);
+    buffer.write_short(out_height as u16);
+    for z in samples {
+        let q = if range_z > 0.0 { (z - min_z) / range_z * 65535.0 } else { 0.0 };
+        buffer.write_short(q.round() as u16);
+    }
     buffer
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_neighbors() -> TileNeighbors {
+        TileNeighbors { next_x: None, next_y: None, corner: None }
+    }
+
+    fn mesh_options(max_error: f64) -> MeshOptions {
+        MeshOptions { max_error, z_range: None, datum: 0.0, morton_order: false, extend_edges: false, decimate_boundary: false, tile_size: 512, endian: Endian::Le }
+    }
+
+    #[test]
+    fn raster_size_and_dequantization() {
+        let (width, height) = (5, 3);
+        let tile: Vec<f32> = (0..width * height).map(|i| 100.0 + i as f32 * 1.5).collect();
+        let buffer = build_terrain_raster(&tile, width, height, no_neighbors(), &RasterOptions { downsample: 2, z_range: None, datum: 0.0, extend_edges: false, endian: Endian::Le });
+
+        let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+        let short = |at: usize| u16::from_le_bytes([buffer.bytes[at], buffer.bytes[at + 1]]);
+        let (base, range) = (float(0), float(4));
+        let (out_width, out_height) = (short(8) as usize, short(10) as usize);
+        // every other sample of 5x3 keeps columns 0, 2, 4 and rows 0, 2
+        assert_eq!((out_width, out_height), (3, 2));
+        assert_eq!(buffer.bytes.len(), 12 + out_width * out_height * 2);
+        assert_eq!(base, 100.0);
+        for y in 0..out_height {
+            for x in 0..out_width {
+                let z = base + short(12 + (y * out_width + x) * 2) as f32 / 65535.0 * range;
+                let expected = tile[y * 2 * width + x * 2];
+                assert!((z - expected).abs() <= range / 65535.0, "({x},{y}): {z} vs {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn extended_edge_tile_has_the_full_grid() {
+        let (width, height) = (4, 3);
+        let tile: Vec<f32> = (0..width * height).map(|i| i as f32).collect();
+        let raster = |extend_edges| build_terrain_raster(&tile, width, height, no_neighbors(), &RasterOptions { downsample: 1, z_range: None, datum: 0.0, extend_edges, endian: Endian::Le });
+        let short = |buffer: &Buffer, at: usize| u16::from_le_bytes([buffer.bytes[at], buffer.bytes[at + 1]]);
+        assert_eq!((short(&raster(false), 8), short(&raster(false), 10)), (4, 3));
+
+        let buffer = raster(true);
+        assert_eq!((short(&buffer, 8), short(&buffer, 10)), (5, 4));
+        // the extra column and row repeat the last ones
+        let sample = |x: usize, y: usize| short(&buffer, 12 + (y * 5 + x) * 2);
+        for y in 0..4 {
+            assert_eq!(sample(4, y), sample(3, y));
+        }
+        for x in 0..5 {
+            assert_eq!(sample(x, 3), sample(x, 2));
+        }
+
+        // the same for a mesh, too small to decimate: one more vertex along each side
+        let size = 8;
+        let tile: Vec<f32> = (0..size * size).map(|i| (i % 3) as f32).collect();
+        let vertices = |extend_edges| mesh_triangles(&build_terrain_mesh(&tile, size, size, no_neighbors(), &MeshOptions { extend_edges, ..mesh_options(0.5) }).0).0.len();
+        assert_eq!((vertices(false), vertices(true)), (size * size, (size + 1) * (size + 1)));
+    }
+
+    #[test]
+    fn edge_tile_spans_the_full_u16_range() {
+        // an edge tile 100 samples across and 60 down, with nothing to stitch to
+        let (width, height) = (100, 60);
+        let tile: Vec<f32> = (0..width * height).map(|i| (i % 7) as f32).collect();
+        let (buffer, _) = build_terrain_mesh(&tile, width, height, no_neighbors(), &mesh_options(0.5));
+        let short = |at: usize| u16::from_le_bytes([buffer.bytes[at], buffer.bytes[at + 1]]);
+        assert_eq!((short(8), short(10)), (99, 59));
+
+        let (vertices, _) = mesh_triangles(&buffer);
+        let max_x = vertices.iter().map(|v| v[0]).max().unwrap();
+        let max_y = vertices.iter().map(|v| v[1]).max().unwrap();
+        assert_eq!((max_x, max_y), (65535, 65535));
+        // one sample in is one step of the extent
+        let step = vertices.iter().map(|v| v[0]).filter(|x| *x > 0).min().unwrap();
+        assert_eq!(step, (65535.0 / 99.0) as u16);
+    }
+
+    /// Quantized vertices and the triangles of a mesh buffer, each triangle as its three vertices.
+    fn mesh_triangles(buffer: &Buffer) -> (Vec<[u16; 3]>, Vec<[[u16; 3]; 3]>) {
+        let short = |at: usize| u16::from_le_bytes([buffer.bytes[at], buffer.bytes[at + 1]]);
+        let vertex_count = short(12) as usize;
+        // x, y, z then a 3 byte normal
+        let vertices: Vec<[u16; 3]> = (0..vertex_count).map(|v| 14 + v * 9).map(|at| [short(at), short(at + 2), short(at + 4)]).collect();
+        let faces_at = 14 + vertex_count * 9;
+        let mut triangles: Vec<[[u16; 3]; 3]> = (0..short(faces_at) as usize).map(|f| faces_at + 2 + f * 6).map(|at| {
+            let mut corners = [vertices[short(at) as usize], vertices[short(at + 2) as usize], vertices[short(at + 4) as usize]];
+            // start from the lowest corner, keeping the winding
+            let first = (0..3).min_by_key(|i| corners[*i]).unwrap();
+            corners.rotate_left(first);
+            corners
+        }).collect();
+        triangles.sort();
+        (vertices, triangles)
+    }
+
+    #[test]
+    fn morton_order_keeps_the_mesh() {
+        assert_eq!(morton_code(1, 0), 1);
+        assert_eq!(morton_code(0, 1), 2);
+        assert_eq!(morton_code(3, 3), 15);
+
+        let size = 8;
+        let tile: Vec<f32> = (0..size * size).map(|i| ((i * 7) % 5) as f32).collect();
+        let (plain_vertices, plain) = mesh_triangles(&build_terrain_mesh(&tile, size, size, no_neighbors(), &mesh_options(0.5)).0);
+        let (vertices, triangles) = mesh_triangles(&build_terrain_mesh(&tile, size, size, no_neighbors(), &MeshOptions { morton_order: true, ..mesh_options(0.5) }).0);
+        let codes: Vec<u32> = vertices.iter().map(|[x,y,_]| morton_code(*x, *y)).collect();
+        assert!(codes.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_ne!(vertices, plain_vertices);
+        // the same triangles, just numbered differently
+        assert_eq!(triangles, plain);
+    }
+
+    #[test]
+    fn smaller_neighbor_repeats_the_edge() {
+        use std::sync::Arc;
+        use crate::region::Tile;
+
+        // a 4x4 tile next to a region only 2 samples high
+        let tile: Vec<f32> = (0..16).map(|i| i as f32).collect();
+        let short = Arc::new(Tile { data: vec![100.0, 101.0], width: 1, height: 2 });
+        let neighbors = TileNeighbors { next_x: Some(short.clone()), next_y: Some(short), corner: None };
+        assert_eq!(sample_with_neighbors(&tile, 4, 4, &neighbors, 4, 1), 101.0);
+        assert_eq!(sample_with_neighbors(&tile, 4, 4, &neighbors, 4, 3), 15.0);
+        assert_eq!(sample_with_neighbors(&tile, 4, 4, &neighbors, 0, 4), 100.0);
+        assert_eq!(sample_with_neighbors(&tile, 4, 4, &neighbors, 2, 4), 14.0);
+        assert_eq!(sample_with_neighbors(&tile, 4, 4, &neighbors, 4, 4), 15.0);
+    }
+
+    #[test]
+    fn decimating_the_boundary_drops_faces() {
+        // a plain slope, so every edge is a straight line in profile
+        let size = 32;
+        let tile: Vec<f32> = (0..size * size).map(|i| (i % size) as f32 * 0.25).collect();
+        let options = |decimate_boundary| MeshOptions { decimate_boundary, tile_size: size, ..mesh_options(1.0) };
+        let (_, kept) = build_terrain_mesh(&tile, size, size, no_neighbors(), &options(false));
+        let (_, thinned) = build_terrain_mesh(&tile, size, size, no_neighbors(), &options(true));
+
+        assert_eq!(kept.initial_faces, 2 * (size - 1) * (size - 1));
+        assert!(thinned.initial_faces < kept.initial_faces, "{} vs {}",thinned.initial_faces,kept.initial_faces);
+        assert!(thinned.faces < kept.faces, "{} vs {}",thinned.faces,kept.faces);
+        assert!(thinned.max_error <= 1.0 + 1e-6);
+    }
+
+    #[test]
+    fn mesh_stats_match_the_buffer() {
+        // big enough to be decimated at all
+        let size = 128;
+        let tile: Vec<f32> = (0..size * size).map(|i| ((i % size) as f32 - 64.0).powi(2) * 0.001).collect();
+        let (buffer, stats) = build_terrain_mesh(&tile, size, size, no_neighbors(), &mesh_options(0.5));
+
+        let short = |at: usize| u16::from_le_bytes([buffer.bytes[at], buffer.bytes[at + 1]]) as usize;
+        let vertices = short(12);
+        assert_eq!(vertices, stats.vertices);
+        // x, y, z then a 3 byte normal
+        let faces_at = 14 + vertices * 9;
+        let faces = short(faces_at);
+        assert_eq!(faces, stats.faces);
+        assert!((0..faces * 3).all(|i| short(faces_at + 2 + i * 2) < vertices));
+        assert_eq!(buffer.bytes.len(), faces_at + 2 + faces * 6);
+
+        assert_eq!(stats.initial_vertices, size * size);
+        assert_eq!(stats.initial_faces, 2 * (size - 1) * (size - 1));
+        assert!(stats.faces < stats.initial_faces && stats.vertices < stats.initial_vertices);
+
+        // a plane decimates without any error
+        let plane: Vec<f32> = (0..size * size).map(|i| (i % size) as f32 * 0.5 + (i / size) as f32 * 0.25).collect();
+        let (_, stats) = build_terrain_mesh(&plane, size, size, no_neighbors(), &mesh_options(0.5));
+        assert!(stats.max_error < 1e-6, "{}", stats.max_error);
+    }
+}
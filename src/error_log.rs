@@ -0,0 +1,25 @@
+use std::{fs::File, io::{BufWriter, Write}};
+
+/// Per-way record of what was skipped or repaired and why, as `way_id,reason` CSV.
+/// Does nothing unless a path was given.
+pub struct ErrorLog {
+    writer: Option<BufWriter<File>>,
+}
+
+impl ErrorLog {
+    pub fn create(path: Option<&str>) -> Self {
+        let writer = path.map(|path| {
+            let file = File::create(path).unwrap_or_else(|err| panic!("failed to create error log {}: {}",path,err));
+            let mut writer = BufWriter::new(file);
+            writeln!(writer, "way_id,reason").unwrap();
+            writer
+        });
+        ErrorLog { writer }
+    }
+
+    pub fn record(&mut self, way_id: i64, reason: &str) {
+        if let Some(writer) = &mut self.writer {
+            writeln!(writer, "{},{}",way_id,reason).unwrap();
+        }
+    }
+}
@@ -0,0 +1,371 @@
+/// Axis-aligned bounding box that grows as points are added.
+pub struct Extent {
+    pub min_x: f32,
+    pub min_y: f32,
+    pub max_x: f32,
+    pub max_y: f32,
+}
+
+impl Default for Extent {
+    fn default() -> Self {
+        Extent { min_x: 1.0/0.0, min_y: 1.0/0.0, max_x: -1.0/0.0, max_y: -1.0/0.0 }
+    }
+}
+
+impl Extent {
+    pub fn add(&mut self, x: f32, y: f32) {
+        self.min_x = self.min_x.min(x);
+        self.min_y = self.min_y.min(y);
+        self.max_x = self.max_x.max(x);
+        self.max_y = self.max_y.max(y);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min_x > self.max_x
+    }
+}
+
+/// Area-weighted centroid of a closed ring (without the repeated final point).
+/// Returns None for degenerate rings with no area.
+pub fn polygon_centroid(path: &[(f32,f32)]) -> Option<(f32,f32)> {
+    if path.len() < 3 {
+        return None;
+    }
+    // work relative to the first point to keep precision in large coordinates
+    let (ox,oy) = path[0];
+    let mut area = 0.0;
+    let mut cx = 0.0;
+    let mut cy = 0.0;
+    for i in 0..path.len() {
+        let (x1,y1) = path[i];
+        let (x2,y2) = path[(i+1)%path.len()];
+        let (x1,y1,x2,y2) = (x1 - ox, y1 - oy, x2 - ox, y2 - oy);
+        let cross = x1 * y2 - x2 * y1;
+        area += cross;
+        cx += (x1 + x2) * cross;
+        cy += (y1 + y2) * cross;
+    }
+    if area.abs() < 1e-6 {
+        return None;
+    }
+    Some((ox + cx / (3.0 * area), oy + cy / (3.0 * area)))
+}
+
+/// Direction of the longest edge of a closed ring, in radians within [0, pi).
+pub fn dominant_orientation(path: &[(f32,f32)]) -> f32 {
+    let mut best_len = 0.0;
+    let mut best_angle = 0.0;
+    for i in 0..path.len() {
+        let (x1,y1) = path[i];
+        let (x2,y2) = path[(i+1)%path.len()];
+        let (dx,dy) = (x2 - x1, y2 - y1);
+        let len = dx * dx + dy * dy;
+        if len > best_len {
+            best_len = len;
+            best_angle = dy.atan2(dx);
+        }
+    }
+    best_angle.rem_euclid(std::f32::consts::PI)
+}
+
+/// Moving average of a profile along a path, over a window of `window` meters centered on each point.
+/// `distances` is the distance along the path of each point.
+pub fn smooth_profile(distances: &[f32], z: &[f32], window: f32) -> Vec<f32> {
+    let half = window * 0.5;
+    let mut start = 0;
+    let mut end = 0;
+    let mut sum = 0.0;
+    let mut result = Vec::with_capacity(z.len());
+    for i in 0..z.len() {
+        while end < z.len() && distances[end] <= distances[i] + half {
+            sum += z[end];
+            end += 1;
+        }
+        while distances[start] < distances[i] - half {
+            sum -= z[start];
+            start += 1;
+        }
+        result.push(sum / (end - start) as f32);
+    }
+    result
+}
+
+/// Whether any two edges of a closed ring (without a repeated first point) cross, other than
+/// neighbors meeting at their shared corner.
+pub fn is_self_intersecting(ring: &[(f32,f32)]) -> bool {
+    // a node repeated in place would make its neighbors' edges look like they touch
+    let mut ring = ring.to_vec();
+    ring.dedup();
+    while ring.len() > 1 && ring.first() == ring.last() {
+        ring.pop();
+    }
+    let n = ring.len();
+    let orient = |a: (f32,f32), b: (f32,f32), c: (f32,f32)| {
+        let cross = (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0);
+        if cross > 0.0 { 1 } else if cross < 0.0 { -1 } else { 0 }
+    };
+    let on_segment = |a: (f32,f32), b: (f32,f32), p: (f32,f32)| {
+        p.0 >= a.0.min(b.0) && p.0 <= a.0.max(b.0) && p.1 >= a.1.min(b.1) && p.1 <= a.1.max(b.1)
+    };
+    // sweep along x, so only edges whose x ranges overlap get compared
+    let mut edges: Vec<usize> = (0..n).collect();
+    let min_x = |i: usize| ring[i].0.min(ring[(i+1)%n].0);
+    let max_x = |i: usize| ring[i].0.max(ring[(i+1)%n].0);
+    edges.sort_by(|i,j| min_x(*i).total_cmp(&min_x(*j)));
+    for (k,&i) in edges.iter().enumerate() {
+        let (a,b) = (ring[i], ring[(i+1)%n]);
+        for &j in edges[k+1..].iter().take_while(|j| min_x(**j) <= max_x(i)) {
+            // edges next to each other share a corner
+            let gap = i.abs_diff(j);
+            if gap == 1 || gap == n - 1 {
+                continue;
+            }
+            let (c,d) = (ring[j], ring[(j+1)%n]);
+            let (o1,o2,o3,o4) = (orient(a,b,c), orient(a,b,d), orient(c,d,a), orient(c,d,b));
+            if o1 != o2 && o3 != o4 {
+                return true;
+            }
+            // touching, or running along each other
+            if (o1 == 0 && on_segment(a,b,c)) || (o2 == 0 && on_segment(a,b,d)) || (o3 == 0 && on_segment(c,d,a)) || (o4 == 0 && on_segment(c,d,b)) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Shoelace area of a closed ring in local x/y: positive when it winds counter-clockwise in those terms.
+/// Local y grows southward, so that is clockwise on a north-up map (and in raw lat/lon), the opposite sign.
+pub fn signed_area(path: &[(f32,f32)]) -> f32 {
+    let mut sum = 0.0;
+    for i in 0..path.len() {
+        let (x1,y1) = path[i];
+        let (x2,y2) = path[(i+1)%path.len()];
+        sum += x1 * y2 - x2 * y1;
+    }
+    sum * 0.5
+}
+
+/// Signed area as seen on a north-up map, where lat/lon winding is judged: positive when counter-clockwise there.
+pub fn map_signed_area(path: &[(f32,f32)]) -> f32 {
+    -signed_area(path)
+}
+
+/// Unit normal of each edge of a closed ring (edge i runs from point i to i+1), pointing out of the ring
+/// whichever way it winds.
+pub fn outward_normals(path: &[(f32,f32)]) -> Vec<(f32,f32)> {
+    let sign = if signed_area(path) >= 0.0 { 1.0 } else { -1.0 };
+    (0..path.len()).map(|i| {
+        let (x1,y1) = path[i];
+        let (x2,y2) = path[(i+1)%path.len()];
+        let (dx,dy) = (x2 - x1, y2 - y1);
+        let len = (dx * dx + dy * dy).sqrt();
+        if len == 0.0 {
+            (0.0, 0.0)
+        } else {
+            (sign * dy / len, -sign * dx / len)
+        }
+    }).collect()
+}
+
+/// Corners of a wall triangle, and the horizontal normal of the wall it's part of.
+pub type WallTriangle = ([(f32,f32,f32); 3], (f32,f32));
+
+/// Two triangles per edge of a closed ring, from each corner's `ground` up to a flat `top`, each with
+/// its wall's outward normal. Triangles wind counter-clockwise seen from the side the normal points to.
+pub fn wall_triangles(path: &[(f32,f32)], ground: &[f32], top: f32) -> Vec<WallTriangle> {
+    let normals = outward_normals(path);
+    let mut triangles = Vec::with_capacity(path.len() * 2);
+    for i in 0..path.len() {
+        let j = (i+1)%path.len();
+        let (x1,y1) = path[i];
+        let (x2,y2) = path[j];
+        let normal = normals[i];
+        let a_bot = (x1, y1, ground[i]);
+        let b_bot = (x2, y2, ground[j]);
+        let a_top = (x1, y1, top);
+        let b_top = (x2, y2, top);
+        // a_bot -> b_bot -> b_top faces along (dy,-dx)
+        if (y2 - y1) * normal.0 - (x2 - x1) * normal.1 >= 0.0 {
+            triangles.push(([a_bot, b_bot, b_top], normal));
+            triangles.push(([a_bot, b_top, a_top], normal));
+        } else {
+            triangles.push(([b_bot, a_bot, a_top], normal));
+            triangles.push(([b_bot, a_top, b_top], normal));
+        }
+    }
+    triangles
+}
+
+/// Convex hull of a set of points, counter-clockwise (in x-right, y-up terms) without repeating the first point.
+pub fn convex_hull(points: &[(f32,f32)]) -> Vec<(f32,f32)> {
+    let mut points = points.to_vec();
+    points.sort_by(|a,b| a.partial_cmp(b).unwrap());
+    points.dedup();
+    if points.len() < 3 {
+        return points;
+    }
+    let cross = |o: (f32,f32), a: (f32,f32), b: (f32,f32)| (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0);
+    let mut hull: Vec<(f32,f32)> = Vec::with_capacity(points.len() * 2);
+    for pass in 0..2 {
+        let start = hull.len();
+        let iter: Box<dyn Iterator<Item = &(f32,f32)>> = if pass == 0 { Box::new(points.iter()) } else { Box::new(points.iter().rev()) };
+        for p in iter {
+            while hull.len() >= start + 2 && cross(hull[hull.len()-2], hull[hull.len()-1], *p) <= 0.0 {
+                hull.pop();
+            }
+            hull.push(*p);
+        }
+        // the last point of each chain starts the other one
+        hull.pop();
+    }
+    hull
+}
+
+/// Smallest-area rectangle around the points (rotating calipers over the hull edges).
+/// Returns its four corners in order, and the direction of its first side in radians within [0, pi).
+pub fn min_area_rect(points: &[(f32,f32)]) -> ([(f32,f32); 4], f32) {
+    let hull = convex_hull(points);
+    let mut best = None;
+    let mut best_area = 1.0f32/0.0;
+    for i in 0..hull.len() {
+        let (x1,y1) = hull[i];
+        let (x2,y2) = hull[(i+1)%hull.len()];
+        let angle = (y2 - y1).atan2(x2 - x1);
+        let (sin,cos) = angle.sin_cos();
+        let mut min_u = 1.0f32/0.0;
+        let mut max_u = -1.0f32/0.0;
+        let mut min_v = 1.0f32/0.0;
+        let mut max_v = -1.0f32/0.0;
+        for (x,y) in &hull {
+            let u = x * cos + y * sin;
+            let v = -x * sin + y * cos;
+            min_u = min_u.min(u);
+            max_u = max_u.max(u);
+            min_v = min_v.min(v);
+            max_v = max_v.max(v);
+        }
+        let area = (max_u - min_u) * (max_v - min_v);
+        if area < best_area {
+            best_area = area;
+            best = Some((angle, min_u, max_u, min_v, max_v));
+        }
+    }
+    let Some((angle, min_u, max_u, min_v, max_v)) = best else {
+        let (x,y) = points.first().copied().unwrap_or((0.0,0.0));
+        return ([(x,y); 4], 0.0);
+    };
+    let (sin,cos) = angle.sin_cos();
+    let to_xy = |u: f32, v: f32| (u * cos - v * sin, u * sin + v * cos);
+    let corners = [to_xy(min_u, min_v), to_xy(max_u, min_v), to_xy(max_u, max_v), to_xy(min_u, max_v)];
+    (corners, angle.rem_euclid(std::f32::consts::PI))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn square_walls_face_out() {
+        // clockwise and counter-clockwise versions of the same 4 m square, on sloping ground
+        let square = [(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0)];
+        let reversed: Vec<(f32,f32)> = square.iter().rev().copied().collect();
+        for path in [&square[..], &reversed[..]] {
+            let triangles = wall_triangles(path, &[1.0, 1.5, 2.0, 1.5], 10.0);
+            assert_eq!(triangles.len(), 8);
+            for ([a,b,c], normal) in triangles {
+                // normal points away from the square's center
+                let mid = ((a.0 + b.0 + c.0) / 3.0 - 2.0, (a.1 + b.1 + c.1) / 3.0 - 2.0);
+                assert!(mid.0 * normal.0 + mid.1 * normal.1 > 0.0, "{normal:?} at {mid:?}");
+                // and the corners wind counter-clockwise seen from there
+                let (u,v) = ((b.0 - a.0, b.1 - a.1, b.2 - a.2), (c.0 - a.0, c.1 - a.1, c.2 - a.2));
+                let cross = (u.1 * v.2 - u.2 * v.1, u.2 * v.0 - u.0 * v.2);
+                assert!(cross.0 * normal.0 + cross.1 * normal.1 > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn l_shape_centroid() {
+        // two 10x20 rectangles: [0,20]x[0,10] and [0,10]x[10,30], with extra nodes bunched along one edge
+        let l_shape = [(0.0, 0.0), (20.0, 0.0), (20.0, 2.0), (20.0, 4.0), (20.0, 6.0), (20.0, 8.0), (20.0, 10.0), (10.0, 10.0), (10.0, 30.0), (0.0, 30.0)];
+        let (x,y) = polygon_centroid(&l_shape).unwrap();
+        // the mean of the rectangles' centers (10,5) and (5,20), as they have equal areas
+        assert!((x - 7.5).abs() < 1e-4 && (y - 12.5).abs() < 1e-4, "({x},{y})");
+
+        // the node mean is pulled over toward the bunched nodes
+        let n = l_shape.len() as f32;
+        let mean = (l_shape.iter().map(|p| p.0).sum::<f32>() / n, l_shape.iter().map(|p| p.1).sum::<f32>() / n);
+        assert!((mean.0 - x).abs() > 5.0, "{mean:?}");
+        // and winding the other way round gives the same point
+        let mut reversed = l_shape;
+        reversed.reverse();
+        let (rx,ry) = polygon_centroid(&reversed).unwrap();
+        assert!((rx - x).abs() < 1e-4 && (ry - y).abs() < 1e-4);
+    }
+
+    #[test]
+    fn profile_average_spans_the_window() {
+        let distances = [0.0, 1.0, 2.0, 3.0, 4.0, 10.0];
+        let z = [0.0, 3.0, 0.0, 3.0, 0.0, 9.0];
+        // 2m either side: the last point is alone in its window
+        assert_eq!(smooth_profile(&distances, &z, 4.0), [1.0, 1.5, 1.2, 1.5, 1.0, 9.0]);
+        // and a window narrower than the spacing changes nothing
+        assert_eq!(smooth_profile(&distances, &z, 0.5), z);
+    }
+
+    #[test]
+    fn degenerate_ring_has_no_centroid() {
+        assert_eq!(polygon_centroid(&[(0.0, 0.0), (5.0, 5.0), (10.0, 10.0)]), None);
+        assert_eq!(polygon_centroid(&[(0.0, 0.0), (5.0, 5.0)]), None);
+    }
+
+    #[test]
+    fn crossed_rings() {
+        assert!(!is_self_intersecting(&[(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0)]));
+        // concave, but fine
+        assert!(!is_self_intersecting(&[(0.0, 0.0), (4.0, 0.0), (2.0, 1.0), (4.0, 4.0), (0.0, 4.0)]));
+        // repeated nodes aren't crossings
+        assert!(!is_self_intersecting(&[(0.0, 0.0), (4.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0), (0.0, 0.0)]));
+        // bow tie
+        assert!(is_self_intersecting(&[(0.0, 0.0), (4.0, 4.0), (4.0, 0.0), (0.0, 4.0)]));
+        // figure of eight through a shared corner
+        assert!(is_self_intersecting(&[(0.0, 0.0), (2.0, 2.0), (4.0, 0.0), (4.0, 4.0), (2.0, 2.0), (0.0, 4.0)]));
+    }
+
+    #[test]
+    fn l_shape_lod_box() {
+        // the L from l_shape_centroid (a 20x30 box) turned 30 degrees
+        let (sin,cos) = 30f32.to_radians().sin_cos();
+        let l_shape: Vec<(f32,f32)> = [(0.0, 0.0), (20.0, 0.0), (20.0, 10.0), (10.0, 10.0), (10.0, 30.0), (0.0, 30.0)].iter()
+            .map(|(x,y)| (x * cos - y * sin + 100.0, x * sin + y * cos + 50.0)).collect();
+        let (corners, angle) = min_area_rect(&l_shape);
+
+        let side = |a: (f32,f32), b: (f32,f32)| ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt();
+        let (long, short) = (side(corners[0], corners[1]).max(side(corners[1], corners[2])), side(corners[0], corners[1]).min(side(corners[1], corners[2])));
+        assert!((long - 30.0).abs() < 1e-3 && (short - 20.0).abs() < 1e-3, "{corners:?}");
+        // lined up with the walls, whichever of them it starts from
+        let turn = angle.to_degrees() % 90.0;
+        assert!((turn - 30.0).abs() < 1e-3, "{angle}");
+        // and every node is inside (or on) it
+        for p in &l_shape {
+            for i in 0..4 {
+                let (a,b) = (corners[i], corners[(i+1)%4]);
+                let cross = (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0);
+                assert!(cross >= -1e-2, "{p:?} outside edge {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn map_signed_area_follows_lat_lon_winding() {
+        // a 10 m square running counter-clockwise on the map: south-west, south-east, north-east, north-west
+        let lon_lat = [(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0)];
+        assert_eq!(signed_area(&lon_lat), 100.0);
+        // the same corners in local coordinates, where y grows southward
+        let local: Vec<(f32,f32)> = lon_lat.iter().map(|(x,y)| (*x, -*y)).collect();
+        // read naively, local coordinates invert the winding
+        assert_eq!(signed_area(&local), -100.0);
+        assert_eq!(map_signed_area(&local), 100.0);
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use crate::{region::Region, Buffer, Endian, Oneway};
+
+/// Road network for --emit-graph: nodes at intersections and dead ends, edges between them.
+#[derive(Default)]
+pub struct RoadGraph {
+    roads: Vec<GraphRoad>,
+}
+
+struct GraphRoad {
+    nodes: Vec<i64>,
+    oneway: bool,
+    max_speed: f32,
+}
+
+pub struct Edge {
+    pub from: u32,
+    pub to: u32,
+    pub length: f32,
+    pub oneway: bool,
+    pub max_speed: f32,
+}
+
+/// Parses `maxspeed` into km/h ("50", "30 mph"). Values like "walk" or "none" give None.
+pub fn parse_max_speed(value: &str) -> Option<f32> {
+    let value = value.trim();
+    if let Some(mph) = value.strip_suffix("mph") {
+        return mph.trim().parse::<f32>().ok().map(|mph| mph * 1.609344);
+    }
+    value.strip_suffix("km/h").unwrap_or(value).trim().parse().ok()
+}
+
+impl RoadGraph {
+    /// Oneway roads are stored in their direction of travel.
+    pub fn add_road(&mut self, mut nodes: Vec<i64>, oneway: Oneway, max_speed: Option<&str>) {
+        if oneway == Oneway::Backward {
+            nodes.reverse();
+        }
+        let max_speed = max_speed.and_then(parse_max_speed).unwrap_or(0.0);
+        self.roads.push(GraphRoad { nodes, oneway: oneway != Oneway::No, max_speed });
+    }
+
+    /// Splits every road at nodes it shares with another road (or itself), returning
+    /// the graph's node ids, in index order, and the edges between them.
+    pub fn build(&self, positions: &HashMap<i64,(f32,f32)>) -> (Vec<i64>, Vec<Edge>) {
+        let mut uses: HashMap<i64,usize> = HashMap::new();
+        for road in &self.roads {
+            for id in &road.nodes {
+                *uses.entry(*id).or_default() += 1;
+            }
+        }
+
+        let mut graph_nodes = Vec::new();
+        let mut indices: HashMap<i64,u32> = HashMap::new();
+        let mut index_of = |id: i64| *indices.entry(id).or_insert_with(|| {
+            graph_nodes.push(id);
+            graph_nodes.len() as u32 - 1
+        });
+
+        let mut edges = Vec::new();
+        for road in &self.roads {
+            if road.nodes.len() < 2 {
+                continue;
+            }
+            let mut start = road.nodes[0];
+            let mut length = 0.0;
+            for i in 1..road.nodes.len() {
+                let (x1,y1) = positions[&road.nodes[i-1]];
+                let (x2,y2) = positions[&road.nodes[i]];
+                length += ((x2 - x1).powi(2) + (y2 - y1).powi(2)).sqrt();
+                let id = road.nodes[i];
+                if i == road.nodes.len() - 1 || uses[&id] > 1 {
+                    edges.push(Edge {
+                        from: index_of(start),
+                        to: index_of(id),
+                        length,
+                        oneway: road.oneway,
+                        max_speed: road.max_speed
+                    });
+                    start = id;
+                    length = 0.0;
+                }
+            }
+        }
+        (graph_nodes, edges)
+    }
+
+    /// Layout: u32 node count, then per node: u64 OSM id, x, y, elevation;
+    /// u32 edge count, then per edge: u32 from, u32 to, length, oneway byte, max speed (km/h, 0 unknown).
+    pub fn write(&self, positions: &HashMap<i64,(f32,f32)>, region: &Region, offset: (f32,f32), endian: Endian) -> Buffer {
+        let (graph_nodes, edges) = self.build(positions);
+        let mut buffer = Buffer::new(endian);
+        buffer.write_int(graph_nodes.len().try_into().expect("too many graph nodes"));
+        for id in &graph_nodes {
+            let (x,y) = positions[id];
+            buffer.write_long(*id as u64);
+            buffer.write_float(x + offset.0);
+            buffer.write_float(y + offset.1);
+            buffer.write_float(region.get_elevation(x, y));
+        }
+        buffer.write_int(edges.len().try_into().expect("too many graph edges"));
+        for edge in &edges {
+            buffer.write_int(edge.from);
+            buffer.write_int(edge.to);
+            buffer.write_float(edge.length);
+            buffer.write_byte(edge.oneway as u8);
+            buffer.write_float(edge.max_speed);
+        }
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_splits_at_shared_nodes() {
+        // a crossroads at 5, with a one-way spur off its east arm at 7 and a loop of its own
+        //   1 - 2 - 5 - 7 - 8
+        //           |   |
+        //   3 ----- 4   9 - 10
+        //               |   |
+        //               12- 11
+        let positions: HashMap<i64,(f32,f32)> = [
+            (1, (0.0, 0.0)), (2, (10.0, 0.0)), (5, (20.0, 0.0)), (7, (30.0, 0.0)), (8, (40.0, 0.0)),
+            (3, (0.0, 10.0)), (4, (20.0, 10.0)),
+            (9, (30.0, 10.0)), (10, (40.0, 10.0)), (11, (40.0, 20.0)), (12, (30.0, 20.0)),
+        ].into_iter().collect();
+        let mut graph = RoadGraph::default();
+        graph.add_road(vec![1, 2, 5, 7, 8], Oneway::No, Some("50"));
+        graph.add_road(vec![3, 4, 5], Oneway::No, None);
+        graph.add_road(vec![9, 7], Oneway::Backward, Some("20 mph"));
+        graph.add_road(vec![9, 10, 11, 12, 9], Oneway::No, None);
+        let (nodes, edges) = graph.build(&positions);
+
+        // ends 1, 8, 3, the crossings 5, 7 and 9
+        assert_eq!(nodes.len(), 6);
+        assert!(nodes.iter().all(|id| [1, 8, 3, 5, 7, 9].contains(id)), "{nodes:?}");
+        // 1-5, 5-7, 7-8, 3-5, 7-9 and the loop from 9 back to itself
+        assert_eq!(edges.len(), 6);
+        let id = |index: u32| nodes[index as usize];
+        let edge = |from: i64, to: i64| edges.iter().find(|edge| id(edge.from) == from && id(edge.to) == to).unwrap();
+        assert_eq!((edge(1, 5).length, edge(1, 5).max_speed, edge(1, 5).oneway), (20.0, 50.0, false));
+        assert_eq!(edge(3, 5).length, 30.0);
+        // the spur runs against its node order, so from 7 to 9
+        assert!(edge(7, 9).oneway && (edge(7, 9).max_speed - 32.19).abs() < 0.01);
+        assert_eq!(edge(9, 9).length, 40.0);
+    }
+
+    #[test]
+    fn max_speed_units() {
+        assert_eq!(parse_max_speed("50"), Some(50.0));
+        assert_eq!(parse_max_speed("80 km/h"), Some(80.0));
+        assert!((parse_max_speed("30 mph").unwrap() - 48.28).abs() < 0.01);
+        assert_eq!(parse_max_speed("walk"), None);
+    }
+}
@@ -0,0 +1,58 @@
+use std::io::Write;
+
+use flate2::{write::ZlibEncoder, Compression};
+
+use crate::{ensure_dir_exists, region::Tile};
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let mut crc = flate2::Crc::new();
+    crc.update(&png[start..]);
+    png.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+/// Encodes row-major samples as a 16-bit grayscale PNG.
+pub fn encode_png16(width: usize, height: usize, samples: &[u16]) -> Vec<u8> {
+    assert_eq!(samples.len(), width * height, "heightmap sized wrongly");
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 16 bit grayscale, deflate, adaptive filtering, not interlaced
+    header.extend_from_slice(&[16, 0, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for row in samples.chunks(width) {
+        // filter type none
+        let mut line = vec![0];
+        for sample in row {
+            line.extend_from_slice(&sample.to_be_bytes());
+        }
+        encoder.write_all(&line).unwrap();
+    }
+    write_chunk(&mut png, b"IDAT", &encoder.finish().unwrap());
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// Height of a sample in the 0-65535 range covering `min_z` to `min_z + range_z`. Missing samples are 0.
+pub fn quantize_height(z: f32, min_z: f32, range_z: f32) -> u16 {
+    if range_z > 0.0 && !z.is_nan() {
+        ((z - min_z) / range_z * 65535.0).round().clamp(0.0, 65535.0) as u16
+    } else {
+        0
+    }
+}
+
+/// Writes the tile to `{out_dir}/tile{index}.png`, quantized against (min, range).
+pub fn save_heightmap(out_dir: &str, index: usize, tile: &Tile, min_z: f32, range_z: f32) {
+    ensure_dir_exists(out_dir);
+    let samples: Vec<u16> = tile.data.iter().map(|z| quantize_height(*z, min_z, range_z)).collect();
+    let png = encode_png16(tile.width as usize, tile.height as usize, &samples);
+    std::fs::write(format!("{}/tile{}.png",out_dir,index), png).unwrap();
+}
@@ -1,18 +1,36 @@
 use core::f32;
-use std::{collections::HashMap, io::Write, path::{Path, PathBuf}};
+use std::{collections::{BTreeMap, HashMap, HashSet}, io::Write, path::Path, process::ExitCode};
 
-use baby_shark::{decimation::{edge_decimation::{AlwaysDecimate, BoundingSphereDecimationCriteria, ConstantErrorDecimationCriteria}, prelude::EdgeDecimator}, exports::nalgebra::{Vector2, Vector3}, io::stl::{StlReader, StlWriter}, mesh::{corner_table::table::CornerTable, traits::Mesh}};
+use baby_shark::exports::nalgebra::{Vector2, Vector3};
 use flate2::{write::GzEncoder, Compression};
-use osmio::{obj_types::StringWay, Node, OSMObj, OSMObjBase, OSMReader, Way};
-use region::Region;
-use tiff::{decoder::DecodingResult, tags::Tag};
+use osmio::{obj_types::{StringOSMObj, StringWay}, Node, OSMObj, OSMObjBase, OSMReader, Way};
+use elevation::ElevationFormat;
+use error_log::ErrorLog;
+use graph::RoadGraph;
+use manifest::Manifest;
+use osm_fetch::FetchFeature;
+use geometry::Extent;
+use region::{AdjacentRegions, ElevationUnits, Region};
+use rules::{RoadClass, Rules};
 use clap::Parser;
+use serde_json::json;
+use summary::Summary;
 
 mod region;
 mod elevation;
 mod osm_fetch;
-
-#[derive(Parser, Debug)]
+mod manifest;
+mod geometry;
+mod validate;
+mod rules;
+mod colors;
+mod error_log;
+mod graph;
+mod summary;
+mod heightmap;
+mod coords;
+
+#[derive(Parser, Debug, Clone)]
 #[command()]
 struct CommandArgs {
     /// The input height-map to process
@@ -21,36 +39,473 @@ struct CommandArgs {
     /// The UTM zone of the region
     zone_number: u8,
 
+    /// The region is in the southern hemisphere (only needed when the elevation map's GeoTIFF keys don't say)
+    #[arg(long)]
+    southern: bool,
+
     /// Generate elevation tiles?
     #[arg(short, long)]
     elevation: bool,
 
     /// Generate map file?
     #[arg(short, long)]
-    map: bool
+    map: bool,
+
+    /// Number of worker threads for elevation processing (defaults to detected parallelism)
+    #[arg(short, long)]
+    threads: Option<usize>,
+
+    /// Output format for elevation tiles
+    #[arg(long, value_enum, default_value_t = ElevationFormat::Mesh)]
+    elevation_format: ElevationFormat,
+
+    /// Keep every Nth sample when writing raster elevation
+    #[arg(long, default_value_t = 4)]
+    raster_downsample: usize,
+
+    /// Quick preview: only process a strided subset of tiles with coarse decimation
+    #[arg(long)]
+    preview: bool,
+
+    /// Tile stride (in both directions) used by --preview
+    #[arg(long, default_value_t = 4)]
+    preview_stride: usize,
+
+    /// Optional bathymetry map (input/<name>.tif) used for terrain below sea level
+    #[arg(long)]
+    bathymetry: Option<String>,
+
+    /// Quantize every elevation tile against the region-wide z range instead of its own
+    #[arg(long)]
+    global_z_range: bool,
+
+    /// Print a summary of what was read from the OSM file
+    #[arg(long)]
+    verbose_osm_stats: bool,
+
+    /// Print the Overpass query for the region and exit without fetching
+    #[arg(long)]
+    dump_osm_query: bool,
+
+    /// Skip elevation tiles that were already written by an earlier, interrupted run
+    #[arg(long)]
+    resume: bool,
+
+    /// Box blur radius (in samples) applied to the elevation map before anything else uses it
+    #[arg(long)]
+    smooth: Option<usize>,
+
+    /// Height floor for all buildings, also used when a building has no height tags (default 3m)
+    #[arg(long)]
+    min_building_height: Option<f32>,
+
+    /// Height floor for commercial and industrial buildings
+    #[arg(long, default_value_t = 6.0)]
+    min_commercial_height: f32,
+
+    /// Height floor for towers
+    #[arg(long)]
+    min_tower_height: Option<f32>,
+
+    /// Also write the parsed map features as GeoJSON (map.geojson) for inspection
+    #[arg(long)]
+    geojson: bool,
+
+    /// Place output coordinates relative to this UTM easting,northing instead of the elevation map's corner
+    #[arg(long, value_parser = parse_coord)]
+    origin: Option<(f64,f64)>,
+
+    /// Emit portal/abutment markers at the ends of tunnels and bridges
+    #[arg(long)]
+    portals: bool,
+
+    /// Ask Overpass for a free slot (and wait for one) before sending the query
+    #[arg(long)]
+    overpass_status: bool,
+
+    /// Fetch the OSM data again even if input/<name>.osm exists
+    #[arg(long)]
+    refresh_osm: bool,
+
+    /// Fetch the OSM data to a temporary file instead of caching it in input/
+    #[arg(long, conflicts_with = "refresh_osm")]
+    no_cache_osm: bool,
+
+    /// Width of one road lane in meters
+    #[arg(long, default_value_t = 3.0)]
+    lane_width: f32,
+
+    /// Width of footpaths in meters
+    #[arg(long, default_value_t = 2.0)]
+    footpath_width: f32,
+
+    /// Width of bike paths in meters
+    #[arg(long, default_value_t = 2.0)]
+    bikepath_width: f32,
+
+    /// Units of the elevation map (detected from the GeoTIFF when possible, otherwise meters)
+    #[arg(long, value_enum)]
+    elevation_units: Option<ElevationUnits>,
+
+    /// Split road segments longer than this many meters, so roads follow the terrain between nodes
+    #[arg(long)]
+    road_drape_spacing: Option<f32>,
+
+    /// Re-read every file after writing it and check it decompresses and is internally consistent
+    #[arg(long)]
+    validate_output: bool,
+
+    /// Write mesh vertices in Morton (Z-curve) order of their position, for better streaming locality
+    #[arg(long)]
+    morton_order: bool,
+
+    /// TOML file of extra tag to building/road/area mappings, checked before the built-in ones
+    #[arg(long)]
+    rules: Option<String>,
+
+    /// Print the elevation at local x,y (meters, y pointing south) and exit
+    #[arg(long, value_parser = parse_coord, allow_hyphen_values = true)]
+    sample_elevation: Option<(f64,f64)>,
+
+    /// Treat --sample-elevation as a UTM easting,northing instead
+    #[arg(long, requires = "sample_elevation")]
+    sample_utm: bool,
+
+    /// Average road elevation over this many meters along the road, to flatten out bumps
+    #[arg(long)]
+    road_smoothing: Option<f32>,
+
+    /// Same as --road-smoothing, for foot and bike paths
+    #[arg(long)]
+    path_smoothing: Option<f32>,
+
+    /// Only process tiles in columns col0..=col1 and rows row0..=row1, and the map features over them
+    #[arg(long, value_parser = parse_crop, value_name = "COL0,ROW0,COL1,ROW1")]
+    crop: Option<[usize;4]>,
+
+    /// More regions in the same zone to process alongside this one, sharing its origin,
+    /// with tiles stitched across the borders where regions line up
+    #[arg(long, num_args = 1.., conflicts_with_all = ["bathymetry", "crop"])]
+    merge_regions: Vec<String>,
+
+    /// Write a CSV of every way that was skipped or simplified, and why
+    #[arg(long)]
+    error_log: Option<String>,
+
+    /// Gzip level for output files, 0 (stored, fastest) to 9 (smallest)
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=9))]
+    compression: u32,
+
+    /// Never contact Overpass; fail if there's no local .osm/.pbf/.o5m for the region
+    #[arg(long, conflicts_with_all = ["refresh_osm", "no_cache_osm", "overpass_status"])]
+    offline: bool,
+
+    /// Add footprint area and floor count to building records
+    #[arg(long)]
+    emit_metrics: bool,
+
+    /// Also write the road network as a graph of intersections and the edges between them (graph.bin.gz)
+    #[arg(long)]
+    emit_graph: bool,
+
+    /// Read map features from this .osm/.pbf/.o5m file instead of input/<name>.osm
+    #[arg(long, conflicts_with_all = ["refresh_osm", "no_cache_osm"])]
+    osm_input: Option<String>,
+
+    /// Add the index of the nearest palette colour to building records
+    #[arg(long)]
+    palette_index: bool,
+
+    /// Palette for --palette-index, one colour per line, instead of the built-in one
+    #[arg(long, requires = "palette_index")]
+    palette: Option<String>,
+
+    /// Add quantized outward wall normals and a roof normal to building records
+    #[arg(long)]
+    building_normals: bool,
+
+    /// Read elevation chunks on a background thread, meshing tiles as soon as they (and their neighbors) are in.
+    /// Only for 512x512 tiled maps, and can't be combined with options that need the whole map up front.
+    #[arg(long, conflicts_with_all = ["bathymetry", "smooth"])]
+    thread_chunked: bool,
+
+    /// Write indoor=* rooms, areas and corridors with their level, instead of skipping them
+    #[arg(long)]
+    indoor: bool,
+
+    /// Refuse elevation maps with more than this many samples, rather than running out of memory
+    #[arg(long, default_value_t = 400_000_000)]
+    max_region_size: u64,
+
+    /// Write highway=construction and highway=proposed roads (flagged in the subtype) instead of skipping them
+    #[arg(long)]
+    construction_roads: bool,
+
+    /// Also write every building as its minimal bounding box, for a low detail level (buildings_lod.bin.gz)
+    #[arg(long)]
+    emit_building_lod: bool,
+
+    /// Raise roads this many meters above the terrain so they don't z-fight with it
+    #[arg(long, default_value_t = 0.0)]
+    road_z_offset: f32,
+
+    /// Only write buildings (with --only-roads, buildings and roads)
+    #[arg(long)]
+    only_buildings: bool,
+
+    /// Only write roads, with their portals and junctions (with --only-buildings, buildings and roads)
+    #[arg(long)]
+    only_roads: bool,
+
+    /// Which copy of an object to keep when the same id appears more than once, as in concatenated extracts
+    #[arg(long, value_enum, default_value_t = DuplicatePolicy::First)]
+    duplicates: DuplicatePolicy,
+
+    /// Give tiles at the edge of the map the same extra row/column as inner tiles, repeating their edge samples,
+    /// so every tile covers a full grid and can be stitched to a region outside this run
+    #[arg(long)]
+    extend_edges: bool,
+
+    /// Write a JSON report of the run here: tile and feature counts, skipped ways, bytes written and time per stage
+    #[arg(long)]
+    summary_json: Option<String>,
+
+    /// Write bridges as raised decks instead of skipping them. The deck spans between the bridge's ends and stays
+    /// at least --bridge-clearance per layer (or its height=* tag) above the ground underneath, clear of ground-level buildings lower than that
+    #[arg(long)]
+    bridges: bool,
+
+    /// Height of one layer=* step under a bridge deck, in meters
+    #[arg(long, default_value_t = 5.0)]
+    bridge_clearance: f32,
+
+    /// Cut each 512x512 elevation tile into output tiles this size (a divisor of 512), numbered across the whole map
+    #[arg(long, default_value_t = 512)]
+    output_tile_size: u32,
+
+    /// Fail when an OSM refresh can't be fetched, instead of carrying on with the previously fetched file
+    #[arg(long)]
+    strict: bool,
+
+    /// Add the ground elevation under each outline point to building records, for walls that follow the slope
+    #[arg(long)]
+    building_ground: bool,
+
+    /// Only fetch these kinds of feature from Overpass (comma separated), instead of everything in the area
+    #[arg(long, value_enum, value_delimiter = ',')]
+    fetch_features: Vec<FetchFeature>,
+
+    /// Add this border width (meters) to area records, for drawing an edge inward from the outline
+    #[arg(long)]
+    area_border: Option<f32>,
+
+    /// Byte order of the numbers in output files
+    #[arg(long, value_enum, default_value_t = Endian::Le)]
+    endian: Endian,
+
+    /// Raise terrain below this elevation (meters) up to it, before meshing and draping map features
+    #[arg(long)]
+    min_elevation: Option<f32>,
+
+    /// Cut terrain above this elevation (meters) down to it, before meshing and draping map features
+    #[arg(long)]
+    max_elevation: Option<f32>,
+
+    /// Measure every output height, terrain and map alike, from the lowest terrain across all regions
+    /// instead of from sea level. The datum is written to the manifest.
+    #[arg(long, requires = "global_z_range")]
+    normalize_heights: bool,
+
+    /// Write buildings, roads and areas that are underground (location=underground, negative layer, subways),
+    /// instead of skipping them
+    #[arg(long)]
+    include_underground: bool,
+
+    /// Add wall triangles to building records (two per outline edge, from the ground at each corner
+    /// up to ground_top + height) with their outward normals
+    #[arg(long)]
+    bake_walls: bool,
+
+    /// Thin out terrain mesh tile edges as well as their insides. Edges are simplified on their own,
+    /// so tiles sharing one still meet without cracks.
+    #[arg(long)]
+    decimate_boundary: bool,
+
+    /// Raise every road by its layer=* times this many meters (on top of any bridge clearance),
+    /// so the ramps of stacked interchanges separate
+    #[arg(long)]
+    layer_height: Option<f32>,
+
+    /// Also write each 512x512 source tile as a 16-bit grayscale PNG to heightmaps/tileN.png,
+    /// covering the region's elevation range (recorded in the manifest)
+    #[arg(long)]
+    heightmap_png: bool,
+
+    /// Skip buildings and areas with less footprint than this, in square meters (collinear outlines have none)
+    #[arg(long, default_value_t = 1.0)]
+    min_area: f32,
+
+    /// Fetch OSM data as it was at this time (YYYY-MM-DD or YYYY-MM-DDTHH:MM:SSZ) from Overpass' history,
+    /// cached separately from current data
+    #[arg(long, value_parser = osm_fetch::parse_osm_date)]
+    osm_date: Option<String>,
+
+    /// Multiply every road's width by this, to thin the network down to its centerlines or fatten it
+    /// to check coverage while debugging
+    #[arg(long, default_value_t = 1.0, value_parser = parse_width_scale)]
+    road_width_scale: f32
+}
+
+/// Parses a coordinate pair, "x,y" or "easting,northing".
+fn parse_coord(value: &str) -> Result<(f64,f64),String> {
+    let (a,b) = value.split_once(',').ok_or("expected two numbers separated by a comma")?;
+    let parse = |v: &str| v.trim().parse::<f64>().map_err(|e| format!("{}: {}",v,e));
+    Ok((parse(a)?, parse(b)?))
+}
+
+/// Parses a --crop window, "col0,row0,col1,row1".
+fn parse_crop(value: &str) -> Result<[usize;4],String> {
+    let parts = value.split(',').map(|v| v.trim().parse::<usize>().map_err(|e| format!("{}: {}",v,e))).collect::<Result<Vec<_>,_>>()?;
+    parts.try_into().map_err(|_| "expected four numbers separated by commas".to_owned())
+}
+
+/// Local position of a --sample-elevation coordinate, and the elevation there if it's on the map.
+fn sample_elevation(region: &Region, coord: (f64,f64), utm: bool) -> (f32,f32,Option<f32>) {
+    let (x,y) = if utm {
+        region.utm_to_local(coord.0, coord.1)
+    } else {
+        (coord.0 as f32, coord.1 as f32)
+    };
+    (x, y, region.contains(x, y).then(|| region.get_elevation(x, y)))
 }
 
-fn main() {
+/// Exit codes: 0 everything written, 2 partial (tiles failed or ways skipped), 1 fatal.
+fn main() -> ExitCode {
     //osm_fetch::fetch();
     //panic!();
 
     let cli_args = CommandArgs::parse();
 
-    let region = Region::new(cli_args.name, cli_args.zone_number);
+    let result = std::panic::catch_unwind(|| run(&cli_args));
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(2),
+        Err(_) => {
+            println!("> failed");
+            ExitCode::from(1)
+        }
+    }
+}
+
+/// Returns false if the run only partially succeeded.
+fn run(cli_args: &CommandArgs) -> bool {
+    let rules = cli_args.rules.as_deref().map(Rules::load).unwrap_or_default();
+    let mut summary = Summary::start();
+    let region = summary.time("load".to_owned(), || Region::new(cli_args));
+
+    if let Some(coord) = cli_args.sample_elevation {
+        match sample_elevation(&region, coord, cli_args.sample_utm) {
+            (x, y, Some(elevation)) => println!("{} {} {}",x,y,elevation),
+            (x, y, None) => println!("{} {} is outside the elevation map",x,y)
+        }
+        return true;
+    }
+
+    if cli_args.dump_osm_query {
+        println!("{}",osm_fetch::build_query(&region.get_bounds(cli_args), &cli_args.fetch_features, cli_args.osm_date.as_deref()));
+        return true;
+    }
 
-    region.ensure_out_dir_exists();
-    if cli_args.elevation {
-        region.process_elevation();
+    let mut region = region;
+    let mut merged: Vec<Region> = cli_args.merge_regions.iter().map(|name| Region::named(name.clone(), cli_args)).collect();
+    if cli_args.normalize_heights {
+        // one datum for every region, so merged regions still line up vertically
+        let datum = std::iter::once(&region).chain(merged.iter()).map(|region| region.global_z_range().0).fold(1.0f32/0.0, f32::min);
+        region.datum = datum;
+        for region in merged.iter_mut() {
+            region.datum = datum;
+        }
+        println!("> heights measured from {}",datum);
+    }
+    let mut args = cli_args.clone();
+    if !merged.is_empty() && args.origin.is_none() {
+        // everything relative to the first region, so the regions line up
+        args.origin = Some((region.coord.easting, region.coord.northing));
+    }
+    let regions: Vec<&Region> = std::iter::once(&region).chain(merged.iter()).collect();
+
+    let mut tiles_written = 0;
+    let mut tiles_failed = 0;
+    let mut ways_skipped = 0;
+    for region in &regions {
+        region.ensure_out_dir_exists(&args);
+        let adjacent = AdjacentRegions::find(region, &regions);
+        let (elevation, osm) = match (args.elevation, args.map) {
+            (true, true) => {
+                let (report, stats) = summary.time(format!("{} elevation+map",region.name), || region.process_all(&args, &adjacent, &rules));
+                (Some(report), Some(stats))
+            }
+            (true, false) => (Some(summary.time(format!("{} elevation",region.name), || region.process_elevation(&args, &adjacent))), None),
+            (false, true) => (None, Some(summary.time(format!("{} map",region.name), || region.process_osm(&args, &rules)))),
+            (false, false) => (None, None)
+        };
+        if let Some(report) = &elevation {
+            tiles_written += report.written;
+            tiles_failed += report.failed;
+        }
+        if let Some(stats) = &osm {
+            ways_skipped += stats.ways_skipped();
+        }
+        summary.add_region(&region.name, &region.out_dir(&args), elevation.as_ref(), osm.as_ref());
     }
-    if cli_args.map {
-        region.process_osm();
+    println!("> done: {} tiles written, {} tiles failed, {} ways skipped",tiles_written,tiles_failed,ways_skipped);
+    if let Some(path) = &args.summary_json {
+        summary.save(path);
     }
+    tiles_failed == 0 && ways_skipped == 0
 }
 
+/// Start of every map file, followed by the byte order of everything after it (0 little-endian,
+/// 1 big-endian), the u16 format version, the f32 bounding box
+/// (min x, min y, max x, max y), the u32 record count and the u32 `MAP_FLAG_*` bits.
+const MAP_MAGIC: &[u8; 4] = b"CMAP";
+/// Bumped whenever the record layout changes.
+/// 1: road access byte
+/// 2: building orientation byte
+/// 3: bounding box and record count in the header
+/// 4: road subtype byte
+/// 5: building colour
+/// 6: area surface byte
+/// 7: flags for optional fields in the header
+/// 8: indoor records
+/// 9: high bit of the road subtype for planned roads
+/// 10: byte order after the magic
+const MAP_VERSION: u16 = 10;
+/// Buildings carry their footprint area and floor count (--emit-metrics).
+const MAP_FLAG_METRICS: u32 = 1;
+/// Buildings carry the index of their nearest palette colour (--palette-index).
+const MAP_FLAG_PALETTE: u32 = 2;
+/// Buildings carry an i8 outward normal per wall and a roof normal (--building-normals).
+const MAP_FLAG_NORMALS: u32 = 4;
+/// Building outline points carry the ground elevation under them as a third float (--building-ground).
+const MAP_FLAG_GROUND: u32 = 8;
+/// Areas carry an f32 border width after their surface byte (--area-border).
+const MAP_FLAG_BORDER: u32 = 16;
+/// Buildings end with a u16 count of wall triangles, each three xyz corners and an i8 xy normal (--bake-walls).
+const MAP_FLAG_WALLS: u32 = 32;
+
 const OBJ_BUILDING: u8 = 0;
 const OBJ_ROAD: u8 = 1;
+const OBJ_AREA: u8 = 2;
+const OBJ_PEAK: u8 = 3;
+const OBJ_PORTAL: u8 = 4;
+const OBJ_JUNCTION: u8 = 7;
+const OBJ_TREE: u8 = 8;
+const OBJ_INDOOR: u8 = 9;
 
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum BuildingKind {
     House, // siding, maybe brick, usually pitched roofs
     Tower, // skyscraper
@@ -62,19 +517,255 @@ enum BuildingKind {
 }
 
 #[repr(u8)]
+#[derive(Clone, Copy)]
 enum RoofKind {
     Flat
 }
 
-fn read_osm(path: &Path, region: &Region) -> Buffer {
-    let base_x = region.coord.easting;
-    let base_y = region.coord.northing;
+#[repr(u8)]
+enum PortalKind {
+    Tunnel,
+    Bridge
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+enum AreaKind {
+    Paved,
+    /// beaches, grass and other unbuilt ground
+    Natural
+}
+
+#[repr(u8)]
+enum IndoorKind {
+    Room,
+    Area,
+    Corridor,
+    Level,
+    Other
+}
+
+impl IndoorKind {
+    fn from_tag(value: &str) -> Self {
+        match value {
+            "room" => Self::Room,
+            "area" => Self::Area,
+            "corridor" => Self::Corridor,
+            "level" => Self::Level,
+            _ => Self::Other
+        }
+    }
+}
+
+/// First level of a `level` tag ("1", "-1", "0;1"), 0 if untagged.
+fn parse_level(value: Option<&str>) -> f32 {
+    value.and_then(|value| value.split(';').next())
+        .and_then(|level| level.trim().parse().ok())
+        .unwrap_or(0.0)
+}
+
+/// Above-ground storeys from `building:levels`: "3", "3.5", or the largest of a list or range ("2;3", "2-3").
+/// None for anything else, so the caller falls back to its default.
+fn parse_levels(value: &str) -> Option<f32> {
+    value.split([';', ',', '-'])
+        .map(|part| part.trim().parse::<f32>().ok().filter(|levels| levels.is_finite() && *levels >= 0.0))
+        .collect::<Option<Vec<f32>>>()?
+        .into_iter()
+        .reduce(f32::max)
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+enum Surface {
+    Unknown,
+    Paved,
+    Grass,
+    Sand,
+    Gravel,
+    Water,
+    Dirt
+}
+
+impl Surface {
+    /// Groups the many `surface=*` values into the few we draw differently.
+    fn parse(value: &str) -> Self {
+        match value {
+            "paved" | "asphalt" | "concrete" | "concrete:plates" | "concrete:lanes" | "paving_stones" | "sett" | "cobblestone" | "unhewn_cobblestone" | "bricks" | "metal" | "wood" => Self::Paved,
+            "grass" | "grass_paver" => Self::Grass,
+            "sand" => Self::Sand,
+            "gravel" | "fine_gravel" | "pebblestone" | "compacted" | "rock" => Self::Gravel,
+            "water" => Self::Water,
+            "unpaved" | "dirt" | "ground" | "earth" | "mud" => Self::Dirt,
+            _ => Self::Unknown
+        }
+    }
+}
+
+/// How an area is drawn.
+struct AreaStyle {
+    kind: AreaKind,
+    surface: Surface,
+    /// Width of the edge to draw inward from the outline (--area-border)
+    border: Option<f32>,
+}
+
+/// A width multiplier: a finite number above 0.
+fn parse_width_scale(value: &str) -> Result<f32, String> {
+    let scale: f32 = value.trim().parse().map_err(|_| format!("{} is not a number",value))?;
+    if !scale.is_finite() || scale <= 0.0 {
+        return Err(format!("{} is not a positive width scale",value));
+    }
+    Ok(scale)
+}
+
+/// False for tag values that say the feature isn't (or isn't yet / any longer) really there,
+/// like `building=no` or `highway=proposed`.
+fn is_existing_feature(value: &str) -> bool {
+    !matches!(value, "no" | "construction" | "proposed" | "demolished")
+}
+
+/// Parses an OSM length value ("12", "12 m", "40 ft", "40'") into meters.
+fn parse_length(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let (number, factor) = if let Some(v) = value.strip_suffix("ft") {
+        (v, 0.3048)
+    } else if let Some(v) = value.strip_suffix('\'') {
+        (v, 0.3048)
+    } else if let Some(v) = value.strip_suffix('m') {
+        (v, 1.0)
+    } else {
+        (value, 1.0)
+    };
+    let number: f32 = number.trim().parse().ok()?;
+    Some(number * factor)
+}
+
+/// Which way traffic may go along a way's nodes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Oneway {
+    No,
+    Forward,
+    /// `oneway=-1`, against the node order
+    Backward
+}
+
+/// Reads a `oneway` tag: yes/1/true run along the node order, -1 against it, anything else (no, reversible, ...) both ways.
+fn parse_oneway(value: Option<&str>) -> Oneway {
+    match value.map(str::trim) {
+        Some("yes") | Some("1") | Some("true") => Oneway::Forward,
+        Some("-1") => Oneway::Backward,
+        _ => Oneway::No
+    }
+}
+
+/// 2 bits per mode (access, motor_vehicle, bicycle, foot from low to high bits):
+/// 0 = yes, 1 = private, 2 = no. Modes without their own tag inherit `access`.
+fn road_access<'a>(tag: impl Fn(&str) -> Option<&'a str>) -> u8 {
+    fn parse(value: &str) -> u8 {
+        match value {
+            "private" | "customers" | "delivery" | "permit" => 1,
+            "no" => 2,
+            _ => 0
+        }
+    }
+    let access = tag("access").map(parse).unwrap_or(0);
+    let mut bits = access;
+    for (i,mode) in ["motor_vehicle", "bicycle", "foot"].iter().enumerate() {
+        let value = tag(mode).map(parse).unwrap_or(access);
+        bits |= value << ((i + 1) * 2);
+    }
+    bits
+}
+
+/// Most nodes a single record can hold (the count is written as a u16).
+const MAX_PATH_NODES: usize = u16::MAX as usize;
+
+/// Thins out paths longer than `max` nodes, keeping the first and last node.
+fn cap_path<T: Copy>(path: &[T], max: usize, way_id: i64, error_log: &mut ErrorLog) -> Vec<T> {
+    if path.len() <= max {
+        return path.to_vec();
+    }
+    let step = (path.len() - 1).div_ceil(max - 1);
+    let mut capped: Vec<T> = path[..path.len()-1].iter().step_by(step).copied().collect();
+    capped.push(path[path.len()-1]);
+    println!("> way {} has {} nodes, simplified to {}",way_id,path.len(),capped.len());
+    error_log.record(way_id, "too many nodes");
+    capped
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum DuplicatePolicy {
+    First,
+    Last
+}
+
+#[derive(Default, Debug, serde::Serialize)]
+struct OsmStats {
+    nodes: usize,
+    buildings: BTreeMap<String,usize>,
+    roads: BTreeMap<String,usize>,
+    areas: usize,
+    junctions: usize,
+    indoor: usize,
+    peaks: usize,
+    trees: usize,
+    portals: usize,
+    skipped_degenerate: usize,
+    skipped_filtered: usize,
+    skipped_indoor: usize,
+    skipped_missing_node: usize,
+    skipped_out_of_bounds: usize,
+    skipped_self_intersecting: usize,
+    skipped_duplicate: usize,
+    skipped_underground: usize,
+    skipped_small: usize,
+}
+
+impl OsmStats {
+    /// Ways that had to be skipped because of bad data.
+    pub fn ways_skipped(&self) -> usize {
+        self.skipped_degenerate + self.skipped_missing_node + self.skipped_self_intersecting
+    }
+
+    /// Number of records written to the map.
+    pub fn feature_count(&self) -> usize {
+        self.buildings.values().sum::<usize>() + self.roads.values().sum::<usize>()
+            + self.areas + self.junctions + self.indoor + self.peaks + self.trees + self.portals
+    }
+
+    pub fn print(&self) {
+        println!("> osm stats");
+        println!("  nodes: {}",self.nodes);
+        for (kind,count) in &self.buildings {
+            println!("  building {}: {}",kind,count);
+        }
+        for (kind,count) in &self.roads {
+            println!("  road {}: {}",kind,count);
+        }
+        println!("  areas: {}",self.areas);
+        println!("  junctions: {}",self.junctions);
+        println!("  indoor: {}",self.indoor);
+        println!("  peaks: {}",self.peaks);
+        println!("  trees: {}",self.trees);
+        println!("  portals: {}",self.portals);
+        println!("  skipped (degenerate): {}",self.skipped_degenerate);
+        println!("  skipped (tunnel/bridge/steps): {}",self.skipped_filtered);
+        println!("  skipped (indoor): {}",self.skipped_indoor);
+        println!("  skipped (missing node): {}",self.skipped_missing_node);
+        println!("  skipped (out of bounds): {}",self.skipped_out_of_bounds);
+        println!("  skipped (self-intersecting): {}",self.skipped_self_intersecting);
+        println!("  skipped (duplicate id): {}",self.skipped_duplicate);
+        println!("  skipped (underground): {}",self.skipped_underground);
+        println!("  skipped (too small): {}",self.skipped_small);
+    }
+}
 
-    fn is_building(way: &StringWay) -> bool {
-        way.tag("building").is_some()
+fn read_osm(path: &Path, region: &Region, args: &CommandArgs, rules: &Rules) -> (Buffer, OsmStats) {
+    fn is_building(way: &StringWay, rules: &Rules) -> bool {
+        rules.building(|key| way.tag(key)).is_some() || way.tag("building").is_some_and(is_existing_feature)
     }
 
-    fn building_height(way: &StringWay) -> f32 {
+    fn building_height(way: &StringWay, default_height: f32) -> f32 {
         if let Some(height) = way.tag("height") {
             // very bare-bones height parsing attempt, TODO units
             let height: Result<f32,_> = height.parse();
@@ -82,16 +773,14 @@ fn read_osm(path: &Path, region: &Region) -> Buffer {
                 return height;
             }
         }
-        if let Some(levels) = way.tag("building:levels") {
-            let levels: Result<f32,_> = levels.parse();
-            if let Ok(levels) = levels {
-                return levels * 3.0;
-            }
+        // building:levels:underground is deliberately left out, only storeys above ground add height
+        if let Some(levels) = way.tag("building:levels").and_then(parse_levels) {
+            return levels * 3.0;
         }
-        3.0
+        default_height
     }
 
-    fn building_infer_kind(way: &StringWay, area: f32, height: f32) -> BuildingKind {
+    fn building_infer_kind(_way: &StringWay, area: f32, height: f32) -> BuildingKind {
         if height > 10.0 {
             BuildingKind::Tower
         } else if area > 500.0 {
@@ -101,53 +790,46 @@ fn read_osm(path: &Path, region: &Region) -> Buffer {
         }
     }
 
+    /// Footprint area of a closed ring, whichever way it winds.
     fn path_area(path: &[(f32,f32)]) -> f32 {
-        // just finds the area of the bounds
-        // TODO actually calculate area
-        if path.len() < 3 {
-            return 0.0;
-        }
-        let mut x_min = 1f32/0.0;
-        let mut y_min = 1f32/0.0;
-        let mut x_max = -1f32/0.0;
-        let mut y_max = -1f32/0.0;
-        for (x,y) in path {
-            x_min = x_min.min(*x);
-            y_min = y_min.min(*y);
-            x_max = x_max.max(*x);
-            y_max = y_max.max(*y);
-        }
-        let w = x_max - x_min;
-        let h = y_max - y_min;
-        w * h
+        geometry::signed_area(path).abs()
     }
 
-    fn building_color(way: &StringWay) -> u32 {
-        if let Some(color) = way.tag("building:colour") {
-            println!("color = {}",color);
-        }
-        if let Some(color) = way.tag("roof:colour") {
-            println!("roof color = {}",color);
-        }
-        // ~
-        if let Some(color) = way.tag("building:material") {
-            println!("mat = {}",color);
-        }
-        if let Some(color) = way.tag("material") {
-            println!("dumb mat = {}",color);
-        }
-        if let Some(color) = way.tag("roof:material") {
-            println!("roof mat = {}",color);
-        }
-        0
+    /// Tagged level count, or one per 3m of height.
+    fn building_floors(way: &StringWay, height: f32) -> f32 {
+        way.tag("building:levels").and_then(parse_levels)
+            .unwrap_or((height / 3.0).round())
+            .max(1.0)
+    }
+
+    /// Wall colour as 0xRRGGBB.
+    fn building_color(way: &StringWay, unknown_colors: &mut HashSet<String>) -> u32 {
+        let material = way.tag("building:material").or(way.tag("material"));
+        colors::resolve_color(way.tag("building:colour"), material, unknown_colors)
     }
 
-    fn is_road(way: &StringWay) -> bool {
-        way.tag("highway").is_some()
+    fn is_road(way: &StringWay, rules: &Rules, args: &CommandArgs) -> bool {
+        rules.road(|key| way.tag(key)).is_some() || way.tag("highway").is_some_and(is_existing_feature)
+            || (args.construction_roads && is_planned_road(way))
+            || RoadSubtype::from_aeroway(way.tag("aeroway")).is_some()
+            || RoadSubtype::from_route(way).is_some()
+    }
+
+    /// highway=construction / proposed
+    fn is_planned_road(way: &StringWay) -> bool {
+        matches!(way.tag("highway"), Some("construction") | Some("proposed"))
+    }
+
+    /// The highway value, or for a planned road, what it's going to be (construction=* / proposed=*).
+    fn highway_class(way: &StringWay) -> Option<&str> {
+        match way.tag("highway") {
+            Some(planned @ ("construction" | "proposed")) => way.tag(planned),
+            highway => highway
+        }
     }
     
     fn is_road_oneway(way: &StringWay) -> bool {
-        way.tag("oneway").is_some()
+        parse_oneway(way.tag("oneway")) != Oneway::No
     }
 
     fn road_lanes(way: &StringWay) -> f32 {
@@ -164,37 +846,206 @@ fn read_osm(path: &Path, region: &Region) -> Buffer {
         2.0
     }
 
-    fn should_skip_road(way: &StringWay) -> bool {
-        way.tag("tunnel").is_some() || way.tag("bridge").is_some() || way.tag("highway") == Some("steps")
+    fn is_area_highway(way: &StringWay) -> bool {
+        let ids = way.nodes();
+        let closed = ids.len() > 3 && ids.first() == ids.last();
+        if !closed {
+            return false;
+        }
+        let highway_val = way.tag("highway");
+        way.tag("area") == Some("yes") || highway_val == Some("pedestrian") || highway_val == Some("service")
+    }
+
+    fn should_skip_road(way: &StringWay, args: &CommandArgs) -> bool {
+        way.tag("tunnel").is_some() || (way.tag("bridge").is_some() && !args.bridges) || way.tag("highway") == Some("steps")
+    }
+
+    /// Whether the way is below the surface: location=underground, a negative layer or a subway line.
+    fn is_underground(way: &StringWay) -> bool {
+        way.tag("location") == Some("underground")
+            || way.tag("layer").and_then(|layer| layer.trim().parse::<i32>().ok()).is_some_and(|layer| layer < 0)
+            || way.tag("railway") == Some("subway")
+    }
+
+    /// The way's layer=*, 0 when untagged.
+    fn road_layer(way: &StringWay) -> i32 {
+        way.tag("layer").and_then(|layer| layer.trim().parse().ok()).unwrap_or(0)
+    }
+
+    /// The way's layer=*, with bridges at least on layer 1.
+    fn bridge_layer(way: &StringWay) -> i32 {
+        way.tag("layer").and_then(|layer| layer.trim().parse().ok()).unwrap_or(1).max(1)
     }
 
     enum RoadKind {
         Road{lanes: f32},
+        Special(RoadSubtype),
         FootPath,
         BikePath
     }
 
+    /// Highway values (and service roads narrowed down by the service tag) that
+    /// need their own width and look, rather than a generic lane-counted road.
+    #[derive(Clone, Copy)]
+    enum RoadSubtype {
+        Service = 1,
+        Driveway = 2,
+        ParkingAisle = 3,
+        Alley = 4,
+        /// unpaved farm / forest track
+        Track = 5,
+        LivingStreet = 6,
+        Busway = 7,
+        Raceway = 8,
+        Runway = 9,
+        Taxiway = 10,
+        /// ferry route, drawn on the water at sea level
+        Ferry = 11
+    }
+
+    impl RoadSubtype {
+        fn from_tags(highway: Option<&str>, service: Option<&str>) -> Option<Self> {
+            Some(match highway? {
+                "service" => match service {
+                    Some("driveway") => Self::Driveway,
+                    Some("parking_aisle") => Self::ParkingAisle,
+                    Some("alley") => Self::Alley,
+                    _ => Self::Service
+                },
+                "track" => Self::Track,
+                "living_street" => Self::LivingStreet,
+                "busway" => Self::Busway,
+                "raceway" => Self::Raceway,
+                _ => return None
+            })
+        }
+
+        fn from_route(way: &StringWay) -> Option<Self> {
+            // ferry=* names the road class a route carries
+            (way.tag("route") == Some("ferry") || way.tag("highway") == Some("ferry") || way.tag("ferry").is_some()).then_some(Self::Ferry)
+        }
+
+        fn from_aeroway(aeroway: Option<&str>) -> Option<Self> {
+            match aeroway? {
+                "runway" => Some(Self::Runway),
+                "taxiway" => Some(Self::Taxiway),
+                _ => None
+            }
+        }
+
+        fn lanes(self) -> f32 {
+            match self {
+                Self::Service | Self::ParkingAisle | Self::LivingStreet => 1.5,
+                Self::Driveway | Self::Alley | Self::Track => 1.0,
+                Self::Busway => 2.0,
+                Self::Raceway => 3.0,
+                // without a width tag: a typical 45m runway and 22.5m taxiway at the default 3m lane width
+                Self::Runway => 15.0,
+                Self::Taxiway => 7.5,
+                Self::Ferry => 2.0
+            }
+        }
+
+        fn name(self) -> &'static str {
+            match self {
+                Self::Service => "service",
+                Self::Driveway => "driveway",
+                Self::ParkingAisle => "parking_aisle",
+                Self::Alley => "alley",
+                Self::Track => "track",
+                Self::LivingStreet => "living_street",
+                Self::Busway => "busway",
+                Self::Raceway => "raceway",
+                Self::Runway => "runway",
+                Self::Taxiway => "taxiway",
+                Self::Ferry => "ferry"
+            }
+        }
+    }
+
     impl RoadKind {
-        pub fn is_level_path(&self) -> bool {
+        pub fn half_width(&self, args: &CommandArgs) -> f32 {
+            match self {
+                Self::FootPath => args.footpath_width * 0.5,
+                Self::BikePath => args.bikepath_width * 0.5,
+                Self::Road { .. } | Self::Special(_) => self.lanes().unwrap() * args.lane_width * 0.5
+            }
+        }
+
+        /// Lane count for vehicle roads, None for paths.
+        pub fn lanes(&self) -> Option<f32> {
+            match self {
+                Self::Road { lanes } => Some(*lanes),
+                Self::Special(subtype) => Some(subtype.lanes()),
+                Self::FootPath | Self::BikePath => None
+            }
+        }
+
+        /// 0 for plain roads and paths
+        pub fn subtype(&self) -> u8 {
+            match self {
+                Self::Special(subtype) => *subtype as u8,
+                _ => 0
+            }
+        }
+
+        pub fn name(&self) -> &'static str {
+            match self {
+                Self::Road{..} => "road",
+                Self::Special(subtype) => subtype.name(),
+                Self::FootPath => "footpath",
+                Self::BikePath => "bikepath"
+            }
+        }
+
+        /// Window for smoothing elevation along the road, if any.
+        pub fn smoothing(&self, args: &CommandArgs) -> Option<f32> {
             match self {
-                Self::BikePath | Self::FootPath => true,
-                _ => false
+                Self::Special(RoadSubtype::Ferry) => None,
+                Self::FootPath | Self::BikePath => args.path_smoothing,
+                Self::Road { .. } | Self::Special(_) => args.road_smoothing
             }
         }
+
+        pub fn is_level_path(&self) -> bool {
+            matches!(self, Self::BikePath | Self::FootPath)
+        }
     }
 
-    fn road_kind(way: &StringWay) -> RoadKind {
-        let highway_val = way.tag("highway");
-        if highway_val == Some("footway") || highway_val == Some("path") || way.tag("footway").is_some() {
+    fn road_kind(way: &StringWay, rules: &Rules) -> RoadKind {
+        if let Some(rule) = rules.road(|key| way.tag(key)) {
+            return match rule.kind {
+                RoadClass::Road => RoadKind::Road{lanes: road_lanes(way)},
+                RoadClass::Footpath => RoadKind::FootPath,
+                RoadClass::Bikepath => RoadKind::BikePath
+            };
+        }
+        if let Some(subtype) = RoadSubtype::from_aeroway(way.tag("aeroway")).or_else(|| RoadSubtype::from_route(way)) {
+            return RoadKind::Special(subtype);
+        }
+        let highway_val = highway_class(way);
+        if highway_val == Some("footway") || highway_val == Some("path") || highway_val == Some("bridleway") || way.tag("footway").is_some() {
             RoadKind::FootPath
         } else if highway_val == Some("cycleway") {
             RoadKind::BikePath
+        } else if let Some(subtype) = RoadSubtype::from_tags(highway_val, way.tag("service")) {
+            RoadKind::Special(subtype)
         } else {
             let lanes = road_lanes(way);
             RoadKind::Road{lanes}
         }
     }
 
+    /// Half the road's width, times --road-width-scale.
+    fn road_half_width(way: &StringWay, kind: &RoadKind, rules: &Rules, args: &CommandArgs) -> f32 {
+        let half_width = rules.road(|key| way.tag(key)).and_then(|rule| rule.width)
+            // runways and taxiways are usually tagged with their width
+            .or_else(|| matches!(kind, RoadKind::Special(RoadSubtype::Runway | RoadSubtype::Taxiway)).then(|| way.tag("width").and_then(parse_length)).flatten())
+            .map(|width| width * 0.5)
+            .unwrap_or_else(|| kind.half_width(args));
+        half_width * args.road_width_scale
+    }
+
     fn mean_pos(way: &StringWay, nodes: &HashMap<i64,(f32,f32)>) -> (f32,f32) {
         let mut count = 0;
         let mut sum_x = 0.0;
@@ -208,43 +1059,254 @@ fn read_osm(path: &Path, region: &Region) -> Buffer {
         (sum_x / count as f32, sum_y / count as f32)
     }
 
+    /// Whether the ring winds counter-clockwise in local x/y, where y grows southward.
+    /// Rings that do are reversed before writing, so every outline is clockwise in local x/y:
+    /// counter-clockwise seen on a north-up map, with the outside of each edge on its left in local terms.
     fn is_ccw(points: &[(f32,f32)]) -> bool {
-        let mut sum = 0.0;
-        for i in 0..points.len() {
-            let (x1,y1) = points[i];
-            let (x2,y2) = points[(i+1)%points.len()];
-            sum += (x2 - x1)*(y2 + y1);
+        // clockwise on the map
+        geometry::map_signed_area(points) < 0.0
+    }
+
+    /// Closed ways that are drawn as paved ground without a rule for them.
+    fn is_paved_area(way: &StringWay) -> bool {
+        way.tag("aeroway") == Some("apron")
+    }
+
+    /// Closed ways that are drawn as ground cover.
+    fn is_natural_area(way: &StringWay) -> bool {
+        matches!(way.tag("natural"), Some("beach") | Some("sand")) || way.tag("landuse") == Some("grass")
+    }
+
+    /// The tagged surface, or what the area's kind implies.
+    fn area_surface(way: &StringWay, kind: AreaKind) -> Surface {
+        let tagged = way.tag("surface").map(Surface::parse).unwrap_or(Surface::Unknown);
+        match (tagged, kind) {
+            (Surface::Unknown, AreaKind::Paved) => Surface::Paved,
+            (Surface::Unknown, AreaKind::Natural) => match way.tag("natural") {
+                Some("beach") | Some("sand") => Surface::Sand,
+                _ => Surface::Grass
+            },
+            (tagged, _) => tagged
         }
-        sum < 0.0
     }
 
-    let mut buffer = Buffer::default();
+    /// Writes the outline (without a repeated final point) relative to `base`. Outlines are wound like buildings
+    /// (see is_ccw), so an inward border is on the right of each edge in local x/y.
+    fn write_area(buffer: &mut Buffer, extent: &mut Extent, region: &Region, offset: (f32,f32), base: (f32,f32), mut path: Vec<(f32,f32)>, style: AreaStyle) {
+        let (base_x,base_y) = base;
+        let base_elevation = region.get_elevation(base_x, base_y);
+
+        if is_ccw(&path) {
+            path.reverse();
+        }
+
+        buffer.write_byte(OBJ_AREA);
+        buffer.write_float(base_x + offset.0);
+        buffer.write_float(base_y + offset.1);
+        buffer.write_float(base_elevation);
+        buffer.write_byte(style.kind as u8);
+        buffer.write_byte(style.surface as u8);
+        if let Some(border) = style.border {
+            buffer.write_float(border);
+        }
+        buffer.write_short(path.len().try_into().expect("too many nodes"));
+        for (x,y) in path {
+            extent.add(x, y);
+            let e = region.get_elevation(x, y);
+            buffer.write_float(x - base_x);
+            buffer.write_float(y - base_y);
+            buffer.write_float(e - base_elevation);
+        }
+    }
+
+    let mut buffer = Buffer::new(args.endian);
+    let mut stats = OsmStats::default();
+    let mut extent = Extent::default();
+    let mut unknown_colors = HashSet::new();
+    let palette = args.palette_index.then(|| colors::load_palette(args.palette.as_deref()));
+    let mut error_log = ErrorLog::create(args.error_log.as_deref());
+
+    // positions are computed relative to the elevation map, this moves them to the output origin
+    let (offset_x,offset_y) = region.output_offset(args);
+
+    // features for --geojson, in lon/lat
+    let mut features = Vec::new();
+    let to_lon_lat = |x: f32, y: f32| {
+        let (lat,lon) = coords::local_to_latlon(region, x, y);
+        json!([lon, lat])
+    };
 
     let file = std::fs::File::open(path).unwrap();
-    let mut reader = osmio::xml::XMLReader::new(file);
+    let mut xml_reader;
+    let mut pbf_reader;
+    let mut o5m_reader;
+    let objects: Box<dyn Iterator<Item = StringOSMObj>> = match path.extension().and_then(|e| e.to_str()) {
+        Some("pbf") => {
+            pbf_reader = osmio::pbf::PBFReader::new(file);
+            Box::new(pbf_reader.objects())
+        }
+        Some("o5m") => {
+            o5m_reader = osmio::o5m::O5mReader::new(file);
+            Box::new(o5m_reader.objects())
+        }
+        _ => {
+            xml_reader = osmio::xml::XMLReader::new(file);
+            Box::new(xml_reader.objects())
+        }
+    };
+
+    // the same id can turn up more than once in merged extracts; keeping the last copy means reading everything first
+    let key = |obj: &StringOSMObj| (obj.object_type() as u8, obj.id());
+    let mut seen = HashSet::new();
+    let (objects, last_index): (Box<dyn Iterator<Item = StringOSMObj>>, _) = match args.duplicates {
+        DuplicatePolicy::First => (objects, None),
+        DuplicatePolicy::Last => {
+            let all: Vec<_> = objects.collect();
+            let last_index: HashMap<_,_> = all.iter().enumerate().map(|(i,obj)| (key(obj), i)).collect();
+            (Box::new(all.into_iter()), Some(last_index))
+        }
+    };
 
     let mut nodes = HashMap::new();
 
-    for obj in reader.objects() {
+    // node id -> indices of the road records passing through it
+    let mut road_membership: HashMap<i64,Vec<u32>> = HashMap::new();
+    let mut graph = RoadGraph::default();
+    // buildings as boxes: base x/y, ground, height, 4 corners relative to the base
+    let mut lod = Buffer::new(args.endian);
+    let mut lod_count: u32 = 0;
+    let mut road_count: u32 = 0;
+    // --only-buildings and --only-roads add up; with neither, everything is written
+    let only = args.only_buildings || args.only_roads;
+    let want_buildings = !only || args.only_buildings;
+    let want_roads = !only || args.only_roads;
+    let want_other = !only;
+
+    for (i,obj) in objects.enumerate() {
+        let duplicate = match &last_index {
+            Some(last_index) => last_index[&key(&obj)] != i,
+            None => !seen.insert(key(&obj))
+        };
+        if duplicate {
+            stats.skipped_duplicate += 1;
+            continue;
+        }
         if let Some(node) = obj.as_node() {
             let (lat,long) = node.lat_lon_f64().unwrap();
-            let (mut y,mut x,_) = utm::to_utm_wgs84_no_zone(lat, long);
-            x -= base_x;
-            y -= base_y;
-            y = -y;
-            nodes.insert(node.id(), (x as f32,y as f32));
+            // local y points south, flipping winding relative to lat/lon; see geometry::signed_area
+            let (x,y) = coords::latlon_to_local(region, lat, long);
+            nodes.insert(node.id(), (x,y));
+
+            if node.tag("natural") == Some("peak") && want_other && region.in_crop(x, y, args) {
+                // surveyed elevation wins over the DEM sample
+                let elevation = node.tag("ele").and_then(parse_length).map(|ele| ele - region.datum).unwrap_or_else(|| region.get_elevation(x, y));
+                extent.add(x, y);
+                buffer.write_byte(OBJ_PEAK);
+                buffer.write_float(x + offset_x);
+                buffer.write_float(y + offset_y);
+                buffer.write_float(elevation);
+                buffer.write_string(node.tag("name").unwrap_or(""));
+                stats.peaks += 1;
+            }
+
+            if node.tag("natural") == Some("tree") && want_other && region.in_crop(x, y, args) {
+                // height and crown diameter are 0 when not tagged
+                extent.add(x, y);
+                buffer.write_byte(OBJ_TREE);
+                buffer.write_float(x + offset_x);
+                buffer.write_float(y + offset_y);
+                buffer.write_float(region.get_elevation(x, y));
+                buffer.write_float(node.tag("height").and_then(parse_length).unwrap_or(0.0));
+                buffer.write_float(node.tag("diameter_crown").and_then(parse_length).unwrap_or(0.0));
+                stats.trees += 1;
+            }
         } else if let Some(way) = obj.as_way() {
-            if is_building(&way) {
+            // indoor rooms and corridors, often mapped without building tags but not to be drawn as buildings
+            let indoor = way.tag("indoor").is_some_and(is_existing_feature);
+            if indoor && !args.indoor {
+                stats.skipped_indoor += 1;
+                continue;
+            }
+            let building = !indoor && is_building(way, rules);
+            let ids = way.nodes();
+            let tag_area = !indoor && ids.len() > 3 && ids.first() == ids.last() && (rules.is_area(|key| way.tag(key)) || is_natural_area(way) || is_paved_area(way));
+            if !indoor && !building && !tag_area && !is_road(way, rules, args) {
+                continue;
+            }
+            if (building && !want_buildings) || ((indoor || tag_area) && !want_other) || (!indoor && !building && !tag_area && !want_roads) {
+                continue;
+            }
+            // indoor features carry their own level instead
+            if !indoor && !args.include_underground && is_underground(way) {
+                stats.skipped_underground += 1;
+                continue;
+            }
+            let ids = way.nodes();
+            if ids.iter().any(|id| !nodes.contains_key(id)) {
+                stats.skipped_missing_node += 1;
+                error_log.record(way.id(), "missing node");
+                continue;
+            }
+            if ids.len() < if building { 4 } else { 2 } {
+                stats.skipped_degenerate += 1;
+                error_log.record(way.id(), "degenerate");
+                continue;
+            }
+            if ids.iter().all(|id| { let (x,y) = nodes[id]; !region.in_crop(x, y, args) }) {
+                stats.skipped_out_of_bounds += 1;
+                error_log.record(way.id(), "out of bounds");
+                continue;
+            }
+            if (building || tag_area || is_area_highway(way)) && path_area(&ids.iter().map(|id| nodes[id]).collect::<Vec<_>>()) < args.min_area {
+                stats.skipped_small += 1;
+                error_log.record(way.id(), "too small");
+                continue;
+            }
+            // walls and fills of a crossed outline come out inside out
+            if (building || tag_area || is_area_highway(way)) && ids.first() == ids.last() && geometry::is_self_intersecting(&ids[..ids.len()-1].iter().map(|id| nodes[id]).collect::<Vec<_>>()) {
+                stats.skipped_self_intersecting += 1;
+                error_log.record(way.id(), "self-intersecting");
+                continue;
+            }
+
+            if indoor {
+                let ids = way.nodes();
+                let closed = ids.len() > 3 && ids.first() == ids.last();
+                let ids = if closed { &ids[..ids.len()-1] } else { ids };
+                let ids = cap_path(ids, MAX_PATH_NODES, way.id(), &mut error_log);
+                let path: Vec<(f32,f32)> = ids.iter().map(|id| nodes[id]).collect();
                 let (base_x,base_y) = mean_pos(way, &nodes);
+                let base_elevation = region.get_elevation(base_x, base_y);
+                let kind = IndoorKind::from_tag(way.tag("indoor").unwrap());
+
+                extent.add(base_x, base_y);
+                buffer.write_byte(OBJ_INDOOR);
+                buffer.write_float(base_x + offset_x);
+                buffer.write_float(base_y + offset_y);
+                buffer.write_float(base_elevation);
+                buffer.write_float(parse_level(way.tag("level")));
+                buffer.write_byte(kind as u8);
+                buffer.write_short(path.len().try_into().expect("too many nodes"));
+                for (x,y) in path {
+                    extent.add(x, y);
+                    buffer.write_float(x - base_x);
+                    buffer.write_float(y - base_y);
+                }
+                stats.indoor += 1;
+            } else if building {
                 let mut ground_top = -1.0 / 0.0;
                 let mut ground_bot = 1.0 / 0.0;
 
                 let ids = way.nodes();
-                // do not include duplicate final node
-                let path_len = ids.len()-1;
+                // do not include duplicate final node, and leave room for two wall triangles per edge
+                let max_nodes = if args.bake_walls { MAX_PATH_NODES / 2 } else { MAX_PATH_NODES };
+                let ids = cap_path(&ids[..ids.len()-1], max_nodes, way.id(), &mut error_log);
+                let path_len = ids.len();
                 let mut path = Vec::with_capacity(path_len);
-                for i in 0..path_len {
-                    let (x,y) = nodes.get(&ids[i]).unwrap();
+                // terrain under each outline point, kept in step with the path for --building-ground
+                let mut ground = Vec::with_capacity(path_len);
+                for id in ids.iter() {
+                    let (x,y) = nodes.get(id).unwrap();
                     let e = region.get_elevation(*x, *y);
                     if e > ground_top {
                         ground_top = e;
@@ -252,57 +1314,214 @@ fn read_osm(path: &Path, region: &Region) -> Buffer {
                     if e < ground_bot {
                         ground_bot = e;
                     }
-                    path.push((*x - base_x, *y - base_y));
+                    path.push((*x, *y));
+                    ground.push(e);
+                }
+
+                let (base_x,base_y) = geometry::polygon_centroid(&path).unwrap_or_else(|| mean_pos(way, &nodes));
+                for (x,y) in path.iter_mut() {
+                    *x -= base_x;
+                    *y -= base_y;
                 }
                 if is_ccw(&path) {
                     path.reverse();
+                    ground.reverse();
                 }
 
-                let mut height = building_height(way);
+                let rule = rules.building(|key| way.tag(key));
+                let default_height = rule.and_then(|rule| rule.height)
+                    .or(rules.default_building_height)
+                    .unwrap_or(args.min_building_height.unwrap_or(3.0));
+                let mut height = building_height(way, default_height);
+                if let Some(min_height) = args.min_building_height {
+                    height = height.max(min_height);
+                }
                 let area = path_area(&path);
-                let kind = building_infer_kind(way, area, height);
+                let kind = rule.and_then(|rule| rule.kind).unwrap_or_else(|| building_infer_kind(way, area, height));
                 let roof_kind = RoofKind::Flat;
                 // bump up height for non-houses
                 match kind {
                     BuildingKind::Commercial | BuildingKind::Industrial => {
-                        height = height.max(6.0)
+                        height = height.max(args.min_commercial_height)
+                    }
+                    BuildingKind::Tower => {
+                        if let Some(min_height) = args.min_tower_height {
+                            height = height.max(min_height);
+                        }
                     }
                     _ => ()
                 }
 
+                *stats.buildings.entry(format!("{:?}",kind)).or_default() += 1;
+                let color = building_color(way, &mut unknown_colors);
+
+                if args.geojson {
+                    let mut ring: Vec<_> = path.iter().map(|(x,y)| to_lon_lat(x + base_x, y + base_y)).collect();
+                    ring.push(ring[0].clone());
+                    features.push(json!({
+                        "type": "Feature",
+                        "geometry": { "type": "Polygon", "coordinates": [ring] },
+                        "properties": { "id": way.id(), "object": "building", "kind": format!("{:?}",kind), "height": height, "ground_bot": ground_bot, "ground_top": ground_top, "color": format!("#{:06x}",color) }
+                    }));
+                }
+
                 buffer.write_byte(OBJ_BUILDING);
-                buffer.write_float(base_x);
-                buffer.write_float(base_y);
+                buffer.write_float(base_x + offset_x);
+                buffer.write_float(base_y + offset_y);
                 buffer.write_float(ground_bot);
                 buffer.write_float(ground_top);
                 buffer.write_float(height);
                 buffer.write_byte(kind as u8);
                 buffer.write_byte(roof_kind as u8);
+                // longest wall direction, 0-255 covering 0-180 degrees
+                let orientation = geometry::dominant_orientation(&path) / f32::consts::PI * 256.0;
+                buffer.write_byte((orientation.round() as u32 % 256) as u8);
+                buffer.write_int(color);
+                if let Some(palette) = &palette {
+                    buffer.write_byte(colors::nearest_palette_index(color, palette));
+                }
+                if args.emit_metrics {
+                    buffer.write_float(area);
+                    buffer.write_short(building_floors(way, height) as u16);
+                }
                 buffer.write_short(path.len().try_into().expect("too many nodes"));
-                for (x,y) in path {
-                    buffer.write_float(x);
-                    buffer.write_float(y);
+                for ((x,y),e) in path.iter().zip(&ground) {
+                    extent.add(x + base_x, y + base_y);
+                    buffer.write_float(*x);
+                    buffer.write_float(*y);
+                    if args.building_ground {
+                        buffer.write_float(*e);
+                    }
+                }
+                if args.emit_building_lod {
+                    let (corners,_) = geometry::min_area_rect(&path);
+                    lod.write_float(base_x + offset_x);
+                    lod.write_float(base_y + offset_y);
+                    lod.write_float(ground_bot);
+                    lod.write_float(height);
+                    for (x,y) in corners {
+                        lod.write_float(x);
+                        lod.write_float(y);
+                    }
+                    lod_count += 1;
+                }
+                if args.building_normals {
+                    // one per wall, then the roof's
+                    for (x,y) in geometry::outward_normals(&path) {
+                        buffer.write_unit_i8(x);
+                        buffer.write_unit_i8(y);
+                    }
+                    let roof_normal = match roof_kind {
+                        RoofKind::Flat => Vector3::new(0.0f32,0.0,1.0)
+                    };
+                    buffer.write_unit_vec3_i8(roof_normal);
+                }
+                if args.bake_walls {
+                    let walls = geometry::wall_triangles(&path, &ground, ground_top + height);
+                    buffer.write_short(walls.len().try_into().expect("too many walls"));
+                    for (corners,(nx,ny)) in walls {
+                        for (x,y,z) in corners {
+                            buffer.write_float(x);
+                            buffer.write_float(y);
+                            buffer.write_float(z);
+                        }
+                        buffer.write_unit_i8(nx);
+                        buffer.write_unit_i8(ny);
+                    }
                 }
                 
-            } else if is_road(&way) {
-                if should_skip_road(&way) {
+            } else if tag_area || is_road(way, rules, args) {
+                if args.emit_graph && !tag_area && !is_area_highway(way) {
+                    graph.add_road(way.nodes().to_vec(), parse_oneway(way.tag("oneway")), way.tag("maxspeed"));
+                }
+                if !tag_area && should_skip_road(way, args) {
+                    let portal_kind = if way.tag("tunnel").is_some() {
+                        Some(PortalKind::Tunnel)
+                    } else if way.tag("bridge").is_some() {
+                        Some(PortalKind::Bridge)
+                    } else {
+                        None
+                    };
+                    if let (true, Some(portal_kind)) = (args.portals, portal_kind) {
+                        // markers where the structure meets the ground, facing into it
+                        let ids = way.nodes();
+                        let half_width = road_half_width(way, &road_kind(way, rules), rules, args);
+                        let portal_kind = portal_kind as u8;
+                        for (end,next) in [(ids[0],ids[1]), (ids[ids.len()-1],ids[ids.len()-2])] {
+                            let (x,y) = nodes[&end];
+                            let end = Vector2::new(x, y);
+                            let (x,y) = nodes[&next];
+                            let next = Vector2::new(x, y);
+                            let dir = (next - end).try_normalize(1e-6).unwrap_or(Vector2::new(1.0,0.0));
+                            extent.add(end.x, end.y);
+                            buffer.write_byte(OBJ_PORTAL);
+                            buffer.write_float(end.x + offset_x);
+                            buffer.write_float(end.y + offset_y);
+                            buffer.write_float(region.get_elevation(end.x, end.y));
+                            buffer.write_byte(portal_kind);
+                            buffer.write_float(dir.x);
+                            buffer.write_float(dir.y);
+                            buffer.write_float(half_width);
+                        }
+                        stats.portals += 2;
+                    }
+                    stats.skipped_filtered += 1;
                     continue;
                 }
-                let kind = road_kind(&way);
-                let half_width = match kind {
-                    RoadKind::FootPath | RoadKind::BikePath => 1.0,
-                    RoadKind::Road { lanes } => lanes as f32 * 1.5
-                };
-
-                let (base_x,base_y) = mean_pos(way, &nodes);
-                let base_elevation = region.get_elevation(base_x, base_y);
+                if tag_area || is_area_highway(way) {
+                    if !want_other {
+                        continue;
+                    }
+                    let kind = if is_natural_area(way) { AreaKind::Natural } else { AreaKind::Paved };
+                    let surface = area_surface(way, kind);
+                    if args.geojson {
+                        let ring: Vec<_> = way.nodes().iter().map(|id| { let (x,y) = nodes[id]; to_lon_lat(x, y) }).collect();
+                        features.push(json!({
+                            "type": "Feature",
+                            "geometry": { "type": "Polygon", "coordinates": [ring] },
+                            "properties": { "id": way.id(), "object": "area", "kind": format!("{:?}",kind), "surface": format!("{:?}",surface) }
+                        }));
+                    }
+                    let ids = way.nodes();
+                    // do not include duplicate final node
+                    let path = cap_path(&ids[..ids.len()-1], MAX_PATH_NODES, way.id(), &mut error_log).iter().map(|id| nodes[id]).collect();
+                    write_area(&mut buffer, &mut extent, region, (offset_x,offset_y), mean_pos(way, &nodes), path, AreaStyle { kind, surface, border: args.area_border });
+                    stats.areas += 1;
+                    continue;
+                }
+                let kind = road_kind(way, rules);
+                *stats.roads.entry(kind.name().to_owned()).or_default() += 1;
+                let half_width = road_half_width(way, &kind, rules, args);
+
+                let (base_x,base_y) = mean_pos(way, &nodes);
+                // ferries lie on the water whatever the map says is under them
+                let sea_level = matches!(kind, RoadKind::Special(RoadSubtype::Ferry));
+                let base_elevation = if sea_level { -region.datum } else { region.get_elevation(base_x, base_y) };
+
+                for id in way.nodes() {
+                    let roads = road_membership.entry(*id).or_default();
+                    if roads.last() != Some(&road_count) {
+                        roads.push(road_count);
+                    }
+                }
+                road_count += 1;
+
+                if args.geojson {
+                    let line: Vec<_> = way.nodes().iter().map(|id| { let (x,y) = nodes[id]; to_lon_lat(x, y) }).collect();
+                    let lanes = kind.lanes().unwrap_or(1.0);
+                    features.push(json!({
+                        "type": "Feature",
+                        "geometry": { "type": "LineString", "coordinates": line },
+                        "properties": { "id": way.id(), "object": "road", "kind": kind.name(), "lanes": lanes, "oneway": is_road_oneway(way), "half_width": half_width }
+                    }));
+                }
 
                 buffer.write_byte(OBJ_ROAD);
-                buffer.write_float(base_x);
-                buffer.write_float(base_y);
+                buffer.write_float(base_x + offset_x);
+                buffer.write_float(base_y + offset_y);
                 buffer.write_float(base_elevation);
 
-                if let RoadKind::Road { lanes } = kind {
+                if let Some(lanes) = kind.lanes() {
                     let kind = if is_road_oneway(way) { 2 } else { 1 };
                     buffer.write_byte(kind);
                     buffer.write_byte(lanes.ceil() as u8);
@@ -310,10 +1529,32 @@ fn read_osm(path: &Path, region: &Region) -> Buffer {
                     buffer.write_byte(0);
                     buffer.write_byte(1);
                 }
+                buffer.write_byte(road_access(|key| way.tag(key)));
+                // high bit marks roads still being built or only planned
+                let planned_flag = if is_planned_road(way) { 0x80 } else { 0 };
+                buffer.write_byte(kind.subtype() | planned_flag);
                 // type
 
-                let ids = way.nodes();
-                let path_len = ids.len();
+                let mut centers: Vec<Vector2<f32>> = Vec::with_capacity(way.nodes().len());
+                for id in way.nodes() {
+                    let (x,y) = nodes.get(id).unwrap();
+                    let center = Vector2::new(*x, *y);
+                    // split long segments so the ribbon follows the terrain between nodes
+                    if let (Some(spacing), Some(prev)) = (args.road_drape_spacing, centers.last().copied()) {
+                        let length = (center - prev).norm();
+                        let steps = (length / spacing.max(0.1)).ceil() as usize;
+                        for step in 1..steps {
+                            centers.push(prev.lerp(&center, step as f32 / steps as f32));
+                        }
+                    }
+                    centers.push(center);
+                }
+                // oneway roads run in their direction of travel
+                let mut centers = cap_path(&centers, MAX_PATH_NODES, way.id(), &mut error_log);
+                if parse_oneway(way.tag("oneway")) == Oneway::Backward {
+                    centers.reverse();
+                }
+                let path_len = centers.len();
                 buffer.write_short(path_len.try_into().expect("too many nodes"));
                 
                 struct RoadNode {
@@ -326,10 +1567,9 @@ fn read_osm(path: &Path, region: &Region) -> Buffer {
 
                 let mut base_path = Vec::with_capacity(path_len);
 
-                for id in ids {
-                    let (x,y) = nodes.get(id).unwrap();
+                for center in centers {
                     base_path.push(RoadNode{
-                        center: Vector2::new(*x, *y),
+                        center,
                         left: Vector3::default(),
                         right: Vector3::default(),
                         normal: Vector3::new(0.0,0.0,1.0),
@@ -338,10 +1578,52 @@ fn read_osm(path: &Path, region: &Region) -> Buffer {
                 }
 
                 let make3d = |coord: Vector2<f32>| {
-                    let e = region.get_elevation(coord.x, coord.y);
+                    let e = if sea_level { -region.datum } else { region.get_elevation(coord.x, coord.y) };
                     Vector3::new(coord.x - base_x,coord.y - base_y, e - base_elevation)
                 };
 
+                // how far each node's smoothed centerline elevation is from the terrain
+                let mut smoothing_shift = vec![0.0; base_path.len()];
+                if let Some(window) = kind.smoothing(args) {
+                    let mut distances = Vec::with_capacity(base_path.len());
+                    let mut distance = 0.0;
+                    for i in 0..base_path.len() {
+                        if i > 0 {
+                            distance += (base_path[i].center - base_path[i-1].center).norm();
+                        }
+                        distances.push(distance);
+                    }
+                    let raw: Vec<f32> = base_path.iter().map(|node| region.get_elevation(node.center.x, node.center.y)).collect();
+                    let smoothed = geometry::smooth_profile(&distances, &raw, window);
+                    for i in 0..raw.len() {
+                        smoothing_shift[i] = smoothed[i] - raw[i];
+                    }
+                }
+
+                // a bridge deck runs straight between its ends, lifted where needed to clear what's beneath by its layer
+                if args.bridges && way.tag("bridge").is_some() && base_path.len() > 2 {
+                    // a tagged height is the clearance under the deck
+                    let clearance = way.tag("height").and_then(parse_length).unwrap_or_else(|| bridge_layer(way) as f32 * args.bridge_clearance);
+                    let raw: Vec<f32> = base_path.iter().map(|node| region.get_elevation(node.center.x, node.center.y)).collect();
+                    let mut distances = vec![0.0];
+                    for i in 1..base_path.len() {
+                        distances.push(distances[i-1] + (base_path[i].center - base_path[i-1].center).norm());
+                    }
+                    let total = distances[distances.len()-1].max(1e-3);
+                    let (start,end) = (raw[0], raw[raw.len()-1]);
+                    for i in 1..raw.len()-1 {
+                        let span = start + (end - start) * distances[i] / total;
+                        smoothing_shift[i] = span.max(raw[i] + clearance) - raw[i];
+                    }
+                }
+
+                if let Some(layer_height) = args.layer_height {
+                    let lift = road_layer(way) as f32 * layer_height;
+                    for shift in smoothing_shift.iter_mut() {
+                        *shift += lift;
+                    }
+                }
+
                 // place left and right nodes
                 for i in 0..base_path.len() {
                     let node = &base_path[i];
@@ -378,12 +1660,16 @@ fn read_osm(path: &Path, region: &Region) -> Buffer {
 
                     let mut left = make3d(node.center + dir_side * half_width * width_mul);
                     let mut right = make3d(node.center - dir_side * half_width * width_mul);
+                    left.z += smoothing_shift[i];
+                    right.z += smoothing_shift[i];
 
                     if kind.is_level_path() {
                         let z = left.z.max(right.z);
                         left.z = z;
                         right.z = z;
                     }
+                    left.z += args.road_z_offset;
+                    right.z += args.road_z_offset;
 
                     let node = &mut base_path[i];
                     node.left = left;
@@ -423,40 +1709,155 @@ fn read_osm(path: &Path, region: &Region) -> Buffer {
                 }
 
                 for node in base_path {
-                    buffer.write_float(node.left.x);
-                    buffer.write_float(node.left.y);
-                    buffer.write_float(node.left.z);
-                    buffer.write_float(node.right.x);
-                    buffer.write_float(node.right.y);
-                    buffer.write_float(node.right.z);
-                    buffer.write_float(node.normal.x);
-                    buffer.write_float(node.normal.y);
-                    buffer.write_float(node.normal.z);
-                    buffer.write_float(node.direction.x);
-                    buffer.write_float(node.direction.y);
-                    buffer.write_float(node.direction.z);
+                    extent.add(node.left.x + base_x, node.left.y + base_y);
+                    extent.add(node.right.x + base_x, node.right.y + base_y);
+                    buffer.write_vec3_f32(node.left);
+                    buffer.write_vec3_f32(node.right);
+                    buffer.write_vec3_f32(node.normal);
+                    buffer.write_vec3_f32(node.direction);
                 }
             }
         }
     }
 
-    buffer
+    // junctions: any node shared by more than one road
+    let mut junctions: Vec<_> = road_membership.into_iter().filter(|(_,roads)| roads.len() > 1).collect();
+    junctions.sort_by_key(|(id,_)| *id);
+    stats.junctions = junctions.len();
+    for (id,roads) in junctions {
+        let (x,y) = nodes.get(&id).unwrap();
+        extent.add(*x, *y);
+        buffer.write_byte(OBJ_JUNCTION);
+        buffer.write_float(*x + offset_x);
+        buffer.write_float(*y + offset_y);
+        buffer.write_float(region.get_elevation(*x, *y));
+        buffer.write_short(roads.len().try_into().expect("too many roads"));
+        for road in roads {
+            buffer.write_int(road);
+        }
+    }
+
+    stats.nodes = nodes.len();
+
+    if args.emit_building_lod {
+        let mut lod_file = Buffer::new(args.endian);
+        lod_file.write_int(lod_count);
+        lod_file.bytes.extend_from_slice(&lod.bytes);
+        let crc = lod_file.save(&region.out_dir(args), "buildings_lod", args.compression);
+        Manifest::record_checksums(&region.out_dir(args), &[("buildings_lod.bin.gz".to_owned(), crc)]);
+    }
+
+    if args.emit_graph {
+        let crc = graph.write(&nodes, region, (offset_x,offset_y), args.endian).save(&region.out_dir(args), "graph", args.compression);
+        Manifest::record_checksums(&region.out_dir(args), &[("graph.bin.gz".to_owned(), crc)]);
+        println!("> wrote graph");
+    }
+
+    if args.geojson {
+        let collection = json!({ "type": "FeatureCollection", "features": features });
+        let out_path = format!("{}/map.geojson",region.out_dir(args));
+        std::fs::write(&out_path, serde_json::to_string(&collection).unwrap()).unwrap();
+        println!("> wrote {}",out_path);
+    }
+
+    if stats.feature_count() == 0 {
+        println!("> warning: no map features found, writing an empty map");
+    }
+
+    // header: which record layout follows, the bounding box of everything written (in output
+    // coordinates) and the record count
+    let mut header = Buffer::new(args.endian);
+    header.bytes.extend_from_slice(MAP_MAGIC);
+    header.bytes.push(args.endian as u8);
+    header.write_short(MAP_VERSION);
+    if extent.is_empty() {
+        extent = Extent { min_x: 0.0, min_y: 0.0, max_x: 0.0, max_y: 0.0 };
+    }
+    header.write_float(extent.min_x + offset_x);
+    header.write_float(extent.min_y + offset_y);
+    header.write_float(extent.max_x + offset_x);
+    header.write_float(extent.max_y + offset_y);
+    header.write_int(stats.feature_count().try_into().expect("too many features"));
+    let mut flags = 0;
+    if args.emit_metrics {
+        flags |= MAP_FLAG_METRICS;
+    }
+    if palette.is_some() {
+        flags |= MAP_FLAG_PALETTE;
+    }
+    if args.building_normals {
+        flags |= MAP_FLAG_NORMALS;
+    }
+    if args.building_ground {
+        flags |= MAP_FLAG_GROUND;
+    }
+    if args.area_border.is_some() {
+        flags |= MAP_FLAG_BORDER;
+    }
+    if args.bake_walls {
+        flags |= MAP_FLAG_WALLS;
+    }
+    header.write_int(flags);
+    header.bytes.extend_from_slice(&buffer.bytes);
+    // the validator reads records back by their layout, so any writer change it doesn't know about shows up here
+    debug_assert_eq!(validate::validate_bytes(&header.bytes, validate::OutputKind::Map, args.endian), Ok(()));
+
+    (header, stats)
+}
+
+/// Creates a directory and any missing parents. Safe to call from several threads at once;
+/// only a directory that already exists is tolerated, anything else (permissions, etc) panics.
+fn ensure_dir_exists(path: &str) {
+    match std::fs::create_dir_all(path) {
+        Ok(()) => (),
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists && Path::new(path).is_dir() => (),
+        Err(err) => panic!("failed to create directory {}: {}",path,err)
+    }
+}
+
+/// Byte order of output buffers, recorded in the manifest and as the map header's byte-order byte.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+enum Endian {
+    /// Little-endian
+    #[default]
+    Le = 0,
+    /// Big-endian (network order)
+    Be = 1
+}
+
+impl Endian {
+    pub fn name(self) -> &'static str {
+        match self {
+            Endian::Le => "le",
+            Endian::Be => "be"
+        }
+    }
 }
 
 #[derive(Default)]
 struct Buffer {
     bytes: Vec<u8>,
+    endian: Endian,
 }
 
 impl Buffer {
-    pub fn save(&self, region: &str, filename: &str) {
-        let out_path = format!("output/{}/{}.bin.gz",region,filename);
+    pub fn new(endian: Endian) -> Self {
+        Buffer { bytes: Vec::new(), endian }
+    }
+
+    /// Returns the crc32 of the compressed file.
+    pub fn save(&self, out_dir: &str, filename: &str, level: u32) -> u32 {
+        let out_path = format!("{}/{}.bin.gz",out_dir,filename);
+        ensure_dir_exists(out_dir);
 
-        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
         encoder.write_all(&self.bytes).unwrap();
         let data = encoder.finish().unwrap();
 
+        let mut crc = flate2::Crc::new();
+        crc.update(&data);
         std::fs::write(Path::new(&out_path), data).unwrap();
+        crc.sum()
     }
 
     pub fn write_byte(&mut self, x: u8) {
@@ -464,16 +1865,1488 @@ impl Buffer {
     }
 
     pub fn write_short(&mut self, x: u16) {
-        let bytes = x.to_le_bytes();
-        self.bytes.push(bytes[0]);
-        self.bytes.push(bytes[1]);
+        let bytes = match self.endian {
+            Endian::Le => x.to_le_bytes(),
+            Endian::Be => x.to_be_bytes()
+        };
+        self.bytes.extend_from_slice(&bytes);
+    }
+
+    pub fn write_int(&mut self, x: u32) {
+        let bytes = match self.endian {
+            Endian::Le => x.to_le_bytes(),
+            Endian::Be => x.to_be_bytes()
+        };
+        self.bytes.extend_from_slice(&bytes);
+    }
+
+    pub fn write_long(&mut self, x: u64) {
+        let bytes = match self.endian {
+            Endian::Le => x.to_le_bytes(),
+            Endian::Be => x.to_be_bytes()
+        };
+        self.bytes.extend_from_slice(&bytes);
+    }
+
+    /// u16 byte length followed by utf-8
+    pub fn write_string(&mut self, x: &str) {
+        self.write_short(x.len().try_into().expect("string too long"));
+        self.bytes.extend_from_slice(x.as_bytes());
     }
 
     pub fn write_float(&mut self, x: f32) {
-        let bytes = x.to_le_bytes();
-        self.bytes.push(bytes[0]);
-        self.bytes.push(bytes[1]);
-        self.bytes.push(bytes[2]);
-        self.bytes.push(bytes[3]);
+        self.write_int(x.to_bits());
+    }
+
+    /// A component of a unit vector as a signed byte, -1..1 to -127..127.
+    pub fn write_unit_i8(&mut self, x: f32) {
+        self.write_byte(quantize_unit(x) as u8);
+    }
+
+    pub fn write_unit_vec3_i8(&mut self, v: Vector3<f32>) {
+        self.write_unit_i8(v.x);
+        self.write_unit_i8(v.y);
+        self.write_unit_i8(v.z);
+    }
+
+    pub fn write_vec3_f32(&mut self, v: Vector3<f32>) {
+        self.write_float(v.x);
+        self.write_float(v.y);
+        self.write_float(v.z);
+    }
+}
+
+/// -1..1 to -127..127, rounded to nearest. Out of range values (and NaN, as 0) are clamped.
+fn quantize_unit(x: f32) -> i8 {
+    (x * 127.0).round().clamp(-127.0, 127.0) as i8
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write as _;
+
+    use region::UTMCoord;
+
+    use super::*;
+
+    /// Magic, version, bounding box, record count and flags.
+    const MAP_HEADER_SIZE: usize = 31;
+
+    /// Builds a small OSM file from local coordinates, for feeding through `read_osm`.
+    #[derive(Default)]
+    struct OsmFixture {
+        nodes: String,
+        ways: String,
+        next_id: i64,
+        rules: Rules,
+    }
+
+    impl OsmFixture {
+        fn node(&mut self, region: &Region, (x,y): (f32,f32), tags: &[(&str,&str)]) -> i64 {
+            self.next_id += 1;
+            let (lat,lon) = coords::local_to_latlon(region, x, y);
+            writeln!(self.nodes, "  <node id=\"{}\" lat=\"{}\" lon=\"{}\">{}</node>",self.next_id,lat,lon,tag_xml(tags)).unwrap();
+            self.next_id
+        }
+
+        /// A way through existing nodes.
+        fn way_through(&mut self, ids: &[i64], tags: &[(&str,&str)]) -> i64 {
+            self.next_id += 1;
+            let refs: String = ids.iter().map(|id| format!("<nd ref=\"{id}\"/>")).collect();
+            writeln!(self.ways, "  <way id=\"{}\">{}{}</way>",self.next_id,refs,tag_xml(tags)).unwrap();
+            self.next_id
+        }
+
+        /// A way through new nodes at `points`, closed if it ends where it starts.
+        fn way(&mut self, region: &Region, points: &[(f32,f32)], tags: &[(&str,&str)]) -> i64 {
+            let closed = points.len() > 1 && points.first() == points.last();
+            let mut ids: Vec<i64> = points[..points.len() - closed as usize].iter().map(|p| self.node(region, *p, &[])).collect();
+            if closed {
+                ids.push(ids[0]);
+            }
+            self.way_through(&ids, tags)
+        }
+
+        fn read(&self, test: &str, region: &Region) -> (Buffer, OsmStats) {
+            self.read_with(test, region, &[])
+        }
+
+        /// Like `read`, with extra command line arguments.
+        fn read_with(&self, test: &str, region: &Region, args: &[&str]) -> (Buffer, OsmStats) {
+            let (mut buffer, stats) = self.read_with_header(test, region, args);
+            // hand back just the records
+            assert_eq!(&buffer.bytes[..4], MAP_MAGIC);
+            assert_eq!(buffer.bytes[5..7], MAP_VERSION.to_le_bytes());
+            buffer.bytes.drain(..MAP_HEADER_SIZE);
+            (buffer, stats)
+        }
+
+        /// The whole map, header and all.
+        fn read_with_header(&self, test: &str, region: &Region, args: &[&str]) -> (Buffer, OsmStats) {
+            let args = CommandArgs::parse_from(["cartographer", &region.name, "33", "--map"].iter().chain(args));
+            let path = std::env::temp_dir().join(format!("cartographer-{}-{}.osm",test,std::process::id()));
+            std::fs::write(&path, format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<osm version=\"0.6\">\n{}{}</osm>\n",self.nodes,self.ways)).unwrap();
+            let result = read_osm(&path, region, &args, &self.rules);
+            std::fs::remove_file(&path).unwrap();
+            result
+        }
+    }
+
+    fn tag_xml(tags: &[(&str,&str)]) -> String {
+        tags.iter().map(|(k,v)| format!("<tag k=\"{k}\" v=\"{v}\"/>")).collect()
+    }
+
+    fn flat_region() -> Region {
+        Region::flat("test", UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, 100.0)
+    }
+
+    #[test]
+    fn pedestrian_plaza_is_an_area() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        let square = [(10.0, 10.0), (30.0, 10.0), (30.0, 30.0), (10.0, 30.0), (10.0, 10.0)];
+        osm.way(&region, &square, &[("highway", "pedestrian"), ("area", "yes")]);
+        let (buffer, _) = osm.read("plaza", &region);
+
+        // a single area record of four points (kind, base, area kind, surface, count, then x/y/z per point) and no ribbon
+        assert_eq!(buffer.bytes[0], OBJ_AREA);
+        assert_eq!(buffer.bytes[13], AreaKind::Paved as u8);
+        assert_eq!(buffer.bytes[14], Surface::Paved as u8);
+        assert_eq!(buffer.bytes.len(), 1 + 12 + 1 + 1 + 2 + 4 * 12);
+    }
+
+    #[test]
+    fn indoor_ways_are_skipped_unless_asked_for() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (40.0, 10.0), (40.0, 40.0), (10.0, 40.0), (10.0, 10.0)], &[("building", "office")]);
+        osm.way(&region, &[(12.0, 12.0), (20.0, 12.0), (20.0, 20.0), (12.0, 20.0), (12.0, 12.0)], &[("indoor", "room"), ("level", "1;2")]);
+        osm.way(&region, &[(20.0, 12.0), (20.0, 38.0)], &[("indoor", "corridor"), ("highway", "corridor"), ("level", "-1")]);
+        osm.way(&region, &[(22.0, 22.0), (38.0, 22.0), (38.0, 38.0), (22.0, 38.0), (22.0, 22.0)], &[("indoor", "area"), ("building", "yes")]);
+
+        let (buffer, stats) = osm.read("indoor-default", &region);
+        assert_eq!((stats.buildings.values().sum::<usize>(), stats.roads.values().sum::<usize>(), stats.indoor, stats.skipped_indoor), (1, 0, 0, 3));
+        assert_eq!(buffer.bytes.len(), 28 + 2 + 4 * 8);
+
+        let (buffer, stats) = osm.read_with("indoor", &region, &["--indoor"]);
+        assert_eq!((stats.buildings.values().sum::<usize>(), stats.roads.values().sum::<usize>(), stats.indoor, stats.skipped_indoor), (1, 0, 3, 0));
+        // after the building: kind, base, level, indoor kind, count, then x/y per point
+        let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at+4].try_into().unwrap());
+        let room = 28 + 2 + 4 * 8;
+        assert_eq!(buffer.bytes[room], OBJ_INDOOR);
+        assert_eq!((float(room + 13), buffer.bytes[room + 17]), (1.0, IndoorKind::Room as u8));
+        assert_eq!(u16::from_le_bytes([buffer.bytes[room + 18], buffer.bytes[room + 19]]), 4);
+        let corridor = room + 20 + 4 * 8;
+        assert_eq!(buffer.bytes[corridor], OBJ_INDOOR);
+        assert_eq!((float(corridor + 13), buffer.bytes[corridor + 17]), (-1.0, IndoorKind::Corridor as u8));
+        let area = corridor + 20 + 2 * 8;
+        assert_eq!((buffer.bytes[area], buffer.bytes[area + 17]), (OBJ_INDOOR, IndoorKind::Area as u8));
+        assert_eq!(buffer.bytes.len(), area + 20 + 4 * 8);
+    }
+
+    #[test]
+    fn only_flags_restrict_the_records() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (40.0, 10.0), (40.0, 40.0), (10.0, 40.0), (10.0, 10.0)], &[("building", "yes")]);
+        osm.way(&region, &[(60.0, 10.0), (60.0, 90.0)], &[("highway", "residential")]);
+        osm.way(&region, &[(100.0, 10.0), (130.0, 10.0), (130.0, 40.0), (100.0, 40.0), (100.0, 10.0)], &[("landuse", "grass")]);
+        osm.node(&region, (150.0, 150.0), &[("natural", "tree")]);
+        let counts = |stats: &OsmStats| (stats.buildings.values().sum::<usize>(), stats.roads.values().sum::<usize>(), stats.areas, stats.trees);
+
+        let (_, stats) = osm.read("only-none", &region);
+        assert_eq!(counts(&stats), (1, 1, 1, 1));
+
+        let (buffer, stats) = osm.read_with("only-buildings", &region, &["--only-buildings"]);
+        assert_eq!(counts(&stats), (1, 0, 0, 0));
+        assert_eq!(buffer.bytes[0], OBJ_BUILDING);
+        assert_eq!(buffer.bytes.len(), 28 + 2 + 4 * 8);
+
+        let (buffer, stats) = osm.read_with("only-roads", &region, &["--only-roads"]);
+        assert_eq!(counts(&stats), (0, 1, 0, 0));
+        assert_eq!(buffer.bytes[0], OBJ_ROAD);
+
+        let (_, stats) = osm.read_with("only-both", &region, &["--only-buildings", "--only-roads"]);
+        assert_eq!(counts(&stats), (1, 1, 0, 0));
+    }
+
+    #[test]
+    fn building_levels_take_the_largest() {
+        assert_eq!(parse_levels("2;3"), Some(3.0));
+        assert_eq!(parse_levels("2-3"), Some(3.0));
+        assert_eq!(parse_levels(" 4 , 2 "), Some(4.0));
+        assert_eq!(parse_levels("3.5"), Some(3.5));
+        assert_eq!(parse_levels("two"), None);
+        assert_eq!(parse_levels("2;"), None);
+        assert_eq!(parse_levels("-1"), None);
+
+        let region = flat_region();
+        let height = |tags: &[(&str,&str)]| {
+            let mut osm = OsmFixture::default();
+            osm.way(&region, &[(10.0, 10.0), (40.0, 10.0), (40.0, 40.0), (10.0, 40.0), (10.0, 10.0)], tags);
+            let (buffer, _) = osm.read("levels", &region);
+            f32::from_le_bytes(buffer.bytes[17..21].try_into().unwrap())
+        };
+        assert_eq!(height(&[("building", "yes"), ("building:levels", "2;3")]), 9.0);
+        assert_eq!(height(&[("building", "yes"), ("building:levels", "2-3")]), 9.0);
+        // storeys below ground don't raise the roof
+        assert_eq!(height(&[("building", "yes"), ("building:levels", "3"), ("building:levels:underground", "2")]), 9.0);
+        // a malformed value falls back as if there were none
+        assert_eq!(height(&[("building", "yes"), ("building:levels", "a few")]), height(&[("building", "yes")]));
+    }
+
+    #[test]
+    fn duplicate_way_is_written_once() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (40.0, 10.0), (40.0, 40.0), (10.0, 40.0), (10.0, 10.0)], &[("building", "yes"), ("height", "6")]);
+        // the same way again, as it would be in a second extract, but since retagged
+        let copy = osm.ways.replace("v=\"6\"", "v=\"9\"");
+        osm.ways.push_str(&copy);
+        let height = |buffer: &Buffer| f32::from_le_bytes(buffer.bytes[17..21].try_into().unwrap());
+
+        let (buffer, stats) = osm.read("duplicate", &region);
+        assert_eq!((stats.buildings.values().sum::<usize>(), stats.skipped_duplicate), (1, 1));
+        assert_eq!(buffer.bytes.len(), 28 + 2 + 4 * 8);
+        assert_eq!(height(&buffer), 6.0);
+
+        let (buffer, stats) = osm.read_with("duplicate-last", &region, &["--duplicates", "last"]);
+        assert_eq!((stats.buildings.values().sum::<usize>(), stats.skipped_duplicate), (1, 1));
+        assert_eq!(buffer.bytes.len(), 28 + 2 + 4 * 8);
+        assert_eq!(height(&buffer), 9.0);
+    }
+
+    #[test]
+    fn area_outlines_are_ccw_with_a_border() {
+        let region = flat_region();
+        let square = [(10.0, 10.0), (30.0, 10.0), (30.0, 30.0), (10.0, 30.0), (10.0, 10.0)];
+        let reversed: Vec<(f32,f32)> = square.iter().rev().copied().collect();
+        for outline in [&square[..], &reversed[..]] {
+            let mut osm = OsmFixture::default();
+            osm.way(&region, outline, &[("landuse", "grass")]);
+            let (buffer, _) = osm.read_with_header("border", &region, &["--area-border", "2.5"]);
+            assert_eq!(u32::from_le_bytes(buffer.bytes[27..31].try_into().unwrap()), MAP_FLAG_BORDER);
+
+            let record = &buffer.bytes[MAP_HEADER_SIZE..];
+            let float = |at: usize| f32::from_le_bytes(record[at..at + 4].try_into().unwrap());
+            assert_eq!((record[0], float(15)), (OBJ_AREA, 2.5));
+            let count = u16::from_le_bytes([record[19], record[20]]) as usize;
+            assert_eq!(record.len(), 21 + count * 12);
+            let points: Vec<(f32,f32)> = (0..count).map(|i| (float(21 + i * 12), float(25 + i * 12))).collect();
+
+            // counter-clockwise once north is up, with the inside on the right of each edge in local x/y
+            let twice_area: f32 = (0..count).map(|i| {
+                let (a, b) = (points[i], points[(i + 1) % count]);
+                a.0 * -b.1 - b.0 * -a.1
+            }).sum();
+            assert!(twice_area > 0.0, "{points:?}");
+            let center = (points.iter().map(|p| p.0).sum::<f32>() / count as f32, points.iter().map(|p| p.1).sum::<f32>() / count as f32);
+            for i in 0..count {
+                let (a, b) = (points[i], points[(i + 1) % count]);
+                let cross = (b.0 - a.0) * (center.1 - a.1) - (b.1 - a.1) * (center.0 - a.0);
+                assert!(cross < 0.0, "edge {i} of {points:?}");
+            }
+        }
+
+        // without the option there's no border and no flag
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &square, &[("landuse", "grass")]);
+        let (buffer, _) = osm.read_with_header("border", &region, &[]);
+        assert_eq!(u32::from_le_bytes(buffer.bytes[27..31].try_into().unwrap()), 0);
+        assert_eq!(buffer.bytes.len(), MAP_HEADER_SIZE + 17 + 4 * 12);
+    }
+
+    #[test]
+    fn unit_vectors_round_to_the_nearest_step() {
+        // exact steps are unchanged from truncating, in between rounds rather than toward zero
+        assert_eq!([1.0, -1.0, 0.0, 0.5, -0.5].map(quantize_unit), [127, -127, 0, 64, -64]);
+        assert_eq!(quantize_unit(0.999), 127);
+        assert_eq!(quantize_unit(-0.003), 0);
+        // out of range and NaN are clamped
+        assert_eq!([1.5, -3.0, f32::NAN].map(quantize_unit), [127, -127, 0]);
+
+        let mut buffer = Buffer::default();
+        buffer.write_unit_vec3_i8(Vector3::new(0.6, -0.8, 0.0));
+        assert_eq!(buffer.bytes, [76, (-102i8) as u8, 0]);
+
+        // the same bytes as writing each component, in either byte order
+        for endian in [Endian::Le, Endian::Be] {
+            let mut helper = Buffer::new(endian);
+            helper.write_vec3_f32(Vector3::new(1.5, -2.25, 1e6));
+            let mut by_hand = Buffer::new(endian);
+            for x in [1.5, -2.25, 1e6] {
+                by_hand.write_float(x);
+            }
+            assert_eq!(helper.bytes, by_hand.bytes);
+        }
+    }
+
+    #[test]
+    fn big_endian_map_round_trips() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (30.0, 10.0), (30.0, 30.0), (10.0, 30.0), (10.0, 10.0)], &[("building", "yes"), ("height", "12")]);
+        let (le, _) = osm.read_with_header("endian-le", &region, &[]);
+        let (be, _) = osm.read_with_header("endian-be", &region, &["--endian", "be"]);
+        assert_eq!((le.bytes[4], be.bytes[4]), (0, 1));
+        assert_eq!(le.bytes.len(), be.bytes.len());
+        assert_ne!(le.bytes, be.bytes);
+        assert_eq!(validate::validate_bytes(&be.bytes, validate::OutputKind::Map, Endian::Be), Ok(()));
+
+        // everything after the byte-order byte reads back the same through a big-endian reader
+        let mut le = validate::BufferReader::new(&le.bytes[5..], Endian::Le);
+        let mut be = validate::BufferReader::new(&be.bytes[5..], Endian::Be);
+        assert_eq!(be.read_short(), Ok(MAP_VERSION));
+        le.read_short().unwrap();
+        for _ in 0..4 {
+            assert_eq!(be.read_float(), le.read_float());
+        }
+        // record count, then flags
+        assert_eq!(be.read_int(), Ok(1));
+        le.read_int().unwrap();
+        assert_eq!(be.read_int(), le.read_int());
+        assert_eq!(be.read_byte(), Ok(OBJ_BUILDING));
+        le.read_byte().unwrap();
+        for _ in 0..5 {
+            assert_eq!(be.read_float(), le.read_float());
+        }
+    }
+
+    #[test]
+    fn area_surface_follows_its_tags() {
+        let region = flat_region();
+        let square = [(10.0, 10.0), (30.0, 10.0), (30.0, 30.0), (10.0, 30.0), (10.0, 10.0)];
+        let area = |tags: &[(&str,&str)]| {
+            let mut osm = OsmFixture::default();
+            osm.way(&region, &square, tags);
+            let (buffer, stats) = osm.read("surface", &region);
+            assert_eq!((stats.areas, buffer.bytes[0]), (1, OBJ_AREA));
+            (buffer.bytes[13], buffer.bytes[14])
+        };
+        assert_eq!(area(&[("natural", "beach")]), (AreaKind::Natural as u8, Surface::Sand as u8));
+        assert_eq!(area(&[("landuse", "grass")]), (AreaKind::Natural as u8, Surface::Grass as u8));
+        assert_eq!(area(&[("natural", "beach"), ("surface", "fine_gravel")]), (AreaKind::Natural as u8, Surface::Gravel as u8));
+        assert_eq!(area(&[("highway", "pedestrian"), ("area", "yes")]), (AreaKind::Paved as u8, Surface::Paved as u8));
+        assert_eq!(area(&[("highway", "pedestrian"), ("area", "yes"), ("surface", "sett")]), (AreaKind::Paved as u8, Surface::Paved as u8));
+        assert_eq!(area(&[("highway", "pedestrian"), ("area", "yes"), ("surface", "grass_paver")]), (AreaKind::Paved as u8, Surface::Grass as u8));
+    }
+
+    #[test]
+    fn compression_levels_round_trip() {
+        // a terrain-like run of slowly changing samples
+        let buffer = Buffer { bytes: (0..200_000u32).flat_map(|i| ((i / 7) as u16).to_le_bytes()).collect(), ..Buffer::default() };
+        let dir = std::env::temp_dir().join(format!("cartographer-compression-{}",std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let saved = |level: u32| {
+            buffer.save(dir, &format!("level{level}"), level);
+            let gzip = std::fs::read(format!("{dir}/level{level}.bin.gz")).unwrap();
+            let mut bytes = Vec::new();
+            std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&gzip[..]), &mut bytes).unwrap();
+            (gzip.len(), bytes)
+        };
+        let (stored, stored_bytes) = saved(0);
+        let (smallest, smallest_bytes) = saved(9);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(stored_bytes, buffer.bytes);
+        assert_eq!(smallest_bytes, buffer.bytes);
+        // level 0 only stores, so it's no smaller than the input
+        assert!(stored >= buffer.bytes.len() && smallest < stored / 4, "{stored} vs {smallest}");
+        assert!(CommandArgs::try_parse_from(["cartographer", "test", "33", "--compression", "10"]).is_err());
+    }
+
+    #[test]
+    fn nested_dirs_from_many_threads() {
+        let root = std::env::temp_dir().join(format!("cartographer-dirs-{}",std::process::id()));
+        let nested = root.join("region/preview/debug");
+        let barrier = std::sync::Barrier::new(8);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    barrier.wait();
+                    ensure_dir_exists(nested.to_str().unwrap());
+                });
+            }
+        });
+        assert!(nested.is_dir());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "failed to create directory")]
+    fn dir_under_a_file_is_an_error() {
+        let file = std::env::temp_dir().join(format!("cartographer-not-a-dir-{}",std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        let result = std::panic::catch_unwind(|| ensure_dir_exists(file.join("tiles").to_str().unwrap()));
+        std::fs::remove_file(&file).unwrap();
+        std::panic::resume_unwind(result.unwrap_err());
+    }
+
+    #[test]
+    fn three_roads_meet_at_a_junction() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        let center = osm.node(&region, (50.0, 50.0), &[]);
+        for end in [(10.0, 50.0), (90.0, 50.0), (50.0, 90.0)] {
+            let end = osm.node(&region, end, &[]);
+            osm.way_through(&[end, center], &[("highway", "residential")]);
+        }
+        let (buffer, _) = osm.read("junction", &region);
+
+        // the one junction comes after the roads: kind, position, elevation, count, then road indices
+        let junction = &buffer.bytes[buffer.bytes.len() - (1 + 12 + 2 + 3 * 4)..];
+        let float = |at: usize| f32::from_le_bytes(junction[at..at + 4].try_into().unwrap());
+        assert_eq!(junction[0], OBJ_JUNCTION);
+        assert!((float(1) - 50.0).abs() < 0.01 && (float(5) - 50.0).abs() < 0.01, "({},{})",float(1),float(5));
+        assert_eq!(float(9), 100.0);
+        assert_eq!(u16::from_le_bytes([junction[13], junction[14]]), 3);
+        let roads: Vec<u32> = (0..3).map(|i| u32::from_le_bytes(junction[15 + i * 4..19 + i * 4].try_into().unwrap())).collect();
+        assert_eq!(roads, [0, 1, 2]);
+    }
+
+    #[test]
+    fn stats_count_a_fixture() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        let square = |x: f32, y: f32| [(x, y), (x + 10.0, y), (x + 10.0, y + 10.0), (x, y + 10.0), (x, y)];
+        osm.way(&region, &square(10.0, 10.0), &[("building", "yes")]);
+        osm.way(&region, &square(30.0, 10.0), &[("building", "yes"), ("height", "40")]);
+        osm.way(&region, &square(50.0, 10.0), &[("building", "yes")]);
+        let crossing = osm.node(&region, (20.0, 50.0), &[]);
+        let (a, b, c) = (osm.node(&region, (0.0, 50.0), &[]), osm.node(&region, (40.0, 50.0), &[]), osm.node(&region, (20.0, 80.0), &[]));
+        osm.way_through(&[a, crossing, b], &[("highway", "residential")]);
+        osm.way_through(&[crossing, c], &[("highway", "footway")]);
+        osm.way(&region, &[(0.0, 90.0), (40.0, 90.0)], &[("highway", "primary"), ("tunnel", "yes")]);
+        osm.way(&region, &square(60.0, 60.0), &[("highway", "pedestrian"), ("area", "yes")]);
+        // a node that isn't in the file, a building with two corners, and a road off the map
+        osm.way_through(&[a, 9999], &[("highway", "service")]);
+        osm.way(&region, &[(70.0, 10.0), (80.0, 10.0), (70.0, 10.0)], &[("building", "yes")]);
+        osm.way(&region, &[(-50.0, -50.0), (-10.0, -50.0)], &[("highway", "residential")]);
+        // neither a building nor a road
+        osm.way(&region, &square(90.0, 90.0), &[("natural", "water")]);
+        let (_, stats) = osm.read("stats", &region);
+
+        assert_eq!(stats.buildings.get("House"), Some(&2));
+        assert_eq!(stats.buildings.get("Tower"), Some(&1));
+        assert_eq!(stats.roads.get("road"), Some(&1));
+        assert_eq!(stats.roads.get("footpath"), Some(&1));
+        assert_eq!(stats.areas, 1);
+        assert_eq!(stats.junctions, 1);
+        assert_eq!(stats.skipped_filtered, 1);
+        assert_eq!(stats.skipped_missing_node, 1);
+        assert_eq!(stats.skipped_degenerate, 1);
+        assert_eq!(stats.skipped_out_of_bounds, 1);
+        assert_eq!(stats.nodes, 30);
+    }
+
+    #[test]
+    fn error_log_lists_dropped_ways() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (20.0, 10.0), (20.0, 20.0), (10.0, 20.0), (10.0, 10.0)], &[("building", "yes")]);
+        // lopsided, as a symmetric one has no area at all and is skipped as too small
+        let bow_tie = osm.way(&region, &[(30.0, 30.0), (40.0, 40.0), (40.0, 30.0), (30.0, 34.0), (30.0, 30.0)], &[("building", "yes")]);
+        let two_corners = osm.way(&region, &[(2.0, 2.0), (4.0, 2.0), (2.0, 2.0)], &[("building", "yes")]);
+        let off_the_map = osm.way(&region, &[(-50.0, -50.0), (-10.0, -50.0)], &[("highway", "residential")]);
+        let missing_node = osm.way_through(&[1, 9999], &[("highway", "service")]);
+        let log = std::env::temp_dir().join(format!("cartographer-error-log-{}.csv",std::process::id()));
+        let (_, stats) = osm.read_with("error-log", &region, &["--error-log", log.to_str().unwrap()]);
+        let csv = std::fs::read_to_string(&log).unwrap();
+        std::fs::remove_file(&log).unwrap();
+
+        assert_eq!(csv, format!("way_id,reason\n{bow_tie},self-intersecting\n{two_corners},degenerate\n{off_the_map},out of bounds\n{missing_node},missing node\n"));
+        assert_eq!(stats.skipped_self_intersecting, 1);
+        assert_eq!(stats.buildings.values().sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn peak_sits_at_its_surveyed_height() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.node(&region, (40.0, 60.0), &[("natural", "peak"), ("ele", "2000"), ("name", "Vrh")]);
+        osm.node(&region, (80.0, 60.0), &[("natural", "peak")]);
+        let (buffer, stats) = osm.read("peak", &region);
+
+        // kind, position, elevation, then the name's length and bytes
+        let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+        assert_eq!(stats.peaks, 2);
+        assert_eq!(buffer.bytes[0], OBJ_PEAK);
+        assert!((float(1) - 40.0).abs() < 0.01 && (float(5) - 60.0).abs() < 0.01);
+        assert_eq!(float(9), 2000.0);
+        assert_eq!(&buffer.bytes[13..18], &[3, 0, b'V', b'r', b'h']);
+        // without an ele tag it falls back to the map
+        assert_eq!(buffer.bytes[18], OBJ_PEAK);
+        assert_eq!(float(27), 100.0);
+        assert_eq!(buffer.bytes.len(), 2 * 15 + 3);
+    }
+
+    #[test]
+    fn tree_at_its_projected_position() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.node(&region, (25.0, 75.0), &[("natural", "tree"), ("height", "40 ft"), ("diameter_crown", "6")]);
+        let (buffer, stats) = osm.read("tree", &region);
+
+        // kind, position, elevation, height, crown diameter
+        let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+        assert_eq!(stats.trees, 1);
+        assert_eq!(buffer.bytes.len(), 1 + 5 * 4);
+        assert_eq!(buffer.bytes[0], OBJ_TREE);
+        assert!((float(1) - 25.0).abs() < 0.01 && (float(5) - 75.0).abs() < 0.01, "({},{})",float(1),float(5));
+        assert_eq!(float(9), 100.0);
+        assert!((float(13) - 12.192).abs() < 0.001);
+        assert_eq!(float(17), 6.0);
+    }
+
+    #[test]
+    fn untagged_house_uses_the_height_floor() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (20.0, 10.0), (20.0, 20.0), (10.0, 20.0), (10.0, 10.0)], &[("building", "house")]);
+        let height = |args: &[&str]| {
+            let (buffer, _) = osm.read_with("min-height", &region, args);
+            assert_eq!(buffer.bytes[21], BuildingKind::House as u8);
+            f32::from_le_bytes(buffer.bytes[17..21].try_into().unwrap())
+        };
+
+        assert_eq!(height(&[]), 3.0);
+        assert_eq!(height(&["--min-building-height", "2.5"]), 2.5);
+        assert_eq!(height(&["--min-building-height", "4"]), 4.0);
+    }
+
+    #[test]
+    fn rules_file_makes_parking_a_building() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (40.0, 10.0), (40.0, 30.0), (10.0, 30.0), (10.0, 10.0)], &[("amenity", "parking")]);
+        // untouched by the built-in rules
+        let (buffer, stats) = osm.read("rules", &region);
+        assert!(buffer.bytes.is_empty() && stats.buildings.is_empty());
+
+        let path = std::env::temp_dir().join(format!("cartographer-rules-{}.toml",std::process::id()));
+        std::fs::write(&path, "[[building]]\nkey = \"amenity\"\nvalue = \"parking\"\nkind = \"parking\"\nheight = 9.0\n").unwrap();
+        osm.rules = Rules::load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let (buffer, stats) = osm.read("rules", &region);
+        assert_eq!(stats.buildings.values().sum::<usize>(), 1);
+        assert_eq!(buffer.bytes[0], OBJ_BUILDING);
+        assert_eq!(f32::from_le_bytes(buffer.bytes[17..21].try_into().unwrap()), 9.0);
+        assert_eq!(buffer.bytes[21], BuildingKind::Parking as u8);
+    }
+
+    #[test]
+    fn sample_elevation_on_a_ramp() {
+        let coord = UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 };
+        let region = Region::from_fn("ramp", coord, (64, 32), |x,y| x as f32 * 0.5 + y as f32 * 0.25);
+        // the value of the sample the point falls in, (10,20)
+        let args = CommandArgs::parse_from(["cartographer", "ramp", "33", "--sample-elevation", "10.5,20.25"]);
+        assert_eq!(sample_elevation(&region, args.sample_elevation.unwrap(), args.sample_utm), (10.5, 20.25, Some(10.0)));
+
+        // the same point by its UTM coordinates
+        let args = CommandArgs::parse_from(["cartographer", "ramp", "33", "--sample-elevation", "500010.5,4999979.75", "--sample-utm"]);
+        assert_eq!(sample_elevation(&region, args.sample_elevation.unwrap(), args.sample_utm), (10.5, 20.25, Some(10.0)));
+
+        let args = CommandArgs::parse_from(["cartographer", "ramp", "33", "--sample-elevation", "-5,3"]);
+        assert_eq!(sample_elevation(&region, args.sample_elevation.unwrap(), false), (-5.0, 3.0, None));
+    }
+
+    #[test]
+    fn negated_and_unbuilt_ways_are_skipped() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (30.0, 10.0), (30.0, 20.0), (10.0, 20.0), (10.0, 10.0)], &[("building", "no")]);
+        osm.way(&region, &[(40.0, 10.0), (60.0, 10.0), (60.0, 20.0), (40.0, 20.0), (40.0, 10.0)], &[("building", "demolished")]);
+        osm.way(&region, &[(5.0, 40.0), (60.0, 40.0)], &[("highway", "construction")]);
+        osm.way(&region, &[(5.0, 60.0), (60.0, 60.0)], &[("highway", "proposed")]);
+        osm.way(&region, &[(5.0, 80.0), (60.0, 80.0)], &[("highway", "no")]);
+        let (buffer, stats) = osm.read("negated", &region);
+        assert!(buffer.bytes.is_empty());
+        assert!(stats.buildings.is_empty() && stats.roads.is_empty());
+        assert_eq!(stats.areas, 0);
+    }
+
+    #[test]
+    fn header_bounds_every_record() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (30.0, 10.0), (30.0, 20.0), (10.0, 20.0), (10.0, 10.0)], &[("building", "yes")]);
+        osm.way(&region, &[(5.0, 40.0), (60.0, 40.0)], &[("highway", "residential"), ("lanes", "2")]);
+        osm.node(&region, (70.0, 80.0), &[("natural", "tree")]);
+        let header = |args: &[&str]| {
+            let (buffer, _) = osm.read_with_header("header", &region, args);
+            let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+            let count = u32::from_le_bytes(buffer.bytes[23..27].try_into().unwrap());
+            ([float(7), float(11), float(15), float(19)], count)
+        };
+
+        // the road's 6m ribbon reaches 3m either side of it
+        let (bbox, count) = header(&[]);
+        for (got, expected) in bbox.iter().zip([5.0, 10.0, 70.0, 80.0]) {
+            assert!((got - expected).abs() < 0.01, "{bbox:?}");
+        }
+        assert_eq!(count, 3);
+
+        // and it moves with the output origin
+        let (bbox, _) = header(&["--origin", "500010,4999990"]);
+        for (got, expected) in bbox.iter().zip([-5.0, 0.0, 60.0, 70.0]) {
+            assert!((got - expected).abs() < 0.01, "{bbox:?}");
+        }
+    }
+
+    #[test]
+    fn building_orientation_follows_the_long_walls() {
+        let region = flat_region();
+        let orientation = |points: &[(f32,f32)]| {
+            let mut osm = OsmFixture::default();
+            osm.way(&region, points, &[("building", "yes")]);
+            let (buffer, _) = osm.read("orientation", &region);
+            buffer.bytes[23]
+        };
+        // 40m east-west by 10m north-south
+        assert_eq!(orientation(&[(10.0, 10.0), (50.0, 10.0), (50.0, 20.0), (10.0, 20.0), (10.0, 10.0)]), 0);
+        // turned a quarter
+        assert_eq!(orientation(&[(10.0, 10.0), (20.0, 10.0), (20.0, 50.0), (10.0, 50.0), (10.0, 10.0)]), 128);
+    }
+
+    #[test]
+    fn building_colour_follows_the_orientation() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        let square = [(10.0, 10.0), (20.0, 10.0), (20.0, 20.0), (10.0, 20.0), (10.0, 10.0)];
+        osm.way(&region, &square, &[("building", "yes"), ("building:colour", "RAL 9010")]);
+        let (buffer, _) = osm.read("colour", &region);
+        assert_eq!(u32::from_le_bytes(buffer.bytes[24..28].try_into().unwrap()), 0xFFFFFF);
+        assert_eq!(u16::from_le_bytes([buffer.bytes[28], buffer.bytes[29]]), 4);
+    }
+
+    #[test]
+    fn metrics_give_footprint_and_floor_area() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (20.0, 10.0), (20.0, 20.0), (10.0, 20.0), (10.0, 10.0)], &[("building", "yes"), ("building:levels", "3")]);
+        let (buffer, _) = osm.read_with_header("metrics", &region, &["--emit-metrics"]);
+        assert_eq!(u32::from_le_bytes(buffer.bytes[27..31].try_into().unwrap()), MAP_FLAG_METRICS);
+
+        // area and floors go between the colour and the node count
+        let record = &buffer.bytes[MAP_HEADER_SIZE..];
+        let footprint = f32::from_le_bytes(record[28..32].try_into().unwrap());
+        let floors = u16::from_le_bytes([record[32], record[33]]);
+        assert!((footprint - 100.0).abs() < 0.1, "{footprint}");
+        assert_eq!(floors, 3);
+        assert!((footprint * floors as f32 - 300.0).abs() < 0.3);
+        assert_eq!(u16::from_le_bytes([record[34], record[35]]), 4);
+
+        // without the flag, the record is as before
+        let (buffer, _) = osm.read("metrics", &region);
+        assert_eq!(u16::from_le_bytes([buffer.bytes[28], buffer.bytes[29]]), 4);
+    }
+
+    #[test]
+    fn red_building_gets_the_palette_red() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (20.0, 10.0), (20.0, 20.0), (10.0, 20.0), (10.0, 10.0)], &[("building", "yes"), ("building:colour", "#ff0000")]);
+        let (buffer, _) = osm.read_with_header("palette", &region, &["--palette-index", "--emit-metrics"]);
+        assert_eq!(u32::from_le_bytes(buffer.bytes[27..31].try_into().unwrap()), MAP_FLAG_METRICS | MAP_FLAG_PALETTE);
+
+        // the index goes after the colour, then the metrics
+        let record = &buffer.bytes[MAP_HEADER_SIZE..];
+        assert_eq!(colors::load_palette(None)[record[28] as usize], 0xFF0000);
+        assert_eq!(u16::from_le_bytes([record[35], record[36]]), 4);
+    }
+
+    #[test]
+    fn building_ground_follows_the_slope() {
+        // rising half a meter per meter east
+        let region = Region::from_fn("ground", UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, (256, 256), |x,_| 100.0 + x as f32 * 0.5);
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (30.0, 10.0), (30.0, 20.0), (10.0, 20.0), (10.0, 10.0)], &[("building", "yes")]);
+        let (buffer, _) = osm.read_with_header("ground", &region, &["--building-ground"]);
+        assert_eq!(u32::from_le_bytes(buffer.bytes[27..31].try_into().unwrap()), MAP_FLAG_GROUND);
+
+        let record = &buffer.bytes[MAP_HEADER_SIZE..];
+        let float = |at: usize| f32::from_le_bytes(record[at..at + 4].try_into().unwrap());
+        let (base_x, base_y, base_z) = (float(1), float(5), float(9));
+        let count = u16::from_le_bytes([record[28], record[29]]) as usize;
+        assert_eq!(record.len(), 30 + count * 12);
+        let mut grounds = Vec::new();
+        for i in 0..count {
+            let (x, y, ground) = (float(30 + i * 12), float(34 + i * 12), float(38 + i * 12));
+            assert_eq!(ground, region.get_elevation(base_x + x, base_y + y));
+            grounds.push((x, ground));
+        }
+        // the west corners sit 10m lower than the east ones, and the base is the lowest of them
+        for (x, ground) in &grounds {
+            let expected = if *x < 0.0 { 105.0 } else { 115.0 };
+            assert!((ground - expected).abs() <= 0.5, "{x}: {ground}");
+        }
+        assert_eq!(base_z, grounds.iter().map(|(_, ground)| *ground).fold(f32::INFINITY, f32::min));
+    }
+
+    #[test]
+    fn normalized_building_sits_flush_across_a_tile_boundary() {
+        use std::io::Read;
+
+        // two tiles on a slope high above the sea
+        let slope = |x: f32| 300.0 + x * 0.05;
+        let mut region = Region::from_fn("normalize", UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, (1024, 256), |x,_| slope(x as f32));
+        region.datum = region.global_z_range().0;
+        assert_eq!(region.datum, 300.0);
+        let args = CommandArgs::parse_from(["cartographer", "normalize", "33", "-e", "--global-z-range", "--normalize-heights"]);
+        region.ensure_out_dir_exists(&args);
+        assert_eq!(region.process_elevation(&args, &AdjacentRegions::default()).written, 2);
+
+        // terrain height above the datum, less the slope, at every vertex of both tiles
+        let mut offsets = Vec::new();
+        for (tile, x0) in [("tile0", 0.0), ("tile1", 512.0)] {
+            let mut data = Vec::new();
+            flate2::read::GzDecoder::new(std::fs::File::open(format!("{}/{tile}.bin.gz",region.out_dir(&args))).unwrap()).read_to_end(&mut data).unwrap();
+            let float = |at: usize| f32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+            let short = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]) as f32;
+            let (min_z, range_z, extent_x) = (float(0), float(4), short(8));
+            for at in (0..short(12) as usize).map(|v| 14 + v * 9) {
+                let x = x0 + short(at) / 65535.0 * extent_x;
+                offsets.push(min_z + short(at + 4) / 65535.0 * range_z - (slope(x) - 300.0));
+            }
+        }
+        std::fs::remove_dir_all(region.out_dir(&args)).unwrap();
+
+        // a building straddling x = 512
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(500.0, 100.0), (524.0, 100.0), (524.0, 120.0), (500.0, 120.0), (500.0, 100.0)], &[("building", "yes")]);
+        let (buffer, _) = osm.read_with_header("normalize", &region, &["--building-ground"]);
+        let record = &buffer.bytes[MAP_HEADER_SIZE..];
+        let float = |at: usize| f32::from_le_bytes(record[at..at + 4].try_into().unwrap());
+        let count = u16::from_le_bytes([record[28], record[29]]) as usize;
+        let (base_x, base_z) = (float(1), float(9));
+        // measured from the datum, not from the sea 300m below
+        assert!((base_z - 25.0).abs() < 0.1, "{base_z}");
+        for i in 0..count {
+            let (x, ground) = (base_x + float(30 + i * 12), float(38 + i * 12));
+            let offset = ground - (slope(x) - 300.0);
+            // both tiles and the building agree on where the ground is, to within a sample
+            for terrain in &offsets {
+                assert!((offset - terrain).abs() < 0.1, "corner at {x}: {offset} against terrain {terrain}");
+            }
+        }
+    }
+
+    #[test]
+    fn rectangle_walls_face_outward() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (30.0, 10.0), (30.0, 20.0), (10.0, 20.0), (10.0, 10.0)], &[("building", "yes")]);
+        let (buffer, _) = osm.read_with_header("normals", &region, &["--building-normals"]);
+        assert_eq!(u32::from_le_bytes(buffer.bytes[27..31].try_into().unwrap()), MAP_FLAG_NORMALS);
+
+        let record = &buffer.bytes[MAP_HEADER_SIZE..];
+        let count = u16::from_le_bytes([record[28], record[29]]) as usize;
+        assert_eq!(count, 4);
+        let point = |i: usize| {
+            let at = 30 + (i % count) * 8;
+            (f32::from_le_bytes(record[at..at+4].try_into().unwrap()), f32::from_le_bytes(record[at+4..at+8].try_into().unwrap()))
+        };
+        let center = ((0..count).map(|i| point(i).0).sum::<f32>() / 4.0, (0..count).map(|i| point(i).1).sum::<f32>() / 4.0);
+        let normals = &record[30 + count * 8..];
+        let mut seen = vec![];
+        for i in 0..count {
+            let normal = (normals[i * 2] as i8, normals[i * 2 + 1] as i8);
+            // along an axis, and away from the middle of its wall's building
+            let (a,b) = (point(i), point(i + 1));
+            let middle = ((a.0 + b.0) / 2.0 - center.0, (a.1 + b.1) / 2.0 - center.1);
+            assert!(normal.0 as f32 * middle.0 + normal.1 as f32 * middle.1 > 0.0, "wall {i}: {normal:?}");
+            seen.push(normal);
+        }
+        seen.sort();
+        assert_eq!(seen, [(-127, 0), (0, -127), (0, 127), (127, 0)]);
+        // the flat roof faces up, and ends the record
+        assert_eq!(&normals[count * 2..], [0, 0, 127]);
+    }
+
+    #[test]
+    fn outlines_wind_one_way_whichever_way_they_were_drawn() {
+        let region = flat_region();
+        let square = [(10.0, 10.0), (30.0, 10.0), (30.0, 30.0), (10.0, 30.0), (10.0, 10.0)];
+        let mut reversed = square;
+        reversed.reverse();
+        for ring in [square, reversed] {
+            let mut osm = OsmFixture::default();
+            osm.way(&region, &ring, &[("building", "yes")]);
+            let (buffer, _) = osm.read_with("winding", &region, &["--building-normals"]);
+            let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at+4].try_into().unwrap());
+            let outline: Vec<(f32,f32)> = (0..4).map(|i| (float(30 + i * 8), float(34 + i * 8))).collect();
+
+            // clockwise in local x/y (y southward), so counter-clockwise on a north-up map
+            let twice_area = |ring: &[(f32,f32)]| (0..4).map(|i| ring[i].0 * ring[(i + 1) % 4].1 - ring[(i + 1) % 4].0 * ring[i].1).sum::<f32>();
+            assert!(twice_area(&outline) < 0.0, "{outline:?}");
+            let north_up: Vec<(f32,f32)> = outline.iter().map(|(x,y)| (*x, -y)).collect();
+            assert!(twice_area(&north_up) > 0.0);
+
+            // each wall's normal is on the left of its edge in local terms, and faces out
+            for i in 0..4 {
+                let (a,b) = (outline[i], outline[(i + 1) % 4]);
+                let normal = (buffer.bytes[62 + i * 2] as i8 as f32, buffer.bytes[63 + i * 2] as i8 as f32);
+                assert!((b.0 - a.0) * normal.1 - (b.1 - a.1) * normal.0 > 0.0, "wall {i} of {outline:?} faces {normal:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn building_in_geojson() {
+        let name = format!("geojson-test-{}",std::process::id());
+        let region = Region::flat(&name, UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, 100.0);
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (30.0, 10.0), (30.0, 20.0), (10.0, 20.0), (10.0, 10.0)], &[("building", "house"), ("height", "8")]);
+        let args = CommandArgs::parse_from(["cartographer", &name, "33", "--map", "--geojson"]);
+        region.ensure_out_dir_exists(&args);
+        osm.read_with("geojson", &region, &["--geojson"]);
+        let text = std::fs::read_to_string(format!("{}/map.geojson",region.out_dir(&args))).unwrap();
+        std::fs::remove_dir_all(&region.output_dir).unwrap();
+
+        let collection: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(collection["type"], "FeatureCollection");
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(features.len(), 1);
+        let building = &features[0];
+        assert_eq!(building["geometry"]["type"], "Polygon");
+        assert_eq!(building["properties"]["object"], "building");
+        assert_eq!(building["properties"]["kind"], "House");
+        assert_eq!(building["properties"]["height"], 8.0);
+
+        // a closed ring through the four corners, back in lon/lat
+        let ring = building["geometry"]["coordinates"][0].as_array().unwrap();
+        assert_eq!(ring.len(), 5);
+        assert_eq!(ring[0], ring[4]);
+        for corner in [(10.0, 10.0), (30.0, 10.0), (30.0, 20.0), (10.0, 20.0)] {
+            let (lat,lon) = coords::local_to_latlon(&region, corner.0, corner.1);
+            assert!(ring.iter().any(|p| (p[0].as_f64().unwrap() - lon).abs() < 1e-6 && (p[1].as_f64().unwrap() - lat).abs() < 1e-6), "{corner:?} missing");
+        }
+    }
+
+    #[test]
+    fn huge_ways_are_capped_not_fatal() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        let count = 70_000;
+        let mut ring: Vec<(f32,f32)> = (0..count).map(|i| {
+            let angle = i as f32 / count as f32 * std::f32::consts::TAU;
+            (200.0 + 50.0 * angle.cos(), 400.0 + 50.0 * angle.sin())
+        }).collect();
+        ring.push(ring[0]);
+        osm.way(&region, &ring, &[("building", "yes")]);
+        let line: Vec<(f32,f32)> = (0..count).map(|i| (10.0 + i as f32 * 0.01, 200.0)).collect();
+        osm.way(&region, &line, &[("highway", "residential")]);
+        let (buffer, stats) = osm.read("huge", &region);
+
+        let short = |at: usize| u16::from_le_bytes([buffer.bytes[at], buffer.bytes[at + 1]]) as usize;
+        assert_eq!(stats.buildings.values().sum::<usize>(), 1);
+        assert_eq!(stats.roads.get("road"), Some(&1));
+        // building: kind, 5 floats, kind, roof, orientation, colour, count, then x/y per node
+        assert_eq!(buffer.bytes[0], OBJ_BUILDING);
+        let building_nodes = short(28);
+        assert!(building_nodes <= MAX_PATH_NODES && building_nodes > MAX_PATH_NODES / 2, "{building_nodes}");
+        // road: kind, 3 floats, road kind, lanes, access, subtype, count
+        let road = 30 + building_nodes * 8;
+        assert_eq!(buffer.bytes[road], OBJ_ROAD);
+        let road_nodes = short(road + 17);
+        assert!(road_nodes <= MAX_PATH_NODES && road_nodes > MAX_PATH_NODES / 2, "{road_nodes}");
+
+        // draping can grow a short road past the limit too
+        let mut osm = OsmFixture::default();
+        let zigzag: Vec<(f32,f32)> = (0..9).map(|i| (if i % 2 == 0 { 10.0 } else { 1010.0 }, 300.0 + i as f32 * 10.0)).collect();
+        osm.way(&region, &zigzag, &[("highway", "residential")]);
+        let (buffer, _) = osm.read_with("huge-drape", &region, &["--road-drape-spacing", "0.1"]);
+        let road_nodes = u16::from_le_bytes([buffer.bytes[17], buffer.bytes[18]]) as usize;
+        assert!(road_nodes <= MAX_PATH_NODES && road_nodes > MAX_PATH_NODES / 2, "{road_nodes}");
+
+        // two wall triangles per edge have to fit the u16 count as well
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &ring, &[("building", "yes")]);
+        let (buffer, _) = osm.read_with("huge-walls", &region, &["--bake-walls"]);
+        let short = |at: usize| u16::from_le_bytes([buffer.bytes[at], buffer.bytes[at + 1]]) as usize;
+        let building_nodes = short(28);
+        assert!(building_nodes <= MAX_PATH_NODES / 2 && building_nodes > MAX_PATH_NODES / 4, "{building_nodes}");
+        assert_eq!(short(30 + building_nodes * 8), building_nodes * 2);
+    }
+
+    #[test]
+    fn baked_walls_face_out() {
+        let region = Region::from_fn("walls", UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, (256, 256), |x,y| 100.0 + x as f32 * 0.1 + y as f32 * 0.05);
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(40.0, 40.0), (60.0, 40.0), (60.0, 60.0), (40.0, 60.0), (40.0, 40.0)], &[("building", "yes"), ("height", "6")]);
+        let (buffer, _) = osm.read_with_header("walls", &region, &["--bake-walls"]);
+        assert_eq!(u32::from_le_bytes(buffer.bytes[27..31].try_into().unwrap()), MAP_FLAG_WALLS);
+
+        let record = &buffer.bytes[MAP_HEADER_SIZE..];
+        let float = |at: usize| f32::from_le_bytes(record[at..at + 4].try_into().unwrap());
+        let short = |at: usize| u16::from_le_bytes([record[at], record[at + 1]]) as usize;
+        assert_eq!((record[0], short(28)), (OBJ_BUILDING, 4));
+        let walls = 30 + 4 * 8;
+        assert_eq!(short(walls), 8);
+        let top = float(13) + float(17);
+        for triangle in (0..8).map(|i| walls + 2 + i * 38) {
+            let corners: Vec<(f32,f32,f32)> = (0..3).map(|c| triangle + c * 12).map(|at| (float(at), float(at + 4), float(at + 8))).collect();
+            let normal = (record[triangle + 36] as i8 as f32 / 127.0, record[triangle + 37] as i8 as f32 / 127.0);
+            // corners are around the centroid, so the wall's midpoint is away from it along the normal
+            let mid = (corners.iter().map(|c| c.0).sum::<f32>() / 3.0, corners.iter().map(|c| c.1).sum::<f32>() / 3.0);
+            assert!(mid.0 * normal.0 + mid.1 * normal.1 > 0.0, "{normal:?} at {mid:?}");
+            // and each corner is either on the terrain or at the roof line
+            for (x,y,z) in corners {
+                let ground = region.get_elevation(float(1) + x, float(5) + y);
+                assert!((z - ground).abs() < 1e-3 || (z - top).abs() < 1e-3, "{z} vs {ground}/{top}");
+            }
+        }
+    }
+
+    #[test]
+    fn smoothing_calms_a_bumpy_road() {
+        let coord = UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 };
+        let region = Region::from_fn("bumpy", coord, (256, 128), |x,_| 50.0 + 2.0 * (x as f32 * 0.7).sin());
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(20.0, 50.0), (220.0, 50.0)], &[("highway", "primary")]);
+        osm.way(&region, &[(20.0, 80.0), (220.0, 80.0)], &[("highway", "footway")]);
+        // total climb and descent along the left edge of each road
+        let variation = |args: &[&str]| {
+            let (buffer, _) = osm.read_with("bumpy", &region, args);
+            let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+            let mut at = 0;
+            let mut result = Vec::new();
+            for _ in 0..2 {
+                let count = u16::from_le_bytes([buffer.bytes[at + 17], buffer.bytes[at + 18]]) as usize;
+                let z: Vec<f32> = (0..count).map(|i| float(at + 19 + i * 48 + 8)).collect();
+                result.push(z.windows(2).map(|pair| (pair[1] - pair[0]).abs()).sum::<f32>());
+                at += 19 + count * 48;
+            }
+            result
+        };
+
+        let bumpy = variation(&["--road-drape-spacing", "1"]);
+        assert!(bumpy.iter().all(|v| *v > 100.0), "{bumpy:?}");
+        // each kind has its own window, smoothing only that kind
+        let roads = variation(&["--road-drape-spacing", "1", "--road-smoothing", "20"]);
+        assert!(roads[0] < bumpy[0] * 0.5 && roads[1] == bumpy[1], "{roads:?} {bumpy:?}");
+        let paths = variation(&["--road-drape-spacing", "1", "--path-smoothing", "20"]);
+        assert!(paths[0] == bumpy[0] && paths[1] < bumpy[1] * 0.5, "{paths:?} {bumpy:?}");
+        // a wider window smooths more
+        let wider = variation(&["--road-drape-spacing", "1", "--road-smoothing", "60"]);
+        assert!(wider[0] < roads[0], "{wider:?} {roads:?}");
+    }
+
+    #[test]
+    fn draped_road_follows_a_bump() {
+        let coord = UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 };
+        let region = Region::from_fn("drape", coord, (256, 128), |x,_| {
+            let dx = x as f32 - 100.0;
+            10.0 * (-dx * dx / 200.0).exp()
+        });
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(20.0, 50.0), (180.0, 50.0)], &[("highway", "residential")]);
+        // heights along the left edge of the road
+        let heights = |args: &[&str]| {
+            let (buffer, _) = osm.read_with("drape", &region, args);
+            let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+            let count = u16::from_le_bytes([buffer.bytes[17], buffer.bytes[18]]) as usize;
+            // nodes of 12 floats follow the header, z relative to the base elevation
+            (0..count).map(|i| float(19 + i * 48 + 8) + float(9)).collect::<Vec<_>>()
+        };
+
+        // the two end nodes are both down on the flat
+        let plain = heights(&[]);
+        assert_eq!(plain.len(), 2);
+        assert!(plain.iter().all(|z| *z < 0.1), "{plain:?}");
+
+        // split every 5m, the road goes up and over
+        let draped = heights(&["--road-drape-spacing", "5"]);
+        assert_eq!(draped.len(), 33);
+        let top = draped.iter().copied().fold(0.0f32, f32::max);
+        assert!((top - 10.0).abs() < 0.5, "{draped:?}");
+        assert!((draped[8] - region.get_elevation(60.0, 50.0)).abs() < 0.5, "{draped:?}");
+    }
+
+    /// Appends an o5m unsigned number: 7 bits a byte, lowest first.
+    fn o5m_unsigned(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    /// Appends an o5m signed number, with the sign in the lowest bit.
+    fn o5m_signed(out: &mut Vec<u8>, value: i64) {
+        o5m_unsigned(out, if value < 0 { (-value * 2 - 1) as u64 } else { value as u64 * 2 });
+    }
+
+    #[test]
+    fn o5m_reads_the_same_as_xml() {
+        let region = flat_region();
+        let points = [(10.0, 10.0), (30.0, 10.0), (30.0, 20.0), (10.0, 20.0), (5.0, 40.0), (60.0, 45.0)];
+        let ways: [(i64, &[i64], (&str,&str)); 2] = [(100, &[1, 2, 3, 4, 1], ("building", "yes")), (101, &[5, 6], ("highway", "residential"))];
+        // both formats carry coordinates in 100 nanodegree steps
+        let coords: Vec<(i64,i64)> = points.iter().map(|(x,y)| {
+            let (lat,lon) = coords::local_to_latlon(&region, *x, *y);
+            ((lat * 1e7).round() as i64, (lon * 1e7).round() as i64)
+        }).collect();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<osm version=\"0.6\">\n");
+        for (i,(lat,lon)) in coords.iter().enumerate() {
+            writeln!(xml, "  <node id=\"{}\" lat=\"{}\" lon=\"{}\"/>",i + 1,*lat as f64 / 1e7,*lon as f64 / 1e7).unwrap();
+        }
+        for (id,refs,tag) in ways {
+            let refs: String = refs.iter().map(|r| format!("<nd ref=\"{r}\"/>")).collect();
+            writeln!(xml, "  <way id=\"{}\">{}{}</way>",id,refs,tag_xml(&[tag])).unwrap();
+        }
+        xml += "</osm>\n";
+
+        // reset, header, then a dataset per object with ids, coordinates and refs delta coded
+        let mut o5m = vec![0xff, 0xe0, 0x04, b'o', b'5', b'm', b'2'];
+        let mut dataset = |kind: u8, body: Vec<u8>| {
+            o5m.push(kind);
+            o5m_unsigned(&mut o5m, body.len() as u64);
+            o5m.extend(body);
+        };
+        let mut last = (0, 0, 0);
+        for (i,(lat,lon)) in coords.iter().enumerate() {
+            let mut body = Vec::new();
+            o5m_signed(&mut body, i as i64 + 1 - last.0);
+            body.push(0); // no version or author
+            o5m_signed(&mut body, lon - last.2);
+            o5m_signed(&mut body, lat - last.1);
+            dataset(0x10, body);
+            last = (i as i64 + 1, *lat, *lon);
+        }
+        let (mut last_way, mut last_ref) = (0, 0);
+        for (id,refs,(key,value)) in ways {
+            let mut body = Vec::new();
+            o5m_signed(&mut body, id - last_way);
+            body.push(0);
+            let mut ref_bytes = Vec::new();
+            for r in refs {
+                o5m_signed(&mut ref_bytes, r - last_ref);
+                last_ref = *r;
+            }
+            o5m_unsigned(&mut body, ref_bytes.len() as u64);
+            body.extend(ref_bytes);
+            body.push(0);
+            body.extend(format!("{key}\0{value}\0").bytes());
+            dataset(0x11, body);
+            last_way = id;
+        }
+        o5m.push(0xfe);
+
+        let args = CommandArgs::parse_from(["cartographer", "test", "33", "--map"]);
+        let read = |ext: &str, data: &[u8]| {
+            let path = std::env::temp_dir().join(format!("cartographer-o5m-{}.{}",std::process::id(),ext));
+            std::fs::write(&path, data).unwrap();
+            let (buffer, stats) = read_osm(&path, &region, &args, &Rules::default());
+            std::fs::remove_file(&path).unwrap();
+            (buffer.bytes, stats.buildings.values().sum::<usize>(), stats.roads.values().sum::<usize>())
+        };
+        let from_xml = read("osm", xml.as_bytes());
+        assert_eq!((from_xml.1, from_xml.2), (1, 1));
+        assert_eq!(read("o5m", &o5m), from_xml);
+    }
+
+    #[test]
+    fn lane_width_sets_the_road_width() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 50.0), (60.0, 50.0)], &[("highway", "primary"), ("lanes", "4")]);
+        let width = |args: &[&str]| {
+            let (buffer, _) = osm.read_with("lane-width", &region, args);
+            let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+            // road: kind, 3 floats, road kind, lanes, access, subtype, count, then left and right of the first node
+            assert_eq!(buffer.bytes[14], 4);
+            (float(23) - float(35)).abs()
+        };
+        assert_eq!(width(&[]), 12.0);
+        // a half-width of 4 * 2.75 / 2 either side
+        assert_eq!(width(&["--lane-width", "2.75"]), 11.0);
+    }
+
+    #[test]
+    fn service_roads_are_narrower() {
+        let region = flat_region();
+        // subtype and width of a single road
+        let road = |tags: &[(&str,&str)]| {
+            let mut osm = OsmFixture::default();
+            osm.way(&region, &[(10.0, 50.0), (60.0, 50.0)], tags);
+            let (buffer, stats) = osm.read("service", &region);
+            let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+            (stats.roads.keys().next().unwrap().clone(), buffer.bytes[16], (float(23) - float(35)).abs())
+        };
+        assert_eq!(road(&[("highway", "residential")]), ("road".to_owned(), 0, 6.0));
+        assert_eq!(road(&[("highway", "service")]), ("service".to_owned(), 1, 4.5));
+        assert_eq!(road(&[("highway", "service"), ("service", "driveway")]), ("driveway".to_owned(), 2, 3.0));
+        assert_eq!(road(&[("highway", "service"), ("service", "parking_aisle")]), ("parking_aisle".to_owned(), 3, 4.5));
+        assert_eq!(road(&[("highway", "service"), ("service", "alley")]), ("alley".to_owned(), 4, 3.0));
+        // other highway values with a look of their own, whatever their lanes tag says
+        assert_eq!(road(&[("highway", "track")]), ("track".to_owned(), 5, 3.0));
+        assert_eq!(road(&[("highway", "living_street"), ("lanes", "4")]), ("living_street".to_owned(), 6, 4.5));
+        assert_eq!(road(&[("highway", "busway")]), ("busway".to_owned(), 7, 6.0));
+        assert_eq!(road(&[("highway", "raceway")]), ("raceway".to_owned(), 8, 9.0));
+        assert_eq!(road(&[("highway", "bridleway")]).0, "footpath");
+    }
+
+    #[test]
+    fn runway_is_a_wide_paved_ribbon() {
+        let region = flat_region();
+        let road = |tags: &[(&str,&str)]| {
+            let mut osm = OsmFixture::default();
+            osm.way(&region, &[(10.0, 50.0), (60.0, 50.0)], tags);
+            let (buffer, stats) = osm.read("runway", &region);
+            let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+            // to the centimeter, as the ribbon edges are written relative to the road's base
+            (stats.roads.keys().next().unwrap().clone(), buffer.bytes[16], ((float(23) - float(35)).abs() * 100.0).round() / 100.0)
+        };
+        assert_eq!(road(&[("aeroway", "runway"), ("width", "60")]), ("runway".to_owned(), 9, 60.0));
+        assert_eq!(road(&[("aeroway", "runway")]), ("runway".to_owned(), 9, 45.0));
+        assert_eq!(road(&[("aeroway", "taxiway")]), ("taxiway".to_owned(), 10, 22.5));
+
+        // aprons are paved ground
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (90.0, 10.0), (90.0, 40.0), (10.0, 40.0), (10.0, 10.0)], &[("aeroway", "apron")]);
+        let (buffer, stats) = osm.read("apron", &region);
+        assert_eq!((stats.areas, buffer.bytes[0]), (1, OBJ_AREA));
+        assert_eq!((buffer.bytes[13], buffer.bytes[14]), (AreaKind::Paved as u8, Surface::Paved as u8));
+    }
+
+    #[test]
+    fn ferry_lies_at_sea_level() {
+        // a slope well above the sea, which the ferry shouldn't follow
+        let region = Region::from_fn("ferry", UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, (256, 256), |x,_| 100.0 + x as f32 * 0.5);
+        for tags in [&[("route", "ferry")][..], &[("highway", "ferry")], &[("ferry", "secondary")]] {
+            let mut osm = OsmFixture::default();
+            osm.way(&region, &[(10.0, 50.0), (60.0, 50.0), (110.0, 80.0)], tags);
+            let (buffer, stats) = osm.read("ferry", &region);
+            assert_eq!(stats.roads.get("ferry"), Some(&1), "{tags:?}");
+            assert_eq!((buffer.bytes[0], buffer.bytes[16]), (OBJ_ROAD, 11));
+
+            let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+            let count = u16::from_le_bytes([buffer.bytes[17], buffer.bytes[18]]) as usize;
+            assert!(count >= 3);
+            for node in (0..count).map(|i| 19 + i * 48) {
+                // left and right edge
+                assert_eq!((float(9) + float(node + 8), float(9) + float(node + 20)), (0.0, 0.0));
+            }
+        }
+    }
+
+    #[test]
+    fn underground_features_are_skipped() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 10.0), (40.0, 10.0), (40.0, 40.0), (10.0, 40.0), (10.0, 10.0)], &[("building", "yes"), ("location", "underground")]);
+        osm.way(&region, &[(10.0, 80.0), (90.0, 80.0)], &[("highway", "residential"), ("layer", "-2")]);
+        let (buffer, stats) = osm.read("underground", &region);
+        assert!(buffer.bytes.is_empty());
+        assert_eq!(stats.skipped_underground, 2);
+        assert!(stats.buildings.is_empty() && stats.roads.is_empty());
+
+        let (buffer, stats) = osm.read_with("underground", &region, &["--include-underground"]);
+        assert!(!buffer.bytes.is_empty());
+        assert_eq!(stats.skipped_underground, 0);
+        assert_eq!((stats.buildings.values().sum::<usize>(), stats.roads.values().sum::<usize>()), (1, 1));
+    }
+
+    #[test]
+    fn collinear_building_is_skipped() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        // all four corners on one line
+        osm.way(&region, &[(10.0, 10.0), (20.0, 10.0), (30.0, 10.0), (40.0, 10.0), (10.0, 10.0)], &[("building", "yes")]);
+        // half a square meter of paving
+        osm.way(&region, &[(10.0, 50.0), (11.0, 50.0), (11.0, 50.5), (10.0, 50.5), (10.0, 50.0)], &[("highway", "pedestrian"), ("area", "yes")]);
+        osm.way(&region, &[(60.0, 60.0), (70.0, 60.0), (70.0, 70.0), (60.0, 70.0), (60.0, 60.0)], &[("building", "yes")]);
+        let (buffer, stats) = osm.read("collinear", &region);
+        assert_eq!(stats.skipped_small, 2);
+        assert_eq!((stats.buildings.values().sum::<usize>(), stats.areas), (1, 0));
+        assert_eq!(buffer.bytes[0], OBJ_BUILDING);
+
+        // the threshold is configurable, but nothing gets a collinear outline through
+        let (_, stats) = osm.read_with("collinear", &region, &["--min-area", "0"]);
+        assert_eq!(stats.skipped_small, 0);
+        assert_eq!((stats.buildings.values().sum::<usize>(), stats.areas), (1, 1));
+        assert_eq!(stats.skipped_self_intersecting + stats.skipped_degenerate, 1);
+    }
+
+    #[test]
+    fn road_width_scale_narrows_ribbons() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 50.0), (60.0, 50.0), (110.0, 80.0)], &[("highway", "residential")]);
+        // distance between the left and right edge at every node
+        let widths = |scale: &str| {
+            let (buffer, _) = osm.read_with("width-scale", &region, &["--road-width-scale", scale]);
+            let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+            let count = u16::from_le_bytes([buffer.bytes[17], buffer.bytes[18]]) as usize;
+            (0..count).map(|i| 19 + i * 48).map(|node| {
+                let (dx,dy) = (float(node) - float(node + 12), float(node + 4) - float(node + 16));
+                (dx * dx + dy * dy).sqrt()
+            }).collect::<Vec<_>>()
+        };
+        let (full, half) = (widths("1"), widths("0.5"));
+        assert_eq!(full.len(), half.len());
+        for (full,half) in full.iter().zip(&half) {
+            assert!((half - full * 0.5).abs() < 1e-3, "{half} vs {full}");
+        }
+    }
+
+    #[test]
+    fn planned_roads_are_flagged_in_the_subtype() {
+        let region = flat_region();
+        let road = |tags: &[(&str,&str)], args: &[&str]| {
+            let mut osm = OsmFixture::default();
+            osm.way(&region, &[(10.0, 50.0), (60.0, 50.0)], tags);
+            let (buffer, stats) = osm.read_with("construction", &region, args);
+            stats.roads.keys().next().map(|name| (name.clone(), buffer.bytes[16]))
+        };
+        // skipped by default
+        assert_eq!(road(&[("highway", "construction"), ("construction", "residential")], &[]), None);
+        assert_eq!(road(&[("highway", "construction"), ("construction", "residential")], &["--construction-roads"]), Some(("road".to_owned(), 0x80)));
+        // styled as what it's going to be
+        assert_eq!(road(&[("highway", "construction"), ("construction", "service"), ("service", "driveway")], &["--construction-roads"]), Some(("driveway".to_owned(), 0x80 | 2)));
+        assert_eq!(road(&[("highway", "proposed"), ("proposed", "footway")], &["--construction-roads"]), Some(("footpath".to_owned(), 0x80)));
+        // finished roads don't get the flag
+        assert_eq!(road(&[("highway", "service"), ("service", "driveway")], &["--construction-roads"]), Some(("driveway".to_owned(), 2)));
+    }
+
+    #[test]
+    fn road_z_is_terrain_plus_offset() {
+        let region = Region::from_fn("z-offset", UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, (256, 256), |x,y| 100.0 + x as f32 * 0.1 + y as f32 * 0.2);
+        // each node's left and right z, and what the terrain is under them
+        let sides = |tags: &[(&str,&str)], args: &[&str]| {
+            let mut osm = OsmFixture::default();
+            osm.way(&region, &[(10.0, 50.0), (60.0, 50.0), (60.0, 90.0)], tags);
+            let (buffer, _) = osm.read_with("z-offset", &region, args);
+            let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+            let (base_x, base_y, base_z) = (float(1), float(5), float(9));
+            let count = u16::from_le_bytes([buffer.bytes[17], buffer.bytes[18]]) as usize;
+            (0..count).map(|i| 19 + i * 48).flat_map(|at| [at, at + 12]).map(|at| {
+                (base_z + float(at + 8), region.get_elevation(base_x + float(at), base_y + float(at + 4)))
+            }).collect::<Vec<_>>()
+        };
+        for (z,ground) in sides(&[("highway", "residential")], &[]) {
+            assert!((z - ground).abs() < 1e-3, "{z} vs {ground}");
+        }
+        for (z,ground) in sides(&[("highway", "residential")], &["--road-z-offset", "0.25"]) {
+            assert!((z - (ground + 0.25)).abs() < 1e-3, "{z} vs {ground}");
+        }
+        // level paths are lifted from their higher side
+        let path = sides(&[("highway", "footway")], &["--road-z-offset", "0.25"]);
+        for pair in path.chunks(2) {
+            let top = pair[0].1.max(pair[1].1) + 0.25;
+            assert!((pair[0].0 - top).abs() < 1e-3 && (pair[1].0 - top).abs() < 1e-3, "{pair:?}");
+        }
+    }
+
+    #[test]
+    fn bridge_deck_clears_the_building_beneath() {
+        let region = flat_region();
+        let deck = |layer: &str| {
+            let mut osm = OsmFixture::default();
+            osm.way(&region, &[(40.0, 40.0), (60.0, 40.0), (60.0, 60.0), (40.0, 60.0), (40.0, 40.0)], &[("building", "yes"), ("height", "4")]);
+            osm.way(&region, &[(20.0, 50.0), (50.0, 50.0), (80.0, 50.0)], &[("highway", "residential"), ("bridge", "yes"), ("layer", layer)]);
+            let (_, stats) = osm.read("bridge", &region);
+            assert_eq!(stats.roads.values().sum::<usize>(), 0);
+
+            let (buffer, stats) = osm.read_with("bridge", &region, &["--bridges"]);
+            assert_eq!((stats.buildings.values().sum::<usize>(), stats.roads.values().sum::<usize>()), (1, 1));
+            let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+            let (building_base, building_top) = (float(9), float(9) + float(17));
+            assert_eq!((building_base, building_top), (100.0, 104.0));
+
+            // the road's node nearest the building's middle
+            let road = 28 + 2 + 4 * 8;
+            assert_eq!(buffer.bytes[road], OBJ_ROAD);
+            let (base_x, base_z) = (float(road + 1), float(road + 9));
+            let count = u16::from_le_bytes([buffer.bytes[road + 17], buffer.bytes[road + 18]]) as usize;
+            let middle = (0..count).map(|i| road + 19 + i * 48).min_by(|a,b| {
+                (base_x + float(*a) - 50.0).abs().total_cmp(&(base_x + float(*b) - 50.0).abs())
+            }).unwrap();
+            let z = base_z + float(middle + 8);
+            assert!(z > building_top, "deck at {z} on layer {layer}");
+            z - building_base
+        };
+        assert_eq!(deck("1"), 5.0);
+        // an unset or ground layer is still a bridge, and higher layers climb by the clearance
+        assert_eq!(deck("0"), 5.0);
+        assert_eq!(deck("2"), 10.0);
+    }
+
+    #[test]
+    fn interchange_layers_are_stacked() {
+        let region = flat_region();
+        // three ramps crossing over the same point, each one layer up
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 50.0), (50.0, 50.0), (90.0, 50.0)], &[("highway", "motorway")]);
+        osm.way(&region, &[(50.0, 10.0), (50.0, 50.0), (50.0, 90.0)], &[("highway", "motorway_link"), ("layer", "1")]);
+        osm.way(&region, &[(20.0, 20.0), (50.0, 50.0), (80.0, 80.0)], &[("highway", "motorway_link"), ("layer", "2")]);
+        // every node's z in each road, in order
+        let heights = |args: &[&str]| {
+            let (buffer, stats) = osm.read_with("interchange", &region, args);
+            assert_eq!(stats.roads.values().sum::<usize>(), 3);
+            let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+            let mut roads = Vec::new();
+            let mut at = 0;
+            while at < buffer.bytes.len() {
+                assert_eq!(buffer.bytes[at], OBJ_ROAD);
+                let count = u16::from_le_bytes([buffer.bytes[at + 17], buffer.bytes[at + 18]]) as usize;
+                let base_z = float(at + 9);
+                let z: Vec<f32> = (0..count).map(|i| at + 19 + i * 48).flat_map(|node| [base_z + float(node + 8), base_z + float(node + 20)]).collect();
+                roads.push(z);
+                at += 19 + count * 48;
+            }
+            roads
+        };
+        // on the ground without the option
+        for road in heights(&[]) {
+            assert!(road.iter().all(|z| (z - 100.0).abs() < 1e-3), "{road:?}");
+        }
+        // one --layer-height apart with it
+        for (layer,road) in heights(&["--layer-height", "6"]).iter().enumerate() {
+            let expected = 100.0 + layer as f32 * 6.0;
+            assert!(road.iter().all(|z| (z - expected).abs() < 1e-3), "layer {layer}: {road:?}");
+        }
+    }
+
+    #[test]
+    fn bridge_height_tag_sets_the_clearance() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(20.0, 50.0), (50.0, 50.0), (80.0, 50.0)], &[("highway", "residential"), ("bridge", "yes"), ("height", "7")]);
+        let (buffer, _) = osm.read_with("bridge-height", &region, &["--bridges"]);
+        let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+        let count = u16::from_le_bytes([buffer.bytes[17], buffer.bytes[18]]) as usize;
+        // the highest point of the deck, in the middle
+        let top = (0..count).map(|i| float(9) + float(19 + i * 48 + 8)).fold(f32::MIN, f32::max);
+        assert_eq!(top - 100.0, 7.0);
+    }
+
+    #[test]
+    fn origin_shifts_every_record() {
+        let region = flat_region();
+        let square = [(10.0, 10.0), (30.0, 10.0), (30.0, 30.0), (10.0, 30.0), (10.0, 10.0)];
+        // 100m east and 50m south of the map's corner
+        let origin = format!("{},{}",region.coord.easting + 100.0,region.coord.northing - 50.0);
+
+        for name in ["peak", "tree", "building", "area", "road"] {
+            let mut osm = OsmFixture::default();
+            match name {
+                "peak" => { osm.node(&region, (40.0, 60.0), &[("natural", "peak")]); }
+                "tree" => { osm.node(&region, (40.0, 60.0), &[("natural", "tree")]); }
+                "building" => { osm.way(&region, &square, &[("building", "yes")]); }
+                "area" => { osm.way(&region, &square, &[("highway", "pedestrian"), ("area", "yes")]); }
+                _ => { osm.way(&region, &[(10.0, 50.0), (90.0, 50.0)], &[("highway", "residential")]); }
+            }
+            let (plain, _) = osm.read(name, &region);
+            let (shifted, _) = osm.read_with(name, &region, &["--origin", &origin]);
+            let float = |buffer: &Buffer, at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+
+            assert_eq!(plain.bytes.len(), shifted.bytes.len(), "{name}");
+            assert!((float(&shifted, 1) - (float(&plain, 1) - 100.0)).abs() < 0.01, "{name}");
+            assert!((float(&shifted, 5) - (float(&plain, 5) - 50.0)).abs() < 0.01, "{name}");
+            // everything past the position is relative to it, or not a position
+            assert_eq!(plain.bytes[9..], shifted.bytes[9..], "{name}");
+        }
+
+        let mut osm = OsmFixture::default();
+        let center = osm.node(&region, (50.0, 50.0), &[]);
+        for end in [(10.0, 50.0), (90.0, 50.0), (50.0, 90.0)] {
+            let end = osm.node(&region, end, &[]);
+            osm.way_through(&[end, center], &[("highway", "residential")]);
+        }
+        let (plain, _) = osm.read("origin-junction", &region);
+        let (shifted, _) = osm.read_with("origin-junction", &region, &["--origin", &origin]);
+        let junction = |buffer: &Buffer| {
+            let at = buffer.bytes.len() - (1 + 12 + 2 + 3 * 4);
+            assert_eq!(buffer.bytes[at], OBJ_JUNCTION);
+            (f32::from_le_bytes(buffer.bytes[at + 1..at + 5].try_into().unwrap()), f32::from_le_bytes(buffer.bytes[at + 5..at + 9].try_into().unwrap()))
+        };
+        let ((x,y), (shifted_x,shifted_y)) = (junction(&plain), junction(&shifted));
+        assert!((shifted_x - (x - 100.0)).abs() < 0.01 && (shifted_y - (y - 50.0)).abs() < 0.01);
+    }
+
+    #[test]
+    fn tunnel_portals_at_terrain_height() {
+        // rising 1m every 10m to the east
+        let region = Region::from_fn("test", UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, (2048, 2048), |x,_| x as f32 * 0.1);
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(20.0, 50.0), (60.0, 50.0), (100.0, 50.0)], &[("highway", "primary"), ("tunnel", "yes")]);
+        let (buffer, stats) = osm.read_with("portals", &region, &["--portals"]);
+
+        // kind, position, elevation, portal kind, direction into the tunnel, half width
+        let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+        assert_eq!(stats.portals, 2);
+        assert_eq!(buffer.bytes.len(), 2 * 26);
+        for (at, x, dir) in [(0, 20.0, 1.0), (26, 100.0, -1.0)] {
+            assert_eq!(buffer.bytes[at], OBJ_PORTAL);
+            assert!((float(at + 1) - x).abs() < 0.01 && (float(at + 5) - 50.0).abs() < 0.01, "({},{})",float(at + 1),float(at + 5));
+            assert!((float(at + 9) - x * 0.1).abs() < 0.15, "{} at {}",float(at + 9),x);
+            assert_eq!(buffer.bytes[at + 13], PortalKind::Tunnel as u8);
+            assert!((float(at + 14) - dir).abs() < 0.01 && float(at + 18).abs() < 0.01);
+        }
+
+        // without --portals the tunnel leaves nothing behind
+        let (buffer, _) = osm.read("portals", &region);
+        assert!(buffer.bytes.is_empty());
+    }
+
+    /// Tag lookup over a fixed list, standing in for an OSM way.
+    fn tags<'a>(pairs: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<&'a str> {
+        move |key| pairs.iter().find(|(k,_)| *k == key).map(|(_,v)| *v)
+    }
+
+    #[test]
+    fn access_bits_per_mode() {
+        assert_eq!(road_access(tags(&[])), 0);
+        // everything inherits access
+        assert_eq!(road_access(tags(&[("access", "no")])), 0b10_10_10_10);
+        assert_eq!(road_access(tags(&[("access", "private")])), 0b01_01_01_01);
+        // modes override it
+        assert_eq!(road_access(tags(&[("access", "no"), ("foot", "yes")])), 0b00_10_10_10);
+        assert_eq!(road_access(tags(&[("bicycle", "no")])), 0b00_10_00_00);
+        assert_eq!(road_access(tags(&[("motor_vehicle", "delivery"), ("bicycle", "no")])), 0b00_10_01_00);
+    }
+
+    #[test]
+    fn access_byte_in_the_road_record() {
+        let region = flat_region();
+        let mut osm = OsmFixture::default();
+        osm.way(&region, &[(10.0, 50.0), (90.0, 50.0)], &[("highway", "residential"), ("access", "private"), ("bicycle", "no")]);
+        let (buffer, _) = osm.read("access", &region);
+
+        // kind, 3 floats, road kind, lanes, then access
+        assert_eq!(buffer.bytes[0], OBJ_ROAD);
+        assert_eq!(buffer.bytes[15], 0b01_10_01_01);
+    }
+
+    #[test]
+    fn width_scale_must_be_positive() {
+        assert_eq!(parse_width_scale("0.5"), Ok(0.5));
+        for bad in ["0", "-1", "inf", "NaN", "wide"] {
+            assert!(parse_width_scale(bad).is_err(), "{bad} accepted");
+        }
+        assert!(CommandArgs::try_parse_from(["cartographer", "test", "33", "--road-width-scale", "0"]).is_err());
+    }
+
+    #[test]
+    fn oneway_values() {
+        assert_eq!(parse_oneway(Some("yes")), Oneway::Forward);
+        assert_eq!(parse_oneway(Some("1")), Oneway::Forward);
+        assert_eq!(parse_oneway(Some("true")), Oneway::Forward);
+        assert_eq!(parse_oneway(Some("-1")), Oneway::Backward);
+        assert_eq!(parse_oneway(Some("no")), Oneway::No);
+        assert_eq!(parse_oneway(Some("reversible")), Oneway::No);
+        assert_eq!(parse_oneway(None), Oneway::No);
+    }
+
+    #[test]
+    fn lengths_in_feet_and_meters() {
+        assert_eq!(parse_length("2000"), Some(2000.0));
+        assert_eq!(parse_length("12.5 m"), Some(12.5));
+        assert_eq!(parse_length("100 ft"), Some(30.48));
+        assert_eq!(parse_length("10'"), Some(3.048));
+        assert_eq!(parse_length("tall"), None);
     }
 }
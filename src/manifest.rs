@@ -0,0 +1,137 @@
+use std::sync::Mutex;
+
+use serde_json::json;
+
+use crate::Endian;
+
+/// Held while the manifest is rewritten, since the map and elevation can both be adding to it at once.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Describes the contents of an output directory, written next to the tiles.
+pub struct Manifest {
+    pub region: String,
+    pub preview: bool,
+    pub tiles: Vec<usize>,
+    /// Number of tiles across and down the full map
+    pub grid: (usize,usize),
+    /// Samples across one tile (before stitching to its neighbors)
+    pub tile_size: usize,
+    /// Byte order of the tile files
+    pub endian: Endian,
+    /// UTM easting/northing that output coordinates are relative to
+    pub origin: (f64,f64),
+    /// Where tile 0's corner lands in output coordinates
+    pub tile_offset: (f32,f32),
+    /// Elevation above sea level that output heights are measured from
+    pub datum: f32,
+}
+
+/// Reads the manifest in `out_dir` (or starts an empty one), lets `change` edit it and writes it back.
+/// Whatever `change` doesn't touch is kept, so elevation and map runs can each add their part.
+fn update(out_dir: &str, change: impl FnOnce(&mut serde_json::Value)) {
+    let _lock = LOCK.lock().unwrap();
+    let path = format!("{}/manifest.json",out_dir);
+    let mut json = std::fs::read_to_string(&path).ok()
+        .and_then(|text| serde_json::from_str::<serde_json::Value>(&text).ok())
+        .filter(|json| json.is_object())
+        .unwrap_or_else(|| json!({}));
+    change(&mut json);
+    std::fs::write(&path, serde_json::to_string_pretty(&json).unwrap()).unwrap();
+}
+
+impl Manifest {
+    /// Writes the elevation fields, keeping the map's entries and checksums from an earlier run.
+    pub fn save(&self, out_dir: &str) {
+        let fields = json!({
+            "region": self.region,
+            "preview": self.preview,
+            "tiles": self.tiles,
+            "grid": [self.grid.0, self.grid.1],
+            "tile_size": self.tile_size,
+            "endian": self.endian.name(),
+            "origin": [self.origin.0, self.origin.1],
+            "tile_offset": [self.tile_offset.0, self.tile_offset.1],
+            "datum": self.datum,
+        });
+        update(out_dir, |json| {
+            for (key,value) in fields.as_object().unwrap() {
+                json[key] = value.clone();
+            }
+        });
+    }
+
+    /// Adds crc32s of written files (by file name) to the manifest's `checksums`.
+    pub fn record_checksums(out_dir: &str, checksums: &[(String, u32)]) {
+        update(out_dir, |json| {
+            if !json["checksums"].is_object() {
+                json["checksums"] = json!({});
+            }
+            for (filename,crc) in checksums {
+                json["checksums"][filename] = json!(format!("{:08x}",crc));
+            }
+        });
+    }
+
+    /// Notes the elevations that --heightmap-png's 0 and 65535 stand for.
+    pub fn record_heightmaps(out_dir: &str, min: f32, max: f32) {
+        update(out_dir, |json| {
+            json["heightmaps"] = json!({
+                "min": min,
+                "max": max,
+            });
+        });
+    }
+
+    /// Notes the map's feature count in the manifest (creating one if elevation wasn't processed),
+    /// so an empty map can be told apart from a broken one.
+    pub fn record_map(out_dir: &str, features: usize, endian: Endian, datum: f32) {
+        update(out_dir, |json| {
+            json["map"] = json!({
+                "features": features,
+                "empty": features == 0,
+            });
+            json["endian"] = json!(endian.name());
+            json["datum"] = json!(datum);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(out_dir: &str) -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(format!("{}/manifest.json",out_dir)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn save_keeps_an_earlier_map_run() {
+        let dir = std::env::temp_dir().join(format!("cartographer-manifest-{}",std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out_dir = dir.to_str().unwrap();
+
+        // -m first, then -e
+        Manifest::record_checksums(out_dir, &[("map.bin.gz".to_owned(), 0xabc)]);
+        Manifest::record_map(out_dir, 7, Endian::Le, 0.0);
+        let manifest = Manifest {
+            region: "test".to_owned(),
+            preview: false,
+            tiles: vec![0, 1],
+            grid: (2, 1),
+            tile_size: 512,
+            endian: Endian::Le,
+            origin: (500000.0, 4000000.0),
+            tile_offset: (0.0, 0.0),
+            datum: 0.0
+        };
+        manifest.save(out_dir);
+        Manifest::record_checksums(out_dir, &[("tile0.bin.gz".to_owned(), 1)]);
+
+        let json = read(out_dir);
+        assert_eq!(json["map"]["features"], 7);
+        assert_eq!(json["checksums"]["map.bin.gz"], "00000abc");
+        assert_eq!(json["checksums"]["tile0.bin.gz"], "00000001");
+        assert_eq!(json["tiles"], json!([0, 1]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
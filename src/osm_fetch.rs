@@ -2,23 +2,97 @@ use std::{path::Path, time::Duration};
 
 use crate::region::Bounds;
 
-pub fn fetch(bounds: Bounds, path: &Path) {
-    let client = reqwest::blocking::Client::new();
-    let bbox = format!("{},{},{},{}",bounds.south,bounds.west,bounds.north,bounds.east);
+/// Groups of features that --fetch-features can narrow the Overpass query to.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum FetchFeature {
+    Buildings,
+    Highways,
+    Water,
+    /// natural=* and landuse=*, including peaks and trees
+    Nature,
+    Indoor
+}
+
+impl FetchFeature {
+    /// Overpass selectors for the feature, without the trailing bbox.
+    fn selectors(self) -> &'static [&'static str] {
+        match self {
+            FetchFeature::Buildings => &[r#"way["building"]"#, r#"relation["building"]"#],
+            FetchFeature::Highways => &[r#"way["highway"]"#],
+            FetchFeature::Water => &[r#"way["natural"="water"]"#, r#"way["waterway"]"#, r#"relation["natural"="water"]"#],
+            FetchFeature::Nature => &[r#"node["natural"]"#, r#"way["natural"]"#, r#"way["landuse"]"#],
+            FetchFeature::Indoor => &[r#"way["indoor"]"#]
+        }
+    }
+
+    /// Short name for file names, matching the command line spelling.
+    pub fn name(self) -> &'static str {
+        match self {
+            FetchFeature::Buildings => "buildings",
+            FetchFeature::Highways => "highways",
+            FetchFeature::Water => "water",
+            FetchFeature::Nature => "nature",
+            FetchFeature::Indoor => "indoor"
+        }
+    }
+}
+
+/// Checks an --osm-date value, which Overpass wants as `YYYY-MM-DDTHH:MM:SSZ`. A bare date means midnight.
+pub fn parse_osm_date(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    let full = if value.len() == 10 { format!("{}T00:00:00Z",value) } else { value.to_owned() };
+    let digits = |range: std::ops::Range<usize>| full.get(range).is_some_and(|part| part.bytes().all(|b| b.is_ascii_digit()));
+    let separators = full.len() == 20 && [(4,b'-'), (7,b'-'), (10,b'T'), (13,b':'), (16,b':'), (19,b'Z')].iter().all(|(i,c)| full.as_bytes()[*i] == *c);
+    if separators && digits(0..4) && digits(5..7) && digits(8..10) && digits(11..13) && digits(14..16) && digits(17..19) {
+        Ok(full)
+    } else {
+        Err(format!("expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SSZ, got {:?}",value))
+    }
+}
+
+/// Builds the Overpass QL query for everything inside the bounds, or only the given features if there are any.
+/// Bounds across the date line are queried as two boxes. With a date, the data is as it was then.
+pub fn build_query(bounds: &Bounds, features: &[FetchFeature], date: Option<&str>) -> String {
+    let bboxes = bounds.bboxes();
+    let date = date.map(|date| format!("[date:\"{date}\"]")).unwrap_or_default();
+    if !features.is_empty() {
+        let mut selectors = String::new();
+        for feature in features {
+            for selector in feature.selectors() {
+                for bbox in &bboxes {
+                    selectors += &format!("            {selector}({bbox});\n");
+                }
+            }
+        }
+        // the selected ways and relations, then the nodes (and member ways) they need
+        return format!(r#"
+        [out:xml]
+        [timeout:60]{date}
+        ;
+        (
+{selectors}        );
+        (._;>;);
+        out body;
+    "#);
+    }
     // south west north east
-    let query = format!(r#"
+    let nodes = match bboxes.as_slice() {
+        [bbox] => format!("node({bbox});"),
+        bboxes => format!("({});",bboxes.iter().map(|bbox| format!("node({bbox});")).collect::<Vec<_>>().join(" "))
+    };
+    format!(r#"
         [out:xml]
-        [timeout:60]
+        [timeout:60]{date}
         ;
         (
-            node({bbox});
+            {nodes}
             <;
             >;
 
             //node(w);
 
-            //way({bbox});
-            //relation({bbox});
+            //way(bbox);
+            //relation(bbox);
 
             // select children
             //node(r);
@@ -26,14 +100,149 @@ pub fn fetch(bounds: Bounds, path: &Path) {
             //way(r);
         );
         out body;
-    "#);
+    "#)
+}
+
+const OVERPASS_URL: &str = "https://overpass-api.de/api";
+
+/// Reads an Overpass /status response. Returns how many seconds until a slot is free
+/// (0 if one is free now), or None if the response doesn't say.
+pub fn parse_status(text: &str) -> Option<u64> {
+    let mut wait: Option<u64> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(count) = line.strip_suffix("slots available now.") {
+            if count.trim().parse::<u64>().ok()? > 0 {
+                return Some(0);
+            }
+        } else if line.starts_with("Slot available after:") {
+            // "Slot available after: 2025-01-01T00:00:00Z, in 12 seconds."
+            let seconds = line.rsplit_once(", in ")?.1.strip_suffix(" seconds.")?;
+            let seconds: u64 = seconds.trim().parse().ok()?;
+            wait = Some(wait.map_or(seconds, |w| w.min(seconds)));
+        }
+    }
+    wait
+}
+
+/// Polls the status endpoint until a query slot is free. Gives up quietly if the
+/// status can't be fetched or understood, the query itself will still be attempted.
+/// `status` fetches the status text and `sleep` waits, so tests can stand in for both.
+fn wait_for_slot(mut status: impl FnMut() -> Option<String>, mut sleep: impl FnMut(Duration)) {
+    for _ in 0..10 {
+        let Some(status) = status() else {
+            println!("> could not reach overpass status, fetching anyway");
+            return;
+        };
+        match parse_status(&status) {
+            Some(0) => return,
+            Some(seconds) => {
+                println!("> waiting {}s for an overpass slot",seconds);
+                sleep(Duration::from_secs(seconds + 1));
+            }
+            None => return
+        }
+    }
+}
+
+/// Fetches everything inside the bounds into `path`. The file is only written once the whole response is in,
+/// so a failed fetch leaves whatever was there before.
+pub fn fetch(bounds: Bounds, features: &[FetchFeature], date: Option<&str>, path: &Path, check_status: bool) -> Result<(), String> {
+    let client = reqwest::blocking::Client::new();
+    let query = build_query(&bounds, features, date);
 
     let encoded = format!("data={}",urlencoding::encode(&query));
 
+    if check_status {
+        let status = || client.get(format!("{}/status",OVERPASS_URL))
+            .timeout(Duration::from_secs(30))
+            .send()
+            .and_then(|res| res.text())
+            .ok();
+        wait_for_slot(status, std::thread::sleep);
+    }
+
     println!("> fetching osm...");
-    let res = client.post("https://overpass-api.de/api/interpreter")
+    let res = client.post(format!("{}/interpreter",OVERPASS_URL))
         .body(encoded)
         .timeout(Duration::from_secs(600))
-        .send().unwrap();
-    std::fs::write(path, res.text().unwrap()).unwrap();
+        .send()
+        .and_then(|res| res.error_for_status())
+        .map_err(|err| format!("overpass request failed: {}",err))?;
+    let text = res.text().map_err(|err| format!("overpass response failed: {}",err))?;
+    std::fs::write(path, text).unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_covers_the_bounds() {
+        let bounds = Bounds { south: 45.1, north: 45.2, east: 15.3, west: 15.25 };
+        let query = build_query(&bounds, &[], None);
+        // south, west, north, east
+        assert!(query.contains("node(45.1,15.25,45.2,15.3);"), "{query}");
+        for selector in ["[out:xml]", "<;", ">;", "out body;"] {
+            assert!(query.contains(selector), "{selector} missing from {query}");
+        }
+    }
+
+    #[test]
+    fn query_has_only_the_selected_features() {
+        let bounds = Bounds { south: 45.1, north: 45.2, east: 15.3, west: 15.25 };
+        let query = build_query(&bounds, &[FetchFeature::Buildings, FetchFeature::Water], None);
+        let selectors: Vec<&str> = query.lines().map(str::trim).filter(|line| line.ends_with("(45.1,15.25,45.2,15.3);")).collect();
+        assert_eq!(selectors, [
+            r#"way["building"](45.1,15.25,45.2,15.3);"#,
+            r#"relation["building"](45.1,15.25,45.2,15.3);"#,
+            r#"way["natural"="water"](45.1,15.25,45.2,15.3);"#,
+            r#"way["waterway"](45.1,15.25,45.2,15.3);"#,
+            r#"relation["natural"="water"](45.1,15.25,45.2,15.3);"#,
+        ]);
+        assert!(!query.contains("highway") && !query.contains("node("), "{query}");
+        // the nodes under the selected ways still come along
+        assert!(query.contains("(._;>;);") && query.contains("out body;"), "{query}");
+    }
+
+    #[test]
+    fn dated_query_has_the_date_setting() {
+        let bounds = Bounds { south: 45.1, north: 45.2, east: 15.3, west: 15.25 };
+        for features in [&[][..], &[FetchFeature::Buildings]] {
+            let query = build_query(&bounds, features, Some("2019-06-01T00:00:00Z"));
+            assert!(query.contains(r#"[timeout:60][date:"2019-06-01T00:00:00Z"]"#), "{query}");
+            let query = build_query(&bounds, features, None);
+            assert!(!query.contains("[date:"), "{query}");
+        }
+    }
+
+    #[test]
+    fn osm_date_is_filled_out_and_checked() {
+        assert_eq!(parse_osm_date("2019-06-01"), Ok("2019-06-01T00:00:00Z".to_owned()));
+        assert_eq!(parse_osm_date(" 2019-06-01T12:30:00Z"), Ok("2019-06-01T12:30:00Z".to_owned()));
+        for bad in ["2019-6-1", "2019-06-01 12:30:00", "2019-06-01T12:30:00", "yesterday", ""] {
+            assert!(parse_osm_date(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn waits_until_a_slot_is_free() {
+        let mut responses = vec![
+            "Connected as: 1\n0 slots available now.\nSlot available after: 2025-01-01T00:00:05Z, in 5 seconds.\n",
+            "Connected as: 1\n1 slots available now.\n",
+        ].into_iter();
+        let mut polls = 0;
+        let mut slept = Vec::new();
+        wait_for_slot(|| { polls += 1; responses.next().map(String::from) }, |wait| slept.push(wait));
+        assert_eq!(polls, 2);
+        assert_eq!(slept, [Duration::from_secs(6)]);
+    }
+
+    #[test]
+    fn unreachable_status_does_not_wait() {
+        let mut slept = Vec::new();
+        wait_for_slot(|| None, |wait| slept.push(wait));
+        assert!(slept.is_empty());
+    }
 }
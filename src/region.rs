@@ -1,20 +1,94 @@
-use std::{collections::VecDeque, path::Path, sync::{Arc, Mutex}, thread::available_parallelism};
+use std::{collections::VecDeque, fs::File, io::{Read, Seek}, num::NonZeroUsize, panic::AssertUnwindSafe, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc, Condvar, Mutex}, thread::available_parallelism, time::Instant};
 
-use tiff::{decoder::DecodingResult, tags::Tag};
+use flate2::read::GzDecoder;
+use tiff::{decoder::{ChunkType, Decoder, DecodingResult}, tags::{CompressionMethod, Predictor, Tag}};
 
-use crate::{elevation::build_terrain_mesh, osm_fetch, read_osm};
+use crate::{coords, ensure_dir_exists, heightmap::save_heightmap, elevation::{build_terrain_mesh, build_terrain_raster, print_mesh_summary, ElevationFormat, MeshOptions, MeshStats, RasterOptions}, manifest::Manifest, osm_fetch, read_osm, OsmStats, rules::Rules, validate::{validate_file, OutputKind}, CommandArgs};
 
 #[derive(Debug)]
 pub struct UTMCoord {
     pub zone_number: u8,
+    /// Southern hemisphere northings are offset by 10,000km, so they need telling apart
+    pub northern: bool,
     pub easting: f64,
     pub northing: f64,
 }
 
+/// What process_elevation did.
+#[derive(Default)]
+pub struct ElevationReport {
+    pub written: usize,
+    pub failed: usize,
+    /// Meshing stats of each written tile, by index (none for rasters)
+    pub mesh_stats: Vec<(usize, MeshStats)>,
+    /// Time taken for each written tile, by index
+    pub seconds: Vec<(usize, f64)>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ElevationUnits {
+    /// Meters
+    M,
+    /// Feet
+    Ft
+}
+
+/// How an elevation map is read into a region: the part of the command line that loading needs,
+/// so regions can be built from a decoder without a full set of arguments.
+pub struct LoadOptions {
+    pub zone_number: u8,
+    /// Hemisphere to assume when the map's GeoTIFF keys don't say
+    pub southern: bool,
+    /// Vertical units, or None to detect them from the map (falling back to meters)
+    pub units: Option<ElevationUnits>,
+    /// Directory the elevation, bathymetry and OSM inputs are read from
+    pub input_dir: String,
+    /// Directory each region's outputs go under, in a directory named after the region
+    pub output_dir: String,
+    /// Name of a bathymetry map in the input directory to fill in water with
+    pub bathymetry: Option<String>,
+    /// Box blur radius in samples
+    pub smooth: Option<usize>,
+    /// Most samples a map may have before it's refused
+    pub max_region_size: u64,
+    /// Floor and ceiling (meters) to clamp the terrain to
+    pub min_elevation: Option<f32>,
+    pub max_elevation: Option<f32>,
+}
+
+impl LoadOptions {
+    pub fn from_args(args: &CommandArgs) -> Self {
+        LoadOptions {
+            zone_number: args.zone_number,
+            southern: args.southern,
+            units: args.elevation_units,
+            input_dir: "input".to_owned(),
+            output_dir: "output".to_owned(),
+            bathymetry: args.bathymetry.clone(),
+            smooth: args.smooth,
+            max_region_size: args.max_region_size,
+            min_elevation: args.min_elevation,
+            max_elevation: args.max_elevation,
+        }
+    }
+}
+
 pub struct Region {
     pub name: String,
     pub coord: UTMCoord,
-    tiles: Vec<Arc<Tile>>,
+    pub input_dir: String,
+    pub output_dir: String,
+    /// Size of the elevation map in samples (= meters)
+    pub width: u32,
+    pub height: u32,
+    /// Number of 512 tiles across and down
+    tiles_x: usize,
+    tiles_y: usize,
+    tiles: Arc<TileStore>,
+    /// Set once an out-of-bounds elevation query has been reported
+    warned_out_of_bounds: AtomicBool,
+    /// Elevation that output heights are measured from; sea level unless --normalize-heights
+    pub datum: f32,
 }
 
 pub struct Tile {
@@ -27,6 +101,114 @@ impl Tile {
     pub fn get(&self, x: usize, y: usize) -> f32 {
         self.data[y * self.width as usize + x]
     }
+
+    /// Like `get`, but None outside the tile instead of panicking (or silently wrapping into the next row).
+    pub fn get_checked(&self, x: usize, y: usize) -> Option<f32> {
+        if x < self.width as usize && y < self.height as usize {
+            self.data.get(y * self.width as usize + x).copied()
+        } else {
+            None
+        }
+    }
+
+    /// Converts samples in the given units to meters.
+    pub fn convert_to_meters(&mut self, units: ElevationUnits) {
+        if let ElevationUnits::Ft = units {
+            for z in self.data.iter_mut() {
+                *z *= 0.3048;
+            }
+        }
+    }
+
+    /// The `size`x`size` block at block position (bx, by), clipped to the tile, or None if the tile doesn't reach it.
+    pub fn block(&self, bx: usize, by: usize, size: usize) -> Option<Tile> {
+        let (x0,y0) = (bx * size, by * size);
+        if x0 >= self.width as usize || y0 >= self.height as usize {
+            return None;
+        }
+        let width = size.min(self.width as usize - x0);
+        let height = size.min(self.height as usize - y0);
+        let mut data = Vec::with_capacity(width * height);
+        for y in y0..y0 + height {
+            let start = y * self.width as usize + x0;
+            data.extend_from_slice(&self.data[start..start + width]);
+        }
+        Some(Tile { data, width: width as u32, height: height as u32 })
+    }
+
+    /// Clamps samples to --min-elevation / --max-elevation. Missing (NaN) samples stay missing.
+    pub fn clamp_elevation(&mut self, min: Option<f32>, max: Option<f32>) {
+        for z in self.data.iter_mut().filter(|z| !z.is_nan()) {
+            if let Some(min) = min {
+                *z = z.max(min);
+            }
+            if let Some(max) = max {
+                *z = z.min(max);
+            }
+        }
+    }
+
+    /// Fills water (no data, or at/below sea level) with depths from a bathymetry tile of the same layout.
+    pub fn merge_bathymetry(&mut self, bathymetry: &Tile) {
+        assert_eq!((self.width,self.height),(bathymetry.width,bathymetry.height),"bathymetry tile sized wrongly");
+        for (land,depth) in self.data.iter_mut().zip(bathymetry.data.iter()) {
+            let is_water = *land <= 0.0 || land.is_nan();
+            if is_water && depth.is_finite() {
+                *land = depth.min(0.0);
+            }
+        }
+    }
+}
+
+/// A region's tiles, which may still be arriving from a background reader (--thread-chunked).
+/// `get` waits until the tile is in.
+pub struct TileStore {
+    /// The tiles, and whether the reader gave up
+    slots: Mutex<(Vec<Option<Arc<Tile>>>, bool)>,
+    arrived: Condvar,
+}
+
+impl TileStore {
+    fn filled(tiles: Vec<Tile>) -> Self {
+        TileStore {
+            slots: Mutex::new((tiles.into_iter().map(|tile| Some(Arc::new(tile))).collect(), false)),
+            arrived: Condvar::new()
+        }
+    }
+
+    fn empty(count: usize) -> Self {
+        TileStore {
+            slots: Mutex::new((vec![None; count], false)),
+            arrived: Condvar::new()
+        }
+    }
+
+    fn put(&self, index: usize, tile: Tile) {
+        self.slots.lock().unwrap().0[index] = Some(Arc::new(tile));
+        self.arrived.notify_all();
+    }
+
+    fn fail(&self) {
+        self.slots.lock().unwrap().1 = true;
+        self.arrived.notify_all();
+    }
+
+    fn len(&self) -> usize {
+        self.slots.lock().unwrap().0.len()
+    }
+
+    pub fn get(&self, index: usize) -> Arc<Tile> {
+        let mut slots = self.slots.lock().unwrap();
+        loop {
+            if let Some(tile) = &slots.0[index] {
+                return tile.clone();
+            }
+            if slots.1 {
+                panic!("tile {} was never read, the elevation map reader failed",index);
+            }
+            slots = self.arrived.wait(slots).unwrap();
+        }
+    }
 }
 
 pub struct TileNeighbors {
@@ -35,6 +217,7 @@ pub struct TileNeighbors {
     pub corner: Option<Arc<Tile>>
 }
 
+/// Lat/lon box. West is greater than east when the box crosses the 180° meridian.
 pub struct Bounds {
     pub south: f64,
     pub north: f64,
@@ -42,131 +225,2308 @@ pub struct Bounds {
     pub west: f64
 }
 
-const REGION_SIZE: u32 = 10012;
+impl Bounds {
+    /// Smallest box around the (lat, lon) corners, with latitude clamped to the poles and
+    /// longitude wrapped to -180..180.
+    pub fn from_corners(corners: &[(f64,f64)]) -> Self {
+        let lats = corners.iter().map(|(lat,_)| lat.clamp(-90.0, 90.0));
+        let lons: Vec<f64> = corners.iter().map(|(_,lon)| (lon + 180.0).rem_euclid(360.0) - 180.0).collect();
+        let south = lats.clone().fold(1.0f64/0.0, f64::min);
+        let north = lats.fold(-1.0f64/0.0, f64::max);
+        let mut west = lons.iter().copied().fold(1.0f64/0.0, f64::min);
+        let mut east = lons.iter().copied().fold(-1.0f64/0.0, f64::max);
+        if east - west > 180.0 {
+            // no region is half the globe wide, so the corners straddle the date line
+            west = lons.iter().copied().filter(|lon| *lon >= 0.0).fold(1.0f64/0.0, f64::min);
+            east = lons.iter().copied().filter(|lon| *lon < 0.0).fold(-1.0f64/0.0, f64::max);
+        }
+        Bounds { south, north, east, west }
+    }
+
+    pub fn crosses_date_line(&self) -> bool {
+        self.west > self.east
+    }
+
+    /// Overpass bboxes (south,west,north,east) covering the bounds: two when it crosses the date line.
+    pub fn bboxes(&self) -> Vec<String> {
+        if self.crosses_date_line() {
+            vec![
+                format!("{},{},{},{}",self.south,self.west,self.north,180.0),
+                format!("{},{},{},{}",self.south,-180.0,self.north,self.east)
+            ]
+        } else {
+            vec![format!("{},{},{},{}",self.south,self.west,self.north,self.east)]
+        }
+    }
+}
+
+
+/// Seconds left for `remaining` tiles, going by how far apart the recent completions (seconds since the start,
+/// across all workers) came. None until there are two completions to measure between.
+pub fn estimate_remaining(recent: &[f64], remaining: usize) -> Option<f64> {
+    if recent.len() < 2 {
+        return None;
+    }
+    let per_tile = (recent[recent.len()-1] - recent[0]) / (recent.len() - 1) as f64;
+    Some(per_tile * remaining as f64)
+}
+
+/// Tile completions across all workers, for the time-left estimate.
+struct Progress {
+    started: Instant,
+    total: usize,
+    done: usize,
+    /// Completion times of the last few tiles
+    recent: VecDeque<f64>,
+}
+
+impl Progress {
+    const WINDOW: usize = 20;
+
+    fn new(total: usize) -> Self {
+        Progress { started: Instant::now(), total, done: 0, recent: VecDeque::new() }
+    }
+
+    /// Notes a finished (or failed) tile and describes progress so far, like "12/400, ~3m20s left".
+    fn complete(&mut self) -> String {
+        self.complete_at(self.started.elapsed().as_secs_f64())
+    }
+
+    /// `complete` for a tile that finished `elapsed` seconds after the start.
+    fn complete_at(&mut self, elapsed: f64) -> String {
+        self.done += 1;
+        self.recent.push_back(elapsed);
+        if self.recent.len() > Self::WINDOW {
+            self.recent.pop_front();
+        }
+        let recent: Vec<f64> = self.recent.iter().copied().collect();
+        match estimate_remaining(&recent, self.total - self.done.min(self.total)) {
+            Some(seconds) => {
+                let seconds = seconds.round() as u64;
+                format!("{}/{}, ~{}m{:02}s left",self.done,self.total,seconds / 60,seconds % 60)
+            }
+            None => format!("{}/{}",self.done,self.total)
+        }
+    }
+}
+
+/// Picks the worker count: an explicit request wins, then detected parallelism, then a single thread.
+pub fn resolve_thread_count(requested: Option<usize>, detected: std::io::Result<NonZeroUsize>) -> usize {
+    if let Some(count) = requested {
+        return count.max(1);
+    }
+    match detected {
+        Ok(count) => count.get(),
+        Err(err) => {
+            println!("> could not detect parallelism ({}), using 1 thread",err);
+            1
+        }
+    }
+}
+
+/// Fails early, naming the problem, on encodings the tiff crate (or we) can't read,
+/// rather than panicking somewhere in the middle of decoding.
+fn check_encoding<R: Read + Seek>(tiff: &mut Decoder<R>) {
+    let compression = tiff.find_tag_unsigned::<u16>(Tag::Compression).unwrap().unwrap_or(1);
+    match CompressionMethod::from_u16_exhaustive(compression) {
+        CompressionMethod::None | CompressionMethod::LZW | CompressionMethod::Deflate | CompressionMethod::OldDeflate | CompressionMethod::PackBits => (),
+        other => panic!("elevation map uses unsupported compression {:?} ({}); re-save it uncompressed, LZW or Deflate",other,compression)
+    }
+
+    let predictor = tiff.find_tag_unsigned::<u16>(Tag::Predictor).unwrap().unwrap_or(1);
+    if Predictor::from_u16(predictor).is_none() {
+        panic!("elevation map uses unsupported predictor {}",predictor);
+    }
+
+    let sample_format = tiff.find_tag_unsigned::<u16>(Tag::SampleFormat).unwrap().unwrap_or(1);
+    let bits = tiff.find_tag_unsigned::<u16>(Tag::BitsPerSample).unwrap().unwrap_or(1);
+    if sample_format != 3 || bits != 32 {
+        panic!("elevation map must be 32-bit float samples (got sample format {}, {} bits)",sample_format,bits);
+    }
+}
+
+/// Reads the vertical units from the GeoTIFF key directory, if it names them.
+fn detect_vertical_units<R: Read + Seek>(tiff: &mut Decoder<R>) -> Option<ElevationUnits> {
+    const VERTICAL_UNITS_GEO_KEY: u16 = 4099;
+    let keys = tiff.get_tag_u16_vec(Tag::GeoKeyDirectoryTag).ok()?;
+    // header is 4 shorts, then 4 shorts per key: id, location, count, value
+    for key in keys.get(4..)?.chunks_exact(4) {
+        if key[0] == VERTICAL_UNITS_GEO_KEY && key[1] == 0 {
+            return match key[3] {
+                9001 => Some(ElevationUnits::M),
+                9002 | 9003 => Some(ElevationUnits::Ft),
+                _ => None
+            };
+        }
+    }
+    None
+}
+
+/// The units the map's samples are in: as requested, else as the GeoTIFF says, else meters.
+fn vertical_units<R: Read + Seek>(tiff: &mut Decoder<R>, requested: Option<ElevationUnits>) -> ElevationUnits {
+    match requested {
+        Some(units) => units,
+        None => detect_vertical_units(tiff).unwrap_or(ElevationUnits::M)
+    }
+}
+
+/// Reads the hemisphere from the GeoTIFF's projected CRS, if it's a WGS 84 UTM one (true for north).
+fn detect_hemisphere<R: Read + Seek>(tiff: &mut Decoder<R>) -> Option<bool> {
+    const PROJECTED_CS_TYPE_GEO_KEY: u16 = 3072;
+    let keys = tiff.get_tag_u16_vec(Tag::GeoKeyDirectoryTag).ok()?;
+    for key in keys.get(4..)?.chunks_exact(4) {
+        if key[0] == PROJECTED_CS_TYPE_GEO_KEY && key[1] == 0 {
+            return match key[3] {
+                32601..=32660 => Some(true),
+                32701..=32760 => Some(false),
+                _ => None
+            };
+        }
+    }
+    None
+}
+
+/// Whether the GeoTIFF key directory says the map is in lat/lon degrees rather than projected meters.
+fn is_geographic<R: Read + Seek>(tiff: &mut Decoder<R>) -> bool {
+    const MODEL_TYPE_GEO_KEY: u16 = 1024;
+    const MODEL_TYPE_GEOGRAPHIC: u16 = 2;
+    let Ok(keys) = tiff.get_tag_u16_vec(Tag::GeoKeyDirectoryTag) else {
+        return false;
+    };
+    let Some(keys) = keys.get(4..) else {
+        return false;
+    };
+    keys.chunks_exact(4).any(|key| key[0] == MODEL_TYPE_GEO_KEY && key[1] == 0 && key[3] == MODEL_TYPE_GEOGRAPHIC)
+}
+
+/// Resamples a lat/lon map onto a 1m grid in the given UTM zone, returning the samples, the
+/// UTM (easting, northing) of the top-left corner and the new dimensions. Samples outside the source are NaN.
+fn reproject_geographic(data: &[f32], dims: (u32,u32), origin: (f64,f64), scale: (f64,f64), zone: u8, max_pixels: u64) -> (Vec<f32>, (f64,f64), (u32,u32)) {
+    let (lon0,lat0) = origin;
+    let lon1 = lon0 + dims.0 as f64 * scale.0;
+    let lat1 = lat0 - dims.1 as f64 * scale.1;
+
+    let corners = [(lat0,lon0), (lat0,lon1), (lat1,lon0), (lat1,lon1)].map(|(lat,lon)| {
+        let (northing,easting,_) = utm::to_utm_wgs84(lat, lon, zone);
+        (easting, northing)
+    });
+    let west = corners.iter().map(|c| c.0).fold(1.0f64/0.0, f64::min).floor();
+    let east = corners.iter().map(|c| c.0).fold(-1.0f64/0.0, f64::max).ceil();
+    let south = corners.iter().map(|c| c.1).fold(1.0f64/0.0, f64::min).floor();
+    let north = corners.iter().map(|c| c.1).fold(-1.0f64/0.0, f64::max).ceil();
+    let out_dims = ((east - west) as u32, (north - south) as u32);
+    check_size(out_dims, max_pixels, " once projected");
+
+    let zone_letter = utm::lat_to_zone_letter((lat0 + lat1) / 2.0).expect("elevation map is outside UTM latitudes");
+    let to_lat_lon = |x: u32, y: u32| utm::wsg84_utm_to_lat_lon(west + x as f64, north - y as f64, zone, zone_letter).unwrap();
+
+    let source = |lat: f64, lon: f64| {
+        let col = (lon - lon0) / scale.0;
+        let row = (lat0 - lat) / scale.1;
+        if col < 0.0 || row < 0.0 || col > (dims.0 - 1) as f64 || row > (dims.1 - 1) as f64 {
+            return f32::NAN;
+        }
+        let (c0,r0) = (col.floor() as usize, row.floor() as usize);
+        let (c1,r1) = ((c0 + 1).min(dims.0 as usize - 1), (r0 + 1).min(dims.1 as usize - 1));
+        let (fx,fy) = ((col - c0 as f64) as f32, (row - r0 as f64) as f32);
+        let at = |c: usize, r: usize| data[r * dims.0 as usize + c];
+        let top = at(c0,r0) * (1.0 - fx) + at(c1,r0) * fx;
+        let bottom = at(c0,r1) * (1.0 - fx) + at(c1,r1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    };
+
+    // the projection is smooth, so it's only evaluated on a coarse lattice and interpolated in between
+    const STEP: u32 = 32;
+    let lattice_w = out_dims.0.div_ceil(STEP) + 1;
+    let lattice_h = out_dims.1.div_ceil(STEP) + 1;
+    let mut lattice = Vec::with_capacity((lattice_w * lattice_h) as usize);
+    for ly in 0..lattice_h {
+        for lx in 0..lattice_w {
+            lattice.push(to_lat_lon(lx * STEP, ly * STEP));
+        }
+    }
+
+    let mut out = Vec::with_capacity(out_dims.0 as usize * out_dims.1 as usize);
+    for y in 0..out_dims.1 {
+        let ly = (y / STEP) as usize;
+        let fy = (y % STEP) as f64 / STEP as f64;
+        for x in 0..out_dims.0 {
+            let lx = (x / STEP) as usize;
+            let fx = (x % STEP) as f64 / STEP as f64;
+            let at = |lx: usize, ly: usize| lattice[ly * lattice_w as usize + lx];
+            let lerp = |a: (f64,f64), b: (f64,f64), t: f64| (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+            let top = lerp(at(lx,ly), at(lx + 1,ly), fx);
+            let bottom = lerp(at(lx,ly + 1), at(lx + 1,ly + 1), fx);
+            let (lat,lon) = lerp(top, bottom, fy);
+            out.push(source(lat, lon));
+        }
+    }
+    println!("> reprojected {}x{} lat/lon map to {}x{} in UTM zone {}",dims.0,dims.1,out_dims.0,out_dims.1,zone);
+    (out, (west, north), out_dims)
+}
+
+/// Reads a lat/lon map and resamples it into the given UTM zone, returning the samples, the
+/// UTM origin, the projected dimensions and whether the map's middle is north of the equator.
+fn read_geographic<R: Read + Seek>(mut tiff: Decoder<R>, zone: u8, max_pixels: u64) -> (Vec<f32>, (f64,f64), (u32,u32), bool) {
+    let (origin, dims) = read_header(&mut tiff, max_pixels);
+    let scale = match tiff.get_tag_f64_vec(Tag::ModelPixelScaleTag) {
+        Ok(scale) if scale.len() >= 2 => (scale[0], scale[1]),
+        _ => panic!("lat/lon elevation map has no pixel scale")
+    };
+    let DecodingResult::F32(data) = tiff.read_image().expect("failed to read elevation map") else {
+        panic!("image in wrong format");
+    };
+    // same hemisphere reproject_geographic picks its zone letter from
+    let northern = origin.1 - dims.1 as f64 * scale.1 / 2.0 >= 0.0;
+    let (data, origin, dims) = reproject_geographic(&data, dims, origin, scale, zone, max_pixels);
+    (data, origin, dims, northern)
+}
+
+fn open_tiff(path: &str) -> Decoder<File> {
+    let file = File::open(path).expect("failed to open elevation map");
+    Decoder::new(file).expect("failed to decode elevation map")
+}
+
+/// Model (easting, northing) of the raster's top-left corner, from the first tie point.
+/// A tie point anchored somewhere other than raster (0,0) is walked back to the corner using the pixel scale.
+fn raster_origin(tie_point: &[f64], pixel_scale: Option<&[f64]>) -> (f64,f64) {
+    if tie_point.len() < 6 || !tie_point.len().is_multiple_of(6) {
+        panic!("elevation map has a malformed tie point ({} values)",tie_point.len());
+    }
+    if tie_point.len() > 6 {
+        println!("> elevation map has {} tie points, using the first",tie_point.len() / 6);
+    }
+    let (i,j) = (tie_point[0], tie_point[1]);
+    let (x,y) = (tie_point[3], tie_point[4]);
+    if i == 0.0 && j == 0.0 {
+        return (x, y);
+    }
+    let (scale_x,scale_y) = match pixel_scale {
+        Some(scale) if scale.len() >= 2 => (scale[0], scale[1]),
+        _ => panic!("elevation map's tie point is at raster ({},{}) rather than (0,0), and there's no pixel scale to place the corner",i,j)
+    };
+    (x - i * scale_x, y + j * scale_y)
+}
+
+/// The model origin and dimensions of an elevation map. Refuses maps of more than `max_pixels`
+/// samples before anything big is allocated.
+fn read_header<R: Read + Seek>(tiff: &mut Decoder<R>, max_pixels: u64) -> ((f64,f64), (u32,u32)) {
+    check_encoding(tiff);
+
+    let dims= tiff.dimensions().unwrap();
+    check_size(dims, max_pixels, "");
+
+    let tie_point = tiff.get_tag_f64_vec(Tag::ModelTiepointTag).unwrap();
+    let pixel_scale = tiff.get_tag_f64_vec(Tag::ModelPixelScaleTag).ok();
+    (raster_origin(&tie_point, pixel_scale.as_deref()), dims)
+}
+
+/// Refuses maps of more than `max_pixels` samples, before anything that big is allocated.
+/// `stage` says what the size is of, for the message (e.g. " once assembled").
+fn check_size(dims: (u32,u32), max_pixels: u64, stage: &str) {
+    let pixels = dims.0 as u64 * dims.1 as u64;
+    if pixels > max_pixels {
+        panic!("DEM too large: {} pixels ({}x{}){} exceeds cap {}; use --crop or raise --max-region-size",pixels,dims.0,dims.1,stage,max_pixels);
+    }
+}
+
+/// Refuses maps whose pixel scale isn't 1m, since samples are placed as one per meter.
+/// Maps without a pixel scale are taken to be 1m.
+fn check_meter_pixels<R: Read + Seek>(tiff: &mut Decoder<R>, path: &str) {
+    if let Ok(scale) = tiff.get_tag_f64_vec(Tag::ModelPixelScaleTag) {
+        if scale.len() >= 2 && ((scale[0] - 1.0).abs() > 1e-3 || (scale[1] - 1.0).abs() > 1e-3) {
+            panic!("{} has {}x{} pixels, only 1m maps can be assembled; resample it first",path,scale[0],scale[1]);
+        }
+    }
+}
+
+/// Whether the map's own chunks can be used as tiles, without reading the whole image first.
+fn is_natively_tiled<R: Read + Seek>(tiff: &Decoder<R>) -> bool {
+    tiff.get_chunk_type() == ChunkType::Tile && tiff.chunk_dimensions() == (512,512)
+}
+
+fn read_chunk<R: Read + Seek>(tiff: &mut Decoder<R>, index: u32) -> Tile {
+    let (width,height) = tiff.chunk_data_dimensions(index);
+    let data = tiff.read_chunk(index).expect("failed to read chunk");
+    let DecodingResult::F32(data) = data else {
+        panic!("chunk in wrong format");
+    };
+    println!("> read chunk {}",index);
+    Tile { data, width, height }
+}
+
+/// Reads every chunk of a tiled elevation map, returning the tiles, the model origin and the map's dimensions.
+fn read_tiles<R: Read + Seek>(mut tiff: Decoder<R>, max_pixels: u64) -> (Vec<Tile>, (f64,f64), (u32,u32)) {
+    let (origin, dims) = read_header(&mut tiff, max_pixels);
+
+    if !is_natively_tiled(&tiff) {
+        // stripped, or tiled with some other size: read it all and cut our own tiles
+        println!("> elevation map is not 512x512 tiled, reading whole image");
+        let data = tiff.read_image().expect("failed to read elevation map");
+        let DecodingResult::F32(data) = data else {
+            panic!("image in wrong format");
+        };
+        return (retile(&data, dims.0, dims.1), origin, dims);
+    }
+
+    let tile_count = dims.0.div_ceil(512) * dims.1.div_ceil(512);
+    let tiles = (0..tile_count).map(|i| read_chunk(&mut tiff, i)).collect();
+    (tiles, origin, dims)
+}
+
+/// Cuts a row-major image into 512x512 tiles, in the same order and with the same
+/// (smaller) edge tiles as a natively tiled map.
+fn retile(data: &[f32], width: u32, height: u32) -> Vec<Tile> {
+    let tiles_x = width.div_ceil(512);
+    let tiles_y = height.div_ceil(512);
+    let mut tiles = Vec::with_capacity((tiles_x * tiles_y) as usize);
+    for ty in 0..tiles_y {
+        for tx in 0..tiles_x {
+            let x0 = tx * 512;
+            let y0 = ty * 512;
+            let tile_width = 512.min(width - x0);
+            let tile_height = 512.min(height - y0);
+            let mut tile_data = Vec::with_capacity((tile_width * tile_height) as usize);
+            for y in y0..y0+tile_height {
+                let start = (y * width + x0) as usize;
+                tile_data.extend_from_slice(&data[start..start + tile_width as usize]);
+            }
+            tiles.push(Tile { data: tile_data, width: tile_width, height: tile_height });
+        }
+    }
+    tiles
+}
+
+/// Box blurs the whole map. Works across tile edges so neighboring tiles still agree on their shared samples.
+fn smooth_tiles(tiles: &[Tile], dims: (u32,u32), radius: usize) -> Vec<Tile> {
+    let tiles_x = dims.0.div_ceil(512) as usize;
+    let sample = |x: i64, y: i64| {
+        let x = x.clamp(0, dims.0 as i64 - 1) as usize;
+        let y = y.clamp(0, dims.1 as i64 - 1) as usize;
+        tiles[(y / 512) * tiles_x + x / 512].get(x % 512, y % 512)
+    };
+
+    tiles.iter().enumerate().map(|(index,tile)| {
+        smooth_tile(&sample, index % tiles_x * 512, index / tiles_x * 512, tile.width, tile.height, radius)
+    }).collect()
+}
+
+/// Box blurs one tile whose top-left sample is at (x0,y0), reading the map (and past its edges) through `sample`.
+fn smooth_tile(sample: &impl Fn(i64,i64)->f32, x0: usize, y0: usize, width: u32, height: u32, radius: usize) -> Tile {
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let (w, h) = (width as i64, height as i64);
+    let r = radius as i64;
+
+    // horizontal pass, including the extra rows the vertical pass needs
+    let padded_height = h + 2 * r;
+    let mut horizontal = Vec::with_capacity((w * padded_height) as usize);
+    for y in 0..padded_height {
+        for x in 0..w {
+            let mut sum = 0.0;
+            for dx in -r..=r {
+                sum += sample(x0 + x + dx, y0 + y - r);
+            }
+            horizontal.push(sum / (2 * r + 1) as f32);
+        }
+    }
+
+    let mut data = Vec::with_capacity((w * h) as usize);
+    for y in 0..h {
+        for x in 0..w {
+            let mut sum = 0.0;
+            for dy in 0..=2*r {
+                sum += horizontal[((y + dy) * w + x) as usize];
+            }
+            data.push(sum / (2 * r + 1) as f32);
+        }
+    }
+    Tile { data, width, height }
+}
+
+fn file_crc32(path: &str) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(&std::fs::read(path).unwrap());
+    crc.sum()
+}
+
+/// Whether a previously written output file is non-empty, complete gzip.
+fn is_valid_output(path: &str) -> bool {
+    let Ok(file) = std::fs::File::open(path) else {
+        return false;
+    };
+    let mut data = Vec::new();
+    match GzDecoder::new(file).read_to_end(&mut data) {
+        Ok(_) => !data.is_empty(),
+        Err(_) => false
+    }
+}
+
+/// Regions sharing an edge with this one on the east, south and south-east,
+/// so tiles along the border are stitched to the neighboring region's tiles.
+#[derive(Default)]
+pub struct AdjacentRegions<'a> {
+    pub east: Option<&'a Region>,
+    pub south: Option<&'a Region>,
+    pub south_east: Option<&'a Region>,
+}
+
+impl<'a> AdjacentRegions<'a> {
+    /// Picks out the regions whose corners line up exactly with this region's edges.
+    pub fn find(region: &Region, others: &[&'a Region]) -> Self {
+        let at = |easting: f64, northing: f64| {
+            others.iter().copied().find(|other| (other.coord.easting - easting).abs() < 0.5 && (other.coord.northing - northing).abs() < 0.5)
+        };
+        let east = region.coord.easting + region.width as f64;
+        let south = region.coord.northing - region.height as f64;
+        AdjacentRegions {
+            east: at(east, region.coord.northing),
+            south: at(region.coord.easting, south),
+            south_east: at(east, south)
+        }
+    }
+}
 
 impl Region {
-    pub fn new(name: String, zone_number: u8) -> Self {
-        let path = format!("input/{name}.tif");
-        let file = std::fs::File::open(path).expect("failed to open elevation map");
-        let mut tiff = tiff::decoder::Decoder::new(file).expect("failed to decode elevation map");
+    pub fn new(args: &CommandArgs) -> Self {
+        Self::named(args.name.clone(), args)
+    }
+
+    /// Loads `{name}.tif` from the input directory, which may be a region other than the one named on the command line.
+    pub fn named(name: String, args: &CommandArgs) -> Self {
+        let options = LoadOptions::from_args(args);
+        let dir = format!("{}/{name}",options.input_dir);
+        if !Path::new(&format!("{dir}.tif")).exists() && Path::new(&dir).is_dir() {
+            return Self::from_directory(name, &dir, &options);
+        }
+        let mut tiff = open_tiff(&format!("{dir}.tif"));
+        if is_geographic(&mut tiff) {
+            return Self::from_geographic(name, tiff, &options);
+        }
+        if args.thread_chunked && is_natively_tiled(&tiff) {
+            return Self::streamed(name, tiff, &options);
+        }
+        Self::from_decoder(name, tiff, &options)
+    }
+
+    /// Builds a region whose tiles are read on a background thread, so meshing can start on the
+    /// first tiles while later ones are still loading. Anything needing a tile waits for it.
+    fn streamed<R: Read + Seek + Send + 'static>(name: String, mut tiff: Decoder<R>, options: &LoadOptions) -> Self {
+        let units = vertical_units(&mut tiff, options.units);
+        let northern = detect_hemisphere(&mut tiff).unwrap_or(!options.southern);
+        let (origin, dims) = read_header(&mut tiff, options.max_region_size);
+        let tile_count = dims.0.div_ceil(512) * dims.1.div_ceil(512);
+        let tiles = Arc::new(TileStore::empty(tile_count as usize));
+
+        let store = tiles.clone();
+        let (min_elevation, max_elevation) = (options.min_elevation, options.max_elevation);
+        std::thread::spawn(move || {
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                for i in 0..tile_count {
+                    let mut tile = read_chunk(&mut tiff, i);
+                    tile.convert_to_meters(units);
+                    tile.clamp_elevation(min_elevation, max_elevation);
+                    store.put(i as usize, tile);
+                }
+            }));
+            if result.is_err() {
+                store.fail();
+            }
+        });
+
+        Self::with_tiles(name, origin, dims, northern, tiles, options)
+    }
+
+    /// Builds a region from an already opened elevation map, which may live in memory or anywhere else.
+    pub fn from_decoder<R: Read + Seek>(name: String, mut tiff: Decoder<R>, options: &LoadOptions) -> Self {
+        let units = vertical_units(&mut tiff, options.units);
+        let northern = detect_hemisphere(&mut tiff).unwrap_or(!options.southern);
+        let (tiles, origin, dims) = read_tiles(tiff, options.max_region_size);
+        Self::from_tiles(name, tiles, origin, dims, northern, units, options)
+    }
+
+    /// Builds a region from a map in lat/lon degrees, resampled into the options' zone.
+    pub fn from_geographic<R: Read + Seek>(name: String, mut tiff: Decoder<R>, options: &LoadOptions) -> Self {
+        let units = vertical_units(&mut tiff, options.units);
+        let (data, origin, dims, northern) = read_geographic(tiff, options.zone_number, options.max_region_size);
+        let tiles = retile(&data, dims.0, dims.1);
+        Self::from_tiles(name, tiles, origin, dims, northern, units, options)
+    }
+
+    /// Builds a region from a directory of elevation maps, placed by their tie points into one grid.
+    /// Samples no map covers are NaN.
+    pub fn from_directory(name: String, dir: &str, options: &LoadOptions) -> Self {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir).unwrap_or_else(|err| panic!("failed to read {}: {}",dir,err))
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| matches!(path.extension().and_then(|ext| ext.to_str()), Some("tif") | Some("tiff")))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty(), "no .tif files in {}",dir);
+
+        let mut units = options.units;
+        let mut northern = None;
+        let mut parts = Vec::with_capacity(paths.len());
+        for path in &paths {
+            let mut tiff = open_tiff(path.to_str().unwrap());
+            if units.is_none() {
+                units = detect_vertical_units(&mut tiff);
+            }
+            if northern.is_none() {
+                northern = detect_hemisphere(&mut tiff);
+            }
+            let (origin, dims) = read_header(&mut tiff, options.max_region_size);
+            check_meter_pixels(&mut tiff, &path.display().to_string());
+            let DecodingResult::F32(data) = tiff.read_image().expect("failed to read elevation map") else {
+                panic!("{} in wrong format",path.display());
+            };
+            println!("> read {}",path.display());
+            parts.push((origin, dims, data));
+        }
+
+        // samples are meters, so the grid is laid out directly in easting/northing
+        let west = parts.iter().map(|(origin,_,_)| origin.0).fold(1.0f64/0.0, f64::min);
+        let north = parts.iter().map(|(origin,_,_)| origin.1).fold(-1.0f64/0.0, f64::max);
+        let east = parts.iter().map(|(origin,dims,_)| origin.0 + dims.0 as f64).fold(-1.0f64/0.0, f64::max);
+        let south = parts.iter().map(|(origin,dims,_)| origin.1 - dims.1 as f64).fold(1.0f64/0.0, f64::min);
+        let dims = ((east - west).round() as u32, (north - south).round() as u32);
+        check_size(dims, options.max_region_size, " once assembled");
+
+        let mut data = vec![f32::NAN; dims.0 as usize * dims.1 as usize];
+        for (origin,part_dims,part) in &parts {
+            let x0 = (origin.0 - west).round() as usize;
+            let y0 = (north - origin.1).round() as usize;
+            for y in 0..part_dims.1 as usize {
+                let row = &part[y * part_dims.0 as usize..(y + 1) * part_dims.0 as usize];
+                let start = (y0 + y) * dims.0 as usize + x0;
+                data[start..start + row.len()].copy_from_slice(row);
+            }
+        }
+        println!("> assembled {} maps into {}x{}",parts.len(),dims.0,dims.1);
+
+        let tiles = retile(&data, dims.0, dims.1);
+        Self::from_tiles(name, tiles, (west, north), dims, northern.unwrap_or(!options.southern), units.unwrap_or(ElevationUnits::M), options)
+    }
+
+    /// Applies unit conversion, bathymetry, smoothing and clamping to freshly read tiles.
+    fn from_tiles(name: String, mut tiles: Vec<Tile>, origin: (f64,f64), dims: (u32,u32), northern: bool, units: ElevationUnits, options: &LoadOptions) -> Self {
+        // the units are the elevation map's, so convert before any bathymetry goes in
+        if let ElevationUnits::Ft = units {
+            for tile in tiles.iter_mut() {
+                tile.convert_to_meters(units);
+            }
+            println!("> converted elevation from feet");
+        }
+
+        if let Some(bathymetry) = &options.bathymetry {
+            // a lat/lon bathymetry map is projected the same way as a lat/lon elevation map, so they still line up
+            let mut bathymetry_tiff = open_tiff(&format!("{}/{bathymetry}.tif",options.input_dir));
+            let (bathymetry_tiles, bathymetry_origin, bathymetry_dims) = if is_geographic(&mut bathymetry_tiff) {
+                let (data, origin, dims, _) = read_geographic(bathymetry_tiff, options.zone_number, options.max_region_size);
+                (retile(&data, dims.0, dims.1), origin, dims)
+            } else {
+                read_tiles(bathymetry_tiff, options.max_region_size)
+            };
+            assert_eq!(origin,bathymetry_origin,"bathymetry map is not aligned with elevation map");
+            assert_eq!(dims,bathymetry_dims,"bathymetry map is not the same size as elevation map");
+            for (tile,bathymetry_tile) in tiles.iter_mut().zip(bathymetry_tiles.iter()) {
+                tile.merge_bathymetry(bathymetry_tile);
+            }
+            println!("> merged bathymetry");
+        }
 
-        let dims= tiff.dimensions().unwrap();
-        assert_eq!(dims,(REGION_SIZE,REGION_SIZE));
+        if let Some(radius) = options.smooth {
+            if radius > 0 {
+                tiles = smooth_tiles(&tiles, dims, radius);
+                println!("> smoothed elevation");
+            }
+        }
+
+        if options.min_elevation.is_some() || options.max_elevation.is_some() {
+            for tile in tiles.iter_mut() {
+                tile.clamp_elevation(options.min_elevation, options.max_elevation);
+            }
+            println!("> clamped elevation");
+        }
 
-        let chunk_dims = tiff.chunk_dimensions();
-        assert_eq!(chunk_dims,(512,512));
+        Self::with_tiles(name, origin, dims, northern, Arc::new(TileStore::filled(tiles)), options)
+    }
 
-        let tie_point = tiff.get_tag_f64_vec(Tag::ModelTiepointTag).unwrap();
+    fn with_tiles(name: String, origin: (f64,f64), dims: (u32,u32), northern: bool, tiles: Arc<TileStore>, options: &LoadOptions) -> Self {
         let coord = UTMCoord {
-            zone_number,
-            easting: tie_point[3],
-            northing: tie_point[4]
+            zone_number: options.zone_number,
+            northern,
+            easting: origin.0,
+            northing: origin.1
         };
-        
-        let mut region = Region{
+
+        Region{
             name,
             coord,
-            tiles: Vec::with_capacity(400)
-        };
+            input_dir: options.input_dir.clone(),
+            output_dir: options.output_dir.clone(),
+            width: dims.0,
+            height: dims.1,
+            tiles_x: dims.0.div_ceil(512) as usize,
+            tiles_y: dims.1.div_ceil(512) as usize,
+            tiles,
+            warned_out_of_bounds: AtomicBool::new(false),
+            datum: 0.0
+        }
+    }
 
-        for i in 0..400 {
-            let (width,height) = tiff.chunk_data_dimensions(i);
-            let data = tiff.read_chunk(i).expect("failed to read chunk");
-            let DecodingResult::F32(data) = data else {
-                panic!("chunk in wrong format");
-            };
-            println!("> read chunk {}",i);
-            region.tiles.push(Arc::new(Tile { data, width, height }));
+    pub fn out_dir(&self, args: &CommandArgs) -> String {
+        if args.preview {
+            format!("{}/{}/preview",self.output_dir,self.name)
+        } else {
+            format!("{}/{}",self.output_dir,self.name)
+        }
+    }
+
+    pub fn ensure_out_dir_exists(&self, args: &CommandArgs) {
+        ensure_dir_exists(&self.out_dir(args));
+    }
+
+    /// Whether a tile is part of this run. Preview runs only keep every Nth tile along each axis.
+    pub fn should_process_tile(&self, index: usize, args: &CommandArgs) -> bool {
+        let x = index % self.tiles_x;
+        let y = index / self.tiles_x;
+        if let Some(crop) = &args.crop {
+            if x < crop[0] || y < crop[1] || x > crop[2] || y > crop[3] {
+                return false;
+            }
+        }
+        if !args.preview {
+            return true;
+        }
+        let stride = args.preview_stride.max(1);
+        x.is_multiple_of(stride) && y.is_multiple_of(stride)
+    }
+
+    /// Local (x0, y0, x1, y1) rectangle being processed: the --crop window, or the whole map.
+    pub fn crop_rect(&self, args: &CommandArgs) -> (f32,f32,f32,f32) {
+        match &args.crop {
+            Some(crop) => {
+                assert!(crop[0] <= crop[2] && crop[1] <= crop[3] && crop[2] < self.tiles_x && crop[3] < self.tiles_y,
+                    "crop {:?} is not within the {}x{} tile grid",crop,self.tiles_x,self.tiles_y);
+                let x0 = (crop[0] * 512) as f32;
+                let y0 = (crop[1] * 512) as f32;
+                let x1 = (((crop[2] + 1) * 512) as f32).min(self.width as f32);
+                let y1 = (((crop[3] + 1) * 512) as f32).min(self.height as f32);
+                (x0, y0, x1, y1)
+            }
+            None => (0.0, 0.0, self.width as f32, self.height as f32)
+        }
+    }
+
+    /// Whether a local coordinate lies inside the --crop window (or the map, without one).
+    pub fn in_crop(&self, x: f32, y: f32, args: &CommandArgs) -> bool {
+        let (x0,y0,x1,y1) = self.crop_rect(args);
+        x >= x0 && y >= y0 && x < x1 && y < y1
+    }
+
+    fn tile_at(&self, x: usize, y: usize) -> Option<Arc<Tile>> {
+        if x < self.tiles_x && y < self.tiles_y {
+            Some(self.tiles.get(y * self.tiles_x + x))
+        } else {
+            None
         }
-        region
     }
 
-    pub fn ensure_out_dir_exists(&self) {
-        std::fs::create_dir(format!("output/{}",self.name)).ok();
+    /// Tile at a grid position that may be one past this region's last row/column,
+    /// in which case it comes from the adjacent region (if any).
+    fn neighbor_tile(&self, x: usize, y: usize, adjacent: &AdjacentRegions) -> Option<Arc<Tile>> {
+        match (x < self.tiles_x, y < self.tiles_y) {
+            (true, true) => self.tile_at(x, y),
+            (false, true) => adjacent.east.and_then(|east| east.tile_at(x - self.tiles_x, y)),
+            (true, false) => adjacent.south.and_then(|south| south.tile_at(x, y - self.tiles_y)),
+            (false, false) => adjacent.south_east.and_then(|corner| corner.tile_at(x - self.tiles_x, y - self.tiles_y))
+        }
+    }
+
+    fn neighbors(&self, index: usize, adjacent: &AdjacentRegions) -> TileNeighbors {
+        let x = index % self.tiles_x;
+        let y = index / self.tiles_x;
+        let next_x = self.neighbor_tile(x + 1, y, adjacent);
+        let next_y = self.neighbor_tile(x, y + 1, adjacent);
+        // the corner is only sampled when both edges are stitched
+        let corner = if next_x.is_some() && next_y.is_some() { self.neighbor_tile(x + 1, y + 1, adjacent) } else { None };
+        TileNeighbors{
+            next_x,
+            next_y,
+            corner,
+        }
     }
 
-    pub fn process_elevation(&self) {
-        let thread_count = available_parallelism().unwrap().get();
+    /// Indices of the output tiles cut from source tile `index` at `tile_size`, numbered row-major across
+    /// the whole map's grid of output tiles. At 512 this is just `index`.
+    fn output_indices(&self, index: usize, tile_size: usize) -> Vec<usize> {
+        let subdivisions = 512 / tile_size;
+        let (tx,ty) = (index % self.tiles_x, index / self.tiles_x);
+        let tile_width = 512.min(self.width as usize - tx * 512);
+        let tile_height = 512.min(self.height as usize - ty * 512);
+        let mut indices = Vec::new();
+        for sy in 0..subdivisions {
+            for sx in 0..subdivisions {
+                if sx * tile_size < tile_width && sy * tile_size < tile_height {
+                    indices.push((ty * subdivisions + sy) * self.tiles_x * subdivisions + tx * subdivisions + sx);
+                }
+            }
+        }
+        indices
+    }
 
-        let queue = self.tiles.iter().enumerate().map(|(index,tile)| {
-            let neighbors = TileNeighbors{
-                next_x: if tile.width == 512 { Some(self.tiles[index + 1].clone()) } else { None },
-                next_y: if tile.height == 512 { Some(self.tiles[index + 20].clone()) } else { None },
-                corner: if tile.width == 512 && tile.height == 512 { Some(self.tiles[index + 21].clone()) } else { None },
+    /// Cuts source tile `index` into output tiles of `tile_size`, each with the neighbors it needs stitching to:
+    /// the next block over within the tile, or the first block of the neighboring tile.
+    fn output_tiles(&self, index: usize, tile: Arc<Tile>, neighbors: TileNeighbors, tile_size: usize) -> Vec<(usize, Arc<Tile>, TileNeighbors)> {
+        if tile_size == 512 {
+            return vec![(index, tile, neighbors)];
+        }
+        let subdivisions = 512 / tile_size;
+        let block = |tile: &Option<Arc<Tile>>, bx: usize, by: usize| tile.as_ref().and_then(|tile| tile.block(bx, by, tile_size)).map(Arc::new);
+        let own = Some(tile.clone());
+        let mut outputs = Vec::new();
+        for out_index in self.output_indices(index, tile_size) {
+            let gx = out_index % (self.tiles_x * subdivisions);
+            let gy = out_index / (self.tiles_x * subdivisions);
+            let (sx,sy) = (gx % subdivisions, gy % subdivisions);
+            let Some(sub) = tile.block(sx, sy, tile_size) else {
+                continue;
             };
+            let inner_x = sx + 1 < subdivisions;
+            let inner_y = sy + 1 < subdivisions;
+            let next_x = if inner_x { block(&own, sx + 1, sy) } else { block(&neighbors.next_x, 0, sy) };
+            let next_y = if inner_y { block(&own, sx, sy + 1) } else { block(&neighbors.next_y, sx, 0) };
+            let corner = if next_x.is_some() && next_y.is_some() {
+                match (inner_x, inner_y) {
+                    (true, true) => block(&own, sx + 1, sy + 1),
+                    (false, true) => block(&neighbors.next_x, 0, sy + 1),
+                    (true, false) => block(&neighbors.next_y, sx + 1, 0),
+                    (false, false) => block(&neighbors.corner, 0, 0)
+                }
+            } else {
+                None
+            };
+            outputs.push((out_index, Arc::new(sub), TileNeighbors { next_x, next_y, corner }));
+        }
+        outputs
+    }
 
-            (index,tile.clone(),neighbors)
-        }).collect::<VecDeque<_>>();
+    /// A tile that panics is counted as failed and the rest carry on.
+    pub fn process_elevation(&self, args: &CommandArgs, adjacent: &AdjacentRegions) -> ElevationReport {
+        let (queue, checksums) = self.start_elevation(args);
+        self.mesh_tiles(args, adjacent, queue, checksums)
+    }
 
-        let queue = Arc::new(Mutex::new(queue));
+    /// Meshes elevation while the map is read and written alongside it. Map features sample elevation
+    /// from the same tiles, waiting for any that haven't loaded yet.
+    pub fn process_all(&self, args: &CommandArgs, adjacent: &AdjacentRegions, rules: &Rules) -> (ElevationReport, OsmStats) {
+        let (queue, checksums) = self.start_elevation(args);
+        std::thread::scope(|scope| {
+            let osm = scope.spawn(|| self.process_osm(args, rules));
+            let report = self.mesh_tiles(args, adjacent, queue, checksums);
+            (report, osm.join().unwrap())
+        })
+    }
+
+    /// Writes the manifest's elevation fields and picks the tiles to mesh, along with the checksums of any already done (--resume).
+    fn start_elevation(&self, args: &CommandArgs) -> (VecDeque<usize>, Vec<(String, u32)>) {
+        // tiles (and their neighbors) are only fetched by the workers, which may have to wait for them to load
+        let mut queue = (0..self.tiles.len()).filter(|index| self.should_process_tile(*index, args)).collect::<VecDeque<_>>();
 
-        let mut threads = Vec::new();
-
-        for _ in 0..thread_count {
-            let queue = queue.clone();
-            let name = self.name.to_owned();
-            let thread = std::thread::spawn(move || {
-                loop {
-                    let item = {
-                        let mut queue = queue.lock().unwrap();
-                        queue.pop_front()
-                    };
-                    let Some((index,tile, neighbors)) = item else {
-                        break;
-                    };
-                    let buffer = build_terrain_mesh(&tile.data, tile.width as usize, tile.height as usize, neighbors);
-                    //std::fs::write(format!("output/{}/tile{}",name,index), buffer.bytes).unwrap();
-                    buffer.save(&name, &format!("tile{}",index));
-                    println!("> elevation mesh {}",index);
+        let tile_size = args.output_tile_size as usize;
+        assert!(tile_size >= 8 && 512 % tile_size == 0, "--output-tile-size must divide 512");
+        let subdivisions = 512 / tile_size;
+
+        let manifest = Manifest {
+            region: self.name.clone(),
+            preview: args.preview,
+            tiles: queue.iter().flat_map(|index| self.output_indices(*index, tile_size)).collect(),
+            grid: (self.tiles_x * subdivisions, self.tiles_y * subdivisions),
+            tile_size,
+            endian: args.endian,
+            origin: self.output_origin(args),
+            tile_offset: self.output_offset(args),
+            datum: self.datum
+        };
+        let out_dir = self.out_dir(args);
+        manifest.save(&out_dir);
+
+        // crc32 of each compressed file, for the manifest
+        let mut checksums = Vec::new();
+
+        if args.resume {
+            let before = queue.len();
+            queue.retain(|index| {
+                let filenames: Vec<String> = self.output_indices(*index, tile_size).iter().map(|out| format!("tile{}.bin.gz",out)).collect();
+                if !filenames.iter().all(|filename| is_valid_output(&format!("{}/{}",out_dir,filename))) {
+                    return true;
                 }
+                for filename in filenames {
+                    let crc = file_crc32(&format!("{}/{}",out_dir,filename));
+                    checksums.push((filename, crc));
+                }
+                false
             });
-            threads.push(thread);
+            println!("> resuming, {} of {} tiles already done",before - queue.len(),before);
+        }
+        (queue, checksums)
+    }
+
+    fn mesh_tiles(&self, args: &CommandArgs, adjacent: &AdjacentRegions, queue: VecDeque<usize>, mut checksums: Vec<(String, u32)>) -> ElevationReport {
+        let thread_count = resolve_thread_count(args.threads, available_parallelism());
+        let tile_size = args.output_tile_size as usize;
+        let out_dir = self.out_dir(args);
+
+        let z_range = if args.global_z_range { Some(self.global_z_range()) } else { None };
+        let mesh_options = MeshOptions {
+            max_error: if args.preview { 4.0 } else { 1.0 },
+            z_range,
+            datum: self.datum,
+            morton_order: args.morton_order,
+            extend_edges: args.extend_edges,
+            decimate_boundary: args.decimate_boundary,
+            tile_size,
+            endian: args.endian
+        };
+        let raster_options = RasterOptions {
+            downsample: args.raster_downsample,
+            z_range,
+            datum: self.datum,
+            extend_edges: args.extend_edges,
+            endian: args.endian
+        };
+
+        // source tiles as 16 bit PNGs, all against the same range so they line up
+        let heightmaps = args.heightmap_png.then(|| {
+            let (min_z, range_z) = self.global_z_range();
+            Manifest::record_heightmaps(&out_dir, min_z - self.datum, min_z + range_z - self.datum);
+            (format!("{}/heightmaps",out_dir), min_z, range_z)
+        });
+        let heightmaps = &heightmaps;
+
+        let progress = Mutex::new(Progress::new(queue.iter().map(|index| self.output_indices(*index, tile_size).len()).sum()));
+        let progress = &progress;
+        let queue = Arc::new(Mutex::new(queue));
+
+        std::thread::scope(|scope| {
+            let mut threads = Vec::new();
+
+            for _ in 0..thread_count {
+                let queue = queue.clone();
+                let out_dir = out_dir.clone();
+                let format = args.elevation_format;
+                let validate_output = args.validate_output;
+                let compression = args.compression;
+                let endian = args.endian;
+                let thread = scope.spawn(move || {
+                    let mut written = 0;
+                    let mut failed = 0;
+                    let mut checksums = Vec::new();
+                    let mut mesh_stats = Vec::new();
+                    let mut seconds = Vec::new();
+                    loop {
+                        let item = {
+                            let mut queue = queue.lock().unwrap();
+                            queue.pop_front()
+                        };
+                        let Some(index) = item else {
+                            break;
+                        };
+                        let outputs = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                            if let Some((dir, min_z, range_z)) = heightmaps {
+                                save_heightmap(dir, index, &self.tiles.get(index), *min_z, *range_z);
+                            }
+                            self.output_tiles(index, self.tiles.get(index), self.neighbors(index, adjacent), tile_size)
+                        }));
+                        let Ok(outputs) = outputs else {
+                            failed += 1;
+                            let mut progress = progress.lock().unwrap();
+                            // none of this tile's outputs will come
+                            progress.done += self.output_indices(index, tile_size).len().saturating_sub(1);
+                            println!("> elevation mesh {} FAILED: panicked ({})",index,progress.complete());
+                            continue;
+                        };
+                        for (out_index, tile, neighbors) in outputs {
+                            let started = Instant::now();
+                            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                                let (buffer, stats) = match format {
+                                    ElevationFormat::Mesh => {
+                                        let (buffer, stats) = build_terrain_mesh(&tile.data, tile.width as usize, tile.height as usize, neighbors, &mesh_options);
+                                        (buffer, Some(stats))
+                                    }
+                                    ElevationFormat::Raster => (build_terrain_raster(&tile.data, tile.width as usize, tile.height as usize, neighbors, &raster_options), None)
+                                };
+                                (buffer.save(&out_dir, &format!("tile{}",out_index), compression), stats)
+                            }));
+                            let result = result.map_err(|_| "panicked".to_owned()).and_then(|saved| {
+                                if validate_output {
+                                    validate_file(&format!("{}/tile{}.bin.gz",out_dir,out_index), OutputKind::Terrain(format), endian)?;
+                                }
+                                Ok(saved)
+                            });
+                            match result {
+                                Ok((crc, stats)) => {
+                                    written += 1;
+                                    checksums.push((format!("tile{}.bin.gz",out_index), crc));
+                                    if let Some(stats) = stats {
+                                        mesh_stats.push((out_index, stats));
+                                    }
+                                    seconds.push((out_index, started.elapsed().as_secs_f64()));
+                                    println!("> elevation mesh {} ({})",out_index,progress.lock().unwrap().complete());
+                                }
+                                Err(err) => {
+                                    failed += 1;
+                                    println!("> elevation mesh {} FAILED: {} ({})",out_index,err,progress.lock().unwrap().complete());
+                                }
+                            }
+                        }
+                    }
+                    (written, failed, checksums, mesh_stats, seconds)
+                });
+                threads.push(thread);
+            }
+
+            let mut report = ElevationReport::default();
+            for thread in threads {
+                let (w,f,c,s,t) = thread.join().unwrap();
+                report.written += w;
+                report.failed += f;
+                checksums.extend(c);
+                report.mesh_stats.extend(s);
+                report.seconds.extend(t);
+            }
+            Manifest::record_checksums(&out_dir, &checksums);
+            report.mesh_stats.sort_by_key(|(index,_)| *index);
+            report.seconds.sort_by_key(|(index,_)| *index);
+            print_mesh_summary(&report.mesh_stats);
+            report
+        })
+    }
+
+    pub fn process_osm(&self, args: &CommandArgs, rules: &Rules) -> OsmStats {
+        self.process_osm_with(args, rules, |path| osm_fetch::fetch(self.get_bounds(args), &args.fetch_features, args.osm_date.as_deref(), path, args.overpass_status))
+    }
+
+    /// `process_osm`, downloading through `fetch` whenever the OSM data isn't on hand.
+    fn process_osm_with(&self, args: &CommandArgs, rules: &Rules, fetch: impl Fn(&Path) -> Result<(), String>) -> OsmStats {
+        let path = if args.no_cache_osm {
+            // fetch somewhere temporary, removed once it's been read
+            let path = std::env::temp_dir().join(format!("cartographer-{}-{}.osm",self.name,std::process::id()));
+            if let Err(err) = fetch(&path) {
+                panic!("{}",err);
+            }
+            path
+        } else if let Some(path) = &args.osm_input {
+            let path = PathBuf::from(path);
+            if std::fs::metadata(&path).is_err() {
+                panic!("--osm-input {} does not exist",path.display());
+            }
+            path
+        } else {
+            // use whichever format is on hand, fetching as xml if there's none. Those are current data,
+            // so a dated fetch never uses them
+            let existing = ["osm", "pbf", "o5m"].iter()
+                .map(|ext| PathBuf::from(format!("{}/{}.{}",self.input_dir,self.name,ext)))
+                .find(|path| path.exists() && args.osm_date.is_none());
+            // a cropped or narrowed fetch only covers part of the map, so it mustn't pass for the full one
+            let mut stem = match &args.crop {
+                Some(crop) => format!("{}/{}-crop-{}-{}-{}-{}",self.input_dir,self.name,crop[0],crop[1],crop[2],crop[3]),
+                None => format!("{}/{}",self.input_dir,self.name)
+            };
+            for feature in &args.fetch_features {
+                stem += "-";
+                stem += feature.name();
+            }
+            if let Some(date) = &args.osm_date {
+                stem += "-";
+                stem += &date.replace(':', "");
+            }
+            let fetch_path = PathBuf::from(format!("{}.osm",stem));
+            let path = match &existing {
+                Some(path) if !args.refresh_osm => path.clone(),
+                _ => fetch_path
+            };
+            if args.refresh_osm || std::fs::metadata(&path).is_err() {
+                if args.offline {
+                    panic!("--offline, but there is no OSM data for {} (looked for {}/{}.osm, .pbf and .o5m)",self.name,self.input_dir,self.name);
+                }
+                if let Err(err) = fetch(&path) {
+                    // a refresh that fails can still go ahead on what was fetched last time
+                    match existing {
+                        Some(stale) if !args.strict => {
+                            println!("> WARNING: {}",err);
+                            println!("> WARNING: falling back to stale OSM data in {}",stale.display());
+                            stale
+                        }
+                        _ => panic!("{}",err)
+                    }
+                } else {
+                    path
+                }
+            } else {
+                path
+            }
+        };
+
+        let (buffer, stats) = read_osm(&path, self, args, rules);
+        if args.no_cache_osm {
+            std::fs::remove_file(&path).ok();
         }
+        let crc = buffer.save(&self.out_dir(args), "map", args.compression);
+        Manifest::record_checksums(&self.out_dir(args), &[("map.bin.gz".to_owned(), crc)]);
+        if args.validate_output {
+            if let Err(err) = validate_file(&format!("{}/map.bin.gz",self.out_dir(args)), OutputKind::Map, args.endian) {
+                panic!("map.bin.gz invalid: {}",err);
+            }
+        }
+        Manifest::record_map(&self.out_dir(args), stats.feature_count(), args.endian, self.datum);
+        if args.verbose_osm_stats {
+            stats.print();
+        }
+        println!("> map done");
+        stats
+    }
 
-        for thread in threads {
-            thread.join().unwrap();
+    /// Min and range of elevation over every tile (above sea level, whatever the datum), for quantizing all tiles against the same scale.
+    pub fn global_z_range(&self) -> (f32,f32) {
+        let mut min_z = 1.0f32/0.0;
+        let mut max_z = -1.0f32/0.0;
+        for index in 0..self.tiles.len() {
+            for z in &self.tiles.get(index).data {
+                min_z = min_z.min(*z);
+                max_z = max_z.max(*z);
+            }
         }
+        (min_z, max_z - min_z)
     }
 
-    pub fn process_osm(&self) {
-        let path = format!("input/{}.osm",self.name);
-        if std::fs::metadata(&path).is_err() {
-            osm_fetch::fetch(self.get_bounds(), Path::new(&path));
+    /// Whether a local coordinate lies inside the elevation map.
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        x >= 0.0 && y >= 0.0 && x < self.width as f32 && y < self.height as f32
+    }
+
+    /// Elevation above the datum at a local coordinate, or None if it's outside the map.
+    pub fn try_get_elevation(&self, x: f32, y: f32) -> Option<f32> {
+        if !self.contains(x, y) {
+            return None;
         }
 
-        let buffer = read_osm(Path::new(&path), self);
-        buffer.save(&self.name, "map");
-        println!("> map done");
+        let chunk_size = 512.0;
+        let cx = (x / chunk_size).floor() as usize;
+        let cy = (y / chunk_size).floor() as usize;
+
+        let chunk_index = cy * self.tiles_x + cx;
+        let tile = self.tiles.get(chunk_index);
+
+        let xx = (x % chunk_size) as usize;
+        let yy = (y % chunk_size) as usize;
+
+        tile.get_checked(xx, yy).map(|z| z - self.datum)
     }
 
+    /// Elevation at a local coordinate. Queries outside the map (roads and buildings that cross
+    /// the edge) are clamped to the nearest edge sample; the first one is reported.
     pub fn get_elevation(&self, x: f32, y: f32) -> f32 {
-        let x = x.clamp(0.01, REGION_SIZE as f32 - 0.01);
-        let y = y.clamp(0.01, REGION_SIZE as f32 - 0.01);
+        if !self.contains(x, y) && !self.warned_out_of_bounds.swap(true, Ordering::Relaxed) {
+            println!("> elevation sampled outside the map at {} {}, clamping to the edge (only reported once)",x,y);
+        }
+        // max/min rather than clamp, so NaN ends up in bounds too
+        let x = x.max(0.0).min(self.width as f32 - 0.01);
+        let y = y.max(0.0).min(self.height as f32 - 0.01);
+        self.try_get_elevation(x, y).unwrap()
+    }
 
-        let chunk_size = 512.0;
-        let chunk_count = 20;
-        let cx = (x / chunk_size).floor() as i32;
-        let cy = (y / chunk_size).floor() as i32;
-        if cx < 0 || cy < 0 || cx >= chunk_count || cy >= chunk_count {
-            panic!("bad coord");
+    /// UTM (easting, northing) that output coordinates are relative to.
+    pub fn output_origin(&self, args: &CommandArgs) -> (f64,f64) {
+        args.origin.unwrap_or((self.coord.easting, self.coord.northing))
+    }
+
+    /// Shift from local map coordinates (y pointing south) to output coordinates.
+    pub fn output_offset(&self, args: &CommandArgs) -> (f32,f32) {
+        let (easting,northing) = self.output_origin(args);
+        ((self.coord.easting - easting) as f32, (northing - self.coord.northing) as f32)
+    }
+
+    /// Converts a UTM easting/northing in the region's zone to local map coordinates.
+    pub fn utm_to_local(&self, easting: f64, northing: f64) -> (f32,f32) {
+        ((easting - self.coord.easting) as f32, (self.coord.northing - northing) as f32)
+    }
+
+    /// Lat/lon bounds of the area being processed, honoring --crop.
+    pub fn get_bounds(&self, args: &CommandArgs) -> Bounds {
+        let (x0,y0,x1,y1) = self.crop_rect(args);
+        // all four corners, the UTM grid isn't aligned with lat/lon
+        let corners = [(x0,y0), (x1,y0), (x0,y1), (x1,y1)].map(|(x,y)| coords::local_to_latlon(self, x, y));
+        let bounds = Bounds::from_corners(&corners);
+        if bounds.crosses_date_line() {
+            println!("> region crosses the date line, splitting the query");
         }
+        bounds
+    }
+}
+
+/// Scratch directory in the system temp dir for a test region's inputs and outputs.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> String {
+    std::env::temp_dir().join(format!("cartographer-{}-{}",name,std::process::id())).to_str().unwrap().to_owned()
+}
+
+#[cfg(test)]
+impl Region {
+    /// A 2048x2048 region at `coord` that is `elevation` meters high everywhere, for tests.
+    pub fn flat(name: &str, coord: UTMCoord, elevation: f32) -> Self {
+        Self::from_fn(name, coord, (2048, 2048), |_,_| elevation)
+    }
+
+    /// A region of `dims` samples at `coord` whose elevation at each sample (x east, y south) is `f(x,y)`,
+    /// reading and writing under its `test_dir`, for tests.
+    pub fn from_fn(name: &str, coord: UTMCoord, dims: (u32,u32), f: impl Fn(u32,u32)->f32) -> Self {
+        let data: Vec<f32> = (0..dims.0 * dims.1).map(|i| f(i % dims.0, i / dims.0)).collect();
+        Region {
+            name: name.to_owned(),
+            coord,
+            input_dir: test_dir(name),
+            output_dir: test_dir(name),
+            width: dims.0,
+            height: dims.1,
+            tiles_x: dims.0.div_ceil(512) as usize,
+            tiles_y: dims.1.div_ceil(512) as usize,
+            tiles: Arc::new(TileStore::filled(retile(&data, dims.0, dims.1))),
+            warned_out_of_bounds: AtomicBool::new(false),
+            datum: 0.0
+        }
+    }
+}
 
-        let chunk_index = (cy * chunk_count + cx) as usize;
-        let tile = &self.tiles[chunk_index];
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
 
-        let xx = (x % chunk_size) as u32;
-        let yy = (y % chunk_size) as u32;
+    use clap::Parser;
+    use tiff::encoder::{colortype, TiffEncoder};
 
-        tile.data[(tile.width * yy + xx) as usize]
+    use crate::{validate::validate_bytes, Endian};
+
+    use super::*;
+
+    /// Loading options for zone 33 reading and writing under `test_dir(name)`.
+    fn test_options(name: &str) -> LoadOptions {
+        LoadOptions { zone_number: 33, southern: false, units: None, input_dir: test_dir(name), output_dir: test_dir(name), bathymetry: None, smooth: None, max_region_size: 400_000_000, min_elevation: None, max_elevation: None }
     }
 
-    pub fn get_bounds(&self) -> Bounds {
-        // todo southern hemisphere
-        let zone_letter = 'T';
+    /// Meshing at 1m error, quantized against `z_range` if given.
+    fn mesh_options(z_range: Option<(f32,f32)>) -> MeshOptions {
+        MeshOptions { max_error: 1.0, z_range, datum: 0.0, morton_order: false, extend_edges: false, decimate_boundary: false, tile_size: 512, endian: Endian::Le }
+    }
 
-        let (north,west) = utm::wsg84_utm_to_lat_lon(self.coord.easting, self.coord.northing, self.coord.zone_number, zone_letter).unwrap();
-        let (south,east) = utm::wsg84_utm_to_lat_lon(self.coord.easting + REGION_SIZE as f64, self.coord.northing - REGION_SIZE as f64, self.coord.zone_number, zone_letter).unwrap();
+    /// An in-memory GeoTIFF of float samples, in strips, with its top-left corner at `origin`.
+    fn geotiff_bytes(dims: (u32,u32), data: &[f32], origin: (f64,f64)) -> Vec<u8> {
+        geotiff_bytes_with_keys(dims, data, origin, &[])
+    }
+
+    /// The same as `geotiff_bytes`, with a GeoTIFF key directory if `keys` isn't empty.
+    fn geotiff_bytes_with_keys(dims: (u32,u32), data: &[f32], origin: (f64,f64), keys: &[u16]) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut out).unwrap();
+        let mut image = encoder.new_image::<colortype::Gray32Float>(dims.0, dims.1).unwrap();
+        image.encoder().write_tag(Tag::ModelTiepointTag, &[0.0, 0.0, 0.0, origin.0, origin.1, 0.0][..]).unwrap();
+        if !keys.is_empty() {
+            image.encoder().write_tag(Tag::GeoKeyDirectoryTag, keys).unwrap();
+        }
+        image.write_data(data).unwrap();
+        out.into_inner()
+    }
+
+    /// The same as `geotiff_bytes`, but cut into 512x512 tiles, padded at the edges.
+    /// `compression` is the TIFF compression tag: 1 for none, 8 for Deflate, anything else is written uncompressed.
+    fn tiled_geotiff_bytes(dims: (u32,u32), data: &[f32], origin: (f64,f64), compression: u16) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut out).unwrap();
+        let mut dir = encoder.new_directory().unwrap();
+        let (mut offsets, mut counts) = (Vec::new(), Vec::new());
+        for ty in 0..dims.1.div_ceil(512) {
+            for tx in 0..dims.0.div_ceil(512) {
+                let mut tile = vec![0.0f32; 512 * 512];
+                for y in 0..512.min(dims.1 - ty * 512) {
+                    for x in 0..512.min(dims.0 - tx * 512) {
+                        tile[(y * 512 + x) as usize] = data[((ty * 512 + y) * dims.0 + tx * 512 + x) as usize];
+                    }
+                }
+                let mut bytes: Vec<u8> = tile.iter().flat_map(|z| z.to_le_bytes()).collect();
+                if compression == 8 {
+                    let mut deflate = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                    std::io::Write::write_all(&mut deflate, &bytes).unwrap();
+                    bytes = deflate.finish().unwrap();
+                }
+                offsets.push(dir.write_data(&bytes[..]).unwrap() as u32);
+                counts.push(bytes.len() as u32);
+            }
+        }
+        dir.write_tag(Tag::ImageWidth, dims.0).unwrap();
+        dir.write_tag(Tag::ImageLength, dims.1).unwrap();
+        dir.write_tag(Tag::BitsPerSample, 32u16).unwrap();
+        dir.write_tag(Tag::Compression, compression).unwrap();
+        dir.write_tag(Tag::PhotometricInterpretation, 1u16).unwrap();
+        dir.write_tag(Tag::SamplesPerPixel, 1u16).unwrap();
+        dir.write_tag(Tag::SampleFormat, 3u16).unwrap();
+        dir.write_tag(Tag::TileWidth, 512u32).unwrap();
+        dir.write_tag(Tag::TileLength, 512u32).unwrap();
+        dir.write_tag(Tag::TileOffsets, &offsets[..]).unwrap();
+        dir.write_tag(Tag::TileByteCounts, &counts[..]).unwrap();
+        dir.write_tag(Tag::ModelTiepointTag, &[0.0, 0.0, 0.0, origin.0, origin.1, 0.0][..]).unwrap();
+        dir.finish().unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn thread_count_falls_back_to_one() {
+        let detected = || Ok(NonZeroUsize::new(8).unwrap());
+        assert_eq!(resolve_thread_count(None, detected()), 8);
+        assert_eq!(resolve_thread_count(Some(3), detected()), 3);
+        assert_eq!(resolve_thread_count(Some(0), detected()), 1);
+        // a sandbox or cgroup that hides the cpu count
+        let unknown = std::io::Error::new(std::io::ErrorKind::Unsupported, "no /proc");
+        assert_eq!(resolve_thread_count(None, Err(unknown)), 1);
+    }
+
+    #[test]
+    fn estimate_follows_the_recent_pace() {
+        assert_eq!(estimate_remaining(&[], 10), None);
+        assert_eq!(estimate_remaining(&[3.0], 10), None);
+        // a tile every 2s
+        assert_eq!(estimate_remaining(&[0.0, 2.0, 4.0, 6.0], 10), Some(20.0));
+        // uneven tiles average out: 3 gaps over 9s
+        assert_eq!(estimate_remaining(&[10.0, 11.0, 15.0, 19.0], 4), Some(12.0));
+        assert_eq!(estimate_remaining(&[0.0, 2.0], 0), Some(0.0));
+
+        let mut progress = Progress::new(130);
+        assert_eq!(progress.complete_at(30.0), "1/130");
+        assert_eq!(progress.complete_at(60.0), "2/130, ~64m00s left");
+        for i in 2..10 {
+            progress.complete_at((i + 1) as f64 * 30.0);
+        }
+        // then a tile a second: once the slow start is out of the window, only the new pace counts
+        let mut line = String::new();
+        for i in 1..=Progress::WINDOW {
+            line = progress.complete_at(300.0 + i as f64);
+        }
+        assert_eq!(line, format!("{}/130, ~1m40s left",10 + Progress::WINDOW));
+    }
 
-        Bounds { north, south, east, west }
+    #[test]
+    fn preview_keeps_every_nth_tile() {
+        // 5x3 tiles, the last column cut short
+        let region = Region::from_fn("preview", UTMCoord { zone_number: 33, northern: true, easting: 0.0, northing: 0.0 }, (4 * 512 + 100, 3 * 512), |_,_| 0.0);
+        let kept = |args: &[&str]| {
+            let args = CommandArgs::parse_from(["cartographer", "test", "33"].iter().chain(args));
+            (0..15).filter(|index| region.should_process_tile(*index, &args)).collect::<Vec<_>>()
+        };
+        assert_eq!(kept(&[]).len(), 15);
+        assert_eq!(kept(&["--preview", "--preview-stride", "2"]), [0, 2, 4, 10, 12, 14]);
+        assert_eq!(kept(&["--preview", "--preview-stride", "3"]), [0, 3]);
+        // a stride of 0 is taken as 1
+        assert_eq!(kept(&["--preview", "--preview-stride", "0"]).len(), 15);
+    }
+
+    #[test]
+    fn crop_keeps_a_window_of_tiles() {
+        // 4x3 tiles, each flat at col + 10 * row
+        let region = Region::from_fn("crop", UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, (4 * 512, 3 * 512), |x,y| (x / 512 + 10 * (y / 512)) as f32);
+        let args = CommandArgs::parse_from(["cartographer", "crop", "33", "--elevation-format", "raster", "--raster-downsample", "8",
+            "--crop", "1,1,2,2"]);
+        region.ensure_out_dir_exists(&args);
+        let report = region.process_elevation(&args, &AdjacentRegions::default());
+        assert_eq!((report.written, report.failed), (4, 0));
+
+        let out_dir = region.out_dir(&args);
+        let manifest: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(format!("{out_dir}/manifest.json")).unwrap()).unwrap();
+        assert_eq!(manifest["tiles"], serde_json::json!([5, 6, 9, 10]));
+        let origin = (manifest["origin"][0].as_f64().unwrap(), manifest["origin"][1].as_f64().unwrap());
+        let offset = (manifest["tile_offset"][0].as_f64().unwrap(), manifest["tile_offset"][1].as_f64().unwrap());
+        for index in [5, 6, 9, 10] {
+            let (col, row) = (index % 4, index / 4);
+            let world = (origin.0 + offset.0 + (col * 512) as f64, origin.1 - offset.1 - (row * 512) as f64);
+            assert_eq!(world, (500000.0 + (col * 512) as f64, 5000000.0 - (row * 512) as f64));
+
+            let mut bytes = Vec::new();
+            std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(File::open(format!("{out_dir}/tile{index}.bin.gz")).unwrap()), &mut bytes).unwrap();
+            let min_z = f32::from_le_bytes(bytes[0..4].try_into().unwrap());
+            assert_eq!(min_z, (col + 10 * row) as f32, "tile{index} holds the wrong samples");
+            // the edge of the window still picks up the row and column past it
+            assert_eq!(u16::from_le_bytes([bytes[8], bytes[9]]), 65);
+        }
+        let (x0,y0,x1,y1) = region.crop_rect(&args);
+        assert_eq!((x0,y0,x1,y1), (512.0, 512.0, 1536.0, 1536.0));
+        assert!(region.in_crop(600.0, 1500.0, &args) && !region.in_crop(100.0, 600.0, &args));
+        std::fs::remove_dir_all(&region.output_dir).unwrap();
+    }
+
+    /// Bit-at-a-time CRC-32 (the gzip/zlib polynomial), to check the manifest against.
+    fn slow_crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for byte in bytes {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+            }
+        }
+        !crc
+    }
+
+    #[test]
+    fn manifest_checksums_match_the_files() {
+        assert_eq!(slow_crc32(b"123456789"), 0xCBF43926);
+        let region = Region::from_fn("checksums", UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, (1024, 512), |x,y| (x + y) as f32 * 0.1);
+        let args = CommandArgs::parse_from(["cartographer", "checksums", "33", "--elevation-format", "raster", "--raster-downsample", "8"]);
+        region.ensure_out_dir_exists(&args);
+        let report = region.process_elevation(&args, &AdjacentRegions::default());
+        assert_eq!((report.written, report.failed), (2, 0));
+
+        let out_dir = region.out_dir(&args);
+        let manifest: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(format!("{out_dir}/manifest.json")).unwrap()).unwrap();
+        let checksums = manifest["checksums"].as_object().unwrap();
+        assert_eq!(checksums.len(), 2);
+        for (filename,crc) in checksums {
+            let bytes = std::fs::read(format!("{out_dir}/{filename}")).unwrap();
+            assert_eq!(*crc, serde_json::json!(format!("{:08x}",slow_crc32(&bytes))), "{filename}");
+        }
+
+        // and a resumed run records the files it skipped
+        let resumed = CommandArgs::parse_from(["cartographer", "checksums", "33", "--elevation-format", "raster", "--raster-downsample", "8", "--resume"]);
+        std::fs::remove_file(format!("{out_dir}/manifest.json")).unwrap();
+        let report = region.process_elevation(&resumed, &AdjacentRegions::default());
+        assert_eq!((report.written, report.failed), (0, 0));
+        let manifest: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(format!("{out_dir}/manifest.json")).unwrap()).unwrap();
+        assert_eq!(manifest["checksums"].as_object().unwrap(), checksums);
+        std::fs::remove_dir_all(&region.output_dir).unwrap();
+    }
+
+    #[test]
+    fn output_tile_size_cuts_each_tile_into_four() {
+        let region = Region::from_fn("output-tile-size", UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, (512, 512), |x,y| (x + 2 * y) as f32);
+        let args = CommandArgs::parse_from(["cartographer", "output-tile-size", "33", "--elevation-format", "raster", "--raster-downsample", "1", "--output-tile-size", "256"]);
+        region.ensure_out_dir_exists(&args);
+        let report = region.process_elevation(&args, &AdjacentRegions::default());
+        assert_eq!((report.written, report.failed), (4, 0));
+
+        let out_dir = region.out_dir(&args);
+        let manifest: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(format!("{out_dir}/manifest.json")).unwrap()).unwrap();
+        assert_eq!((manifest["grid"].clone(), manifest["tile_size"].clone()), (serde_json::json!([2, 2]), serde_json::json!(256)));
+        assert_eq!(manifest["tiles"], serde_json::json!([0, 1, 2, 3]));
+        for (index, corner, dims) in [(0, (0, 0), (257, 257)), (1, (256, 0), (256, 257)), (2, (0, 256), (257, 256)), (3, (256, 256), (256, 256))] {
+            let mut raster = Vec::new();
+            GzDecoder::new(File::open(format!("{out_dir}/tile{index}.bin.gz")).unwrap()).read_to_end(&mut raster).unwrap();
+            let float = |at: usize| f32::from_le_bytes(raster[at..at + 4].try_into().unwrap());
+            let short = |at: usize| u16::from_le_bytes([raster[at], raster[at + 1]]);
+            // stitched to the next block over inside the tile, but not past the map's edge
+            assert_eq!((short(8), short(10)), dims, "tile{index}");
+            let (min_z, range_z) = (float(0), float(4));
+            assert_eq!(min_z, (corner.0 + 2 * corner.1) as f32, "tile{index}");
+            let last = min_z + short(12 + (dims.0 as usize * dims.1 as usize - 1) * 2) as f32 / 65535.0 * range_z;
+            assert_eq!(last, (corner.0 + dims.0 as u32 - 1 + 2 * (corner.1 + dims.1 as u32 - 1)) as f32, "tile{index}");
+        }
+        std::fs::remove_dir_all(&region.output_dir).unwrap();
+    }
+
+    #[test]
+    fn failed_tile_is_counted_and_the_rest_are_written() {
+        let region = Region::flat("partial", UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, 50.0);
+        let args = CommandArgs::parse_from(["cartographer", "partial", "33", "--elevation-format", "raster", "--raster-downsample", "8",
+            "--preview", "--preview-stride", "2", "--threads", "2"]);
+        // a directory where tile 2 goes, so writing it fails
+        let out_dir = region.out_dir(&args);
+        std::fs::create_dir_all(format!("{out_dir}/tile2.bin.gz")).unwrap();
+
+        let report = region.process_elevation(&args, &AdjacentRegions::default());
+        assert_eq!((report.written, report.failed), (3, 1));
+        for index in [0, 8, 10] {
+            assert!(Path::new(&format!("{out_dir}/tile{index}.bin.gz")).is_file(), "tile{index} not written");
+        }
+        std::fs::remove_dir_all(&region.output_dir).unwrap();
+    }
+
+    #[test]
+    fn resume_skips_tiles_already_written() {
+        let region = Region::flat("resume", UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, 50.0);
+        let args = CommandArgs::parse_from(["cartographer", "resume", "33", "--elevation-format", "raster", "--raster-downsample", "8",
+            "--preview", "--preview-stride", "2", "--resume"]);
+        region.ensure_out_dir_exists(&args);
+        let out_dir = region.out_dir(&args);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, b"done").unwrap();
+        let gzip = encoder.finish().unwrap();
+
+        // half of the four tiles done, and one more cut short
+        std::fs::write(format!("{out_dir}/tile0.bin.gz"), &gzip).unwrap();
+        std::fs::write(format!("{out_dir}/tile2.bin.gz"), &gzip).unwrap();
+        std::fs::write(format!("{out_dir}/tile8.bin.gz"), &gzip[..gzip.len() / 2]).unwrap();
+
+        let report = region.process_elevation(&args, &AdjacentRegions::default());
+        assert_eq!((report.written, report.failed), (2, 0));
+        assert!(is_valid_output(&format!("{out_dir}/tile8.bin.gz")));
+        assert_eq!(std::fs::read(format!("{out_dir}/tile0.bin.gz")).unwrap(), gzip);
+        assert_eq!(std::fs::read(format!("{out_dir}/tile2.bin.gz")).unwrap(), gzip);
+        std::fs::remove_dir_all(&region.output_dir).unwrap();
+    }
+
+    #[test]
+    fn refresh_and_no_cache_go_through_the_fetcher() {
+        let harness = Harness::new("osm-cache");
+        let cached = PathBuf::from(format!("{}/{}.osm",harness.region.input_dir,harness.region.name));
+        let xml = std::fs::read_to_string(&cached).unwrap();
+        let fetched = std::cell::RefCell::new(Vec::<PathBuf>::new());
+        let fetch = |path: &Path| {
+            fetched.borrow_mut().push(path.to_owned());
+            std::fs::write(path, &xml).unwrap();
+            Ok(())
+        };
+
+        // the cached file is used as it is
+        harness.region.process_osm_with(&harness.args(&["--map"]), &Rules::default(), fetch);
+        assert!(fetched.borrow().is_empty());
+
+        // a refresh fetches over it
+        harness.region.process_osm_with(&harness.args(&["--map", "--refresh-osm"]), &Rules::default(), fetch);
+        assert_eq!(fetched.borrow().as_slice(), std::slice::from_ref(&cached));
+
+        // without the cache, fetches go somewhere else that's cleaned up after
+        std::fs::remove_file(&cached).unwrap();
+        harness.region.process_osm_with(&harness.args(&["--map", "--no-cache-osm"]), &Rules::default(), fetch);
+        let temp = fetched.borrow()[1].clone();
+        assert_ne!(temp, cached);
+        assert!(!temp.exists() && !cached.exists());
+        assert_eq!(harness.read(&harness.args(&["--map"]), "map")[..4], *crate::MAP_MAGIC);
+    }
+
+    #[test]
+    fn failed_refresh_falls_back_to_the_stale_file() {
+        let harness = Harness::new("stale");
+        let cached = format!("{}/{}.osm",harness.region.input_dir,harness.region.name);
+        let stale = std::fs::read(&cached).unwrap();
+        let down = |_: &Path| Err("overpass request failed: 504 Gateway Timeout".to_owned());
+
+        // the run goes ahead on the cached file, which is left alone
+        let args = harness.args(&["--map", "--refresh-osm"]);
+        let stats = harness.region.process_osm_with(&args, &Rules::default(), down);
+        assert_eq!((stats.buildings.values().sum::<usize>(), stats.roads.values().sum::<usize>()), (1, 1));
+        assert_eq!(std::fs::read(&cached).unwrap(), stale);
+        assert_eq!(u32::from_le_bytes(harness.read(&args, "map")[23..27].try_into().unwrap()), 2);
+
+        // unless that's not wanted
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            harness.region.process_osm_with(&harness.args(&["--map", "--refresh-osm", "--strict"]), &Rules::default(), down)
+        }));
+        assert_eq!(*result.unwrap_err().downcast::<String>().unwrap(), "overpass request failed: 504 Gateway Timeout");
+
+        // and with nothing cached there's nothing to fall back to
+        std::fs::remove_file(&cached).unwrap();
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            harness.region.process_osm_with(&harness.args(&["--map"]), &Rules::default(), down)
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn offline_without_osm_data_fails_without_fetching() {
+        let harness = Harness::new("offline");
+        std::fs::remove_file(format!("{}/{}.osm",harness.region.input_dir,harness.region.name)).unwrap();
+        let fetched = std::cell::Cell::new(false);
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            harness.region.process_osm_with(&harness.args(&["--map", "--offline"]), &Rules::default(), |_| { fetched.set(true); Ok(()) })
+        }));
+        let message = result.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.starts_with("--offline, but there is no OSM data for harness-offline"), "{message}");
+        assert!(!fetched.get());
+        assert!(CommandArgs::try_parse_from(["cartographer", "test", "33", "--offline", "--refresh-osm"]).is_err());
+    }
+
+    #[test]
+    fn osm_input_reads_any_file() {
+        let harness = Harness::new("osm-input");
+        let cached = format!("{}/{}.osm",harness.region.input_dir,harness.region.name);
+        let custom = std::env::temp_dir().join(format!("cartographer-extract-{}.osm",std::process::id()));
+        std::fs::rename(&cached, &custom).unwrap();
+
+        let args = harness.args(&["--map", "--osm-input", custom.to_str().unwrap()]);
+        let stats = harness.region.process_osm_with(&args, &Rules::default(), |_| panic!("nothing should be fetched"));
+        std::fs::remove_file(&custom).unwrap();
+        assert_eq!(stats.ways_skipped(), 0);
+        // the building and the road from the extract, written under the region's name
+        let map = harness.read(&args, "map");
+        assert_eq!(u32::from_le_bytes(map[23..27].try_into().unwrap()), 2);
+        assert!(!Path::new(&cached).exists());
+    }
+
+    #[test]
+    fn untagged_nodes_give_a_valid_empty_map() {
+        let harness = Harness::new("empty-map");
+        let args = harness.args(&["--map", "--validate-output"]);
+        let manifest = || {
+            let text = std::fs::read_to_string(format!("{}/manifest.json",harness.region.out_dir(&args))).unwrap();
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()["map"].clone()
+        };
+        harness.region.process_osm_with(&args, &Rules::default(), |_| panic!("nothing should be fetched"));
+        assert_eq!(manifest(), serde_json::json!({ "features": 2, "empty": false }));
+
+        std::fs::write(format!("{}/{}.osm",harness.region.input_dir,harness.region.name), "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<osm version=\"0.6\">\n\
+            <node id=\"1\" lat=\"45.1\" lon=\"15.2\"/>\n<node id=\"2\" lat=\"45.1\" lon=\"15.3\"/>\n</osm>\n").unwrap();
+        assert_eq!(harness.region.process_osm_with(&args, &Rules::default(), |_| panic!("nothing should be fetched")).ways_skipped(), 0);
+        let map = harness.read(&args, "map");
+        assert_eq!(validate_bytes(&map, OutputKind::Map, Endian::Le), Ok(()));
+        // just the header, counting no records
+        assert_eq!(map.len(), 31);
+        assert_eq!(u32::from_le_bytes(map[23..27].try_into().unwrap()), 0);
+        assert_eq!(manifest(), serde_json::json!({ "features": 0, "empty": true }));
+    }
+
+    #[test]
+    fn elevation_in_bounds_at_the_edge_and_far_out() {
+        let coord = UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 };
+        let region = Region::from_fn("bounds", coord, (600, 40), |x,y| x as f32 + y as f32 * 1000.0);
+
+        // inside, including the second tile along x
+        assert_eq!(region.try_get_elevation(10.5, 3.2), Some(3010.0));
+        assert_eq!(region.try_get_elevation(550.0, 20.0), Some(20550.0));
+        assert_eq!(region.get_elevation(550.0, 20.0), 20550.0);
+        assert!(!region.warned_out_of_bounds.load(Ordering::Relaxed));
+
+        // the last samples are inside, the far edges aren't
+        assert_eq!(region.try_get_elevation(599.9, 39.9), Some(39599.0));
+        assert_eq!(region.try_get_elevation(600.0, 0.0), None);
+        assert_eq!(region.try_get_elevation(0.0, 40.0), None);
+        assert_eq!(region.get_elevation(600.0, 0.0), 599.0);
+
+        // far out is clamped to the nearest edge sample, without panicking
+        assert_eq!(region.try_get_elevation(-1e6, 1e6), None);
+        assert_eq!(region.get_elevation(-1e6, 1e6), 39000.0);
+        assert_eq!(region.get_elevation(1e6, -5.0), 599.0);
+        assert_eq!(region.get_elevation(f32::NAN, 5.0), 5000.0);
+        assert!(region.warned_out_of_bounds.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn region_from_an_in_memory_map() {
+        let bytes = geotiff_bytes((3,2), &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], (400000.0, 6000000.0));
+        let options = test_options("memory");
+        let region = Region::from_decoder("memory".into(), Decoder::new(Cursor::new(bytes)).unwrap(), &options);
+        assert_eq!((region.width, region.height), (3,2));
+        assert_eq!((region.coord.zone_number, region.coord.easting, region.coord.northing), (33, 400000.0, 6000000.0));
+        assert_eq!(region.get_elevation(1.0, 1.0), 5.0);
+        assert_eq!(region.get_elevation(2.5, 0.5), 3.0);
+    }
+
+    /// A small synthetic region to run the whole pipeline over: a 48x40 hill read from an in-memory
+    /// map, with a building and a road on it in an OSM file. Input and output both live in a temp
+    /// directory, which is removed when the harness is dropped.
+    struct Harness {
+        region: Region,
+    }
+
+    impl Harness {
+        fn new(test: &str) -> Self {
+            let name = format!("harness-{test}");
+            let dims = (48, 40);
+            let data: Vec<f32> = (0..dims.0 * dims.1).map(|i| {
+                let (x,y) = ((i % dims.0) as f32 - 24.0, (i / dims.0) as f32 - 20.0);
+                100.0 + 10.0 * (-(x * x + y * y) / 100.0).exp()
+            }).collect();
+            let options = test_options(&name);
+            let bytes = geotiff_bytes(dims, &data, (500000.0, 5000000.0));
+            let region = Region::from_decoder(name, Decoder::new(Cursor::new(bytes)).unwrap(), &options);
+
+            let points = [(10.0, 10.0), (18.0, 10.0), (18.0, 18.0), (10.0, 18.0), (5.0, 30.0), (20.0, 32.0), (40.0, 30.0)];
+            let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<osm version=\"0.6\">\n");
+            for (i,(x,y)) in points.iter().enumerate() {
+                let (lat,lon) = coords::local_to_latlon(&region, *x, *y);
+                xml += &format!("  <node id=\"{}\" lat=\"{}\" lon=\"{}\"/>\n",i + 1,lat,lon);
+            }
+            xml += "  <way id=\"100\"><nd ref=\"1\"/><nd ref=\"2\"/><nd ref=\"3\"/><nd ref=\"4\"/><nd ref=\"1\"/><tag k=\"building\" v=\"yes\"/></way>\n";
+            xml += "  <way id=\"101\"><nd ref=\"5\"/><nd ref=\"6\"/><nd ref=\"7\"/><tag k=\"highway\" v=\"residential\"/></way>\n";
+            xml += "</osm>\n";
+            ensure_dir_exists(&region.input_dir);
+            std::fs::write(format!("{}/{}.osm",region.input_dir,region.name), xml).unwrap();
+
+            Harness { region }
+        }
+
+        fn args(&self, extra: &[&str]) -> CommandArgs {
+            let base = ["cartographer", &self.region.name, "33"];
+            CommandArgs::parse_from(base.iter().chain(extra))
+        }
+
+        /// Unzipped contents of one of the outputs, e.g. "tile0" or "map".
+        fn read(&self, args: &CommandArgs, output: &str) -> Vec<u8> {
+            let file = File::open(format!("{}/{}.bin.gz",self.region.out_dir(args),output)).unwrap();
+            let mut data = Vec::new();
+            GzDecoder::new(file).read_to_end(&mut data).unwrap();
+            data
+        }
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.region.output_dir);
+        }
+    }
+
+    #[test]
+    fn pipeline_outputs_parse() {
+        let harness = Harness::new("pipeline");
+        let short = |data: &[u8], at: usize| u16::from_le_bytes([data[at], data[at + 1]]) as usize;
+
+        let args = harness.args(&["--elevation-format", "mesh", "--validate-output"]);
+        harness.region.ensure_out_dir_exists(&args);
+        let report = harness.region.process_elevation(&args, &AdjacentRegions::default());
+        assert_eq!((report.written, report.failed), (1, 0));
+        let mesh = harness.read(&args, "tile0");
+        let vertices = short(&mesh, 12);
+        assert_eq!(mesh.len(), 14 + vertices * 9 + 2 + short(&mesh, 14 + vertices * 9) * 6);
+        assert_eq!(validate_bytes(&mesh, OutputKind::Terrain(ElevationFormat::Mesh), Endian::Le), Ok(()));
+
+        let args = harness.args(&["--elevation-format", "raster", "--validate-output"]);
+        let report = harness.region.process_elevation(&args, &AdjacentRegions::default());
+        assert_eq!((report.written, report.failed), (1, 0));
+        let raster = harness.read(&args, "tile0");
+        assert_eq!(raster.len(), 12 + short(&raster, 8) * short(&raster, 10) * 2);
+        assert_eq!(validate_bytes(&raster, OutputKind::Terrain(ElevationFormat::Raster), Endian::Le), Ok(()));
+
+        let args = harness.args(&["--map", "--validate-output"]);
+        assert_eq!(harness.region.process_osm(&args, &Rules::default()).ways_skipped(), 0);
+        let map = harness.read(&args, "map");
+        assert_eq!(&map[..4], crate::MAP_MAGIC);
+        assert_eq!(map[5..7], crate::MAP_VERSION.to_le_bytes());
+        assert_eq!(validate_bytes(&map, OutputKind::Map, Endian::Le), Ok(()));
+    }
+
+    #[test]
+    fn process_all_matches_one_pass_after_the_other() {
+        let harness = Harness::new("together");
+        // a second region to the east, so the tile is stitched across the border
+        let east = Harness::new("together-east");
+        let adjacent = AdjacentRegions { east: Some(&east.region), ..Default::default() };
+        let args = harness.args(&["--elevation", "--map"]);
+        harness.region.ensure_out_dir_exists(&args);
+
+        harness.region.process_elevation(&args, &adjacent);
+        harness.region.process_osm(&args, &Rules::default());
+        let (tile, map) = (harness.read(&args, "tile0"), harness.read(&args, "map"));
+        std::fs::remove_dir_all(harness.region.out_dir(&args)).unwrap();
+
+        harness.region.ensure_out_dir_exists(&args);
+        let (report, stats) = harness.region.process_all(&args, &adjacent, &Rules::default());
+        assert_eq!((report.written, report.failed), (1, 0));
+        assert_eq!((stats.buildings.values().sum::<usize>(), stats.roads.values().sum::<usize>()), (1, 1));
+        assert_eq!(harness.read(&args, "tile0"), tile);
+        assert_eq!(harness.read(&args, "map"), map);
+
+        // neither pass lost the other's part of the manifest
+        let manifest = std::fs::read_to_string(format!("{}/manifest.json",harness.region.out_dir(&args))).unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["tiles"], serde_json::json!([0]));
+        assert_eq!(manifest["map"]["features"], serde_json::json!(2));
+        assert!(manifest["checksums"]["tile0.bin.gz"].is_string());
+        assert!(manifest["checksums"]["map.bin.gz"].is_string());
+    }
+
+    /// Width, height and samples of a 16-bit grayscale PNG as `encode_png16` writes it (one IDAT, no filtering).
+    fn decode_png16(png: &[u8]) -> (usize, usize, Vec<u16>) {
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        let (mut width, mut height, mut data) = (0, 0, Vec::new());
+        let mut at = 8;
+        while at < png.len() {
+            let len = u32::from_be_bytes(png[at..at + 4].try_into().unwrap()) as usize;
+            let body = &png[at + 8..at + 8 + len];
+            match &png[at + 4..at + 8] {
+                b"IHDR" => {
+                    width = u32::from_be_bytes(body[0..4].try_into().unwrap()) as usize;
+                    height = u32::from_be_bytes(body[4..8].try_into().unwrap()) as usize;
+                    assert_eq!(&body[8..10], &[16, 0]);
+                }
+                b"IDAT" => { flate2::read::ZlibDecoder::new(body).read_to_end(&mut data).unwrap(); }
+                _ => {}
+            }
+            at += 12 + len;
+        }
+        let samples = data.chunks(1 + width * 2).flat_map(|line| {
+            assert_eq!(line[0], 0);
+            line[1..].chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect::<Vec<_>>()
+        }).collect();
+        (width, height, samples)
+    }
+
+    #[test]
+    fn heightmap_png_reads_back_as_the_source_tile() {
+        let harness = Harness::new("heightmap");
+        let args = harness.args(&["--elevation", "--heightmap-png"]);
+        harness.region.ensure_out_dir_exists(&args);
+        harness.region.process_elevation(&args, &AdjacentRegions::default());
+
+        let out_dir = harness.region.out_dir(&args);
+        let manifest: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(format!("{out_dir}/manifest.json")).unwrap()).unwrap();
+        let (min, max) = (manifest["heightmaps"]["min"].as_f64().unwrap() as f32, manifest["heightmaps"]["max"].as_f64().unwrap() as f32);
+        assert!(min >= 100.0 && max <= 110.0 && max > min + 9.0, "{min}..{max}");
+
+        let (width, height, samples) = decode_png16(&std::fs::read(format!("{out_dir}/heightmaps/tile0.png")).unwrap());
+        let tile = harness.region.tiles.get(0);
+        assert_eq!((width, height), (tile.width as usize, tile.height as usize));
+        // within a quantization step of the source
+        let step = (max - min) / 65535.0;
+        for (sample, z) in samples.iter().zip(&tile.data) {
+            let back = min + *sample as f32 * step;
+            assert!((back - z).abs() <= step, "{back} vs {z}");
+        }
+    }
+
+    #[test]
+    fn summary_json_counts_the_fixture_run() {
+        let harness = Harness::new("summary");
+        let args = harness.args(&["--map", "--summary-json", "unused"]);
+        harness.region.ensure_out_dir_exists(&args);
+        let mut summary = crate::summary::Summary::start();
+        let report = summary.time("elevation".to_owned(), || harness.region.process_elevation(&args, &AdjacentRegions::default()));
+        let stats = summary.time("map".to_owned(), || harness.region.process_osm(&args, &Rules::default()));
+        summary.add_region(&harness.region.name, &harness.region.out_dir(&args), Some(&report), Some(&stats));
+        let path = format!("{}/summary.json",harness.region.output_dir);
+        summary.save(&path);
+
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let stages: Vec<&String> = json["stages"].as_object().unwrap().keys().collect();
+        assert_eq!(stages, ["elevation", "map"]);
+        let region = &json["regions"][0];
+        assert_eq!(region["name"], harness.region.name.as_str());
+        assert_eq!((region["elevation"]["written"].as_u64(), region["elevation"]["failed"].as_u64()), (Some(1), Some(0)));
+        let tiles = region["elevation"]["tiles"].as_array().unwrap();
+        assert_eq!(tiles.len(), 1);
+        assert_eq!(tiles[0]["faces"].as_u64(), Some(report.mesh_stats[0].1.faces as u64));
+        let count = |kinds: &serde_json::Value| kinds.as_object().unwrap().values().map(|n| n.as_u64().unwrap()).sum::<u64>();
+        assert_eq!((count(&region["osm"]["buildings"]), count(&region["osm"]["roads"])), (1, 1));
+        assert_eq!(region["osm"]["skipped_missing_node"], 0);
+        // the tile, the map and the manifest
+        assert!(region["bytes_written"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn feet_map_matches_its_metric_twin() {
+        let meters = [100.0, 120.0, 90.0, 0.0, -10.0, 3000.0];
+        let feet: Vec<f32> = meters.iter().map(|z| z / 0.3048).collect();
+        let options = |units| LoadOptions { units, ..test_options("feet") };
+        let load = |bytes: Vec<u8>, units| Region::from_decoder("feet".into(), Decoder::new(Cursor::new(bytes)).unwrap(), &options(units));
+
+        let metric = load(geotiff_bytes((3,2), &meters, (400000.0, 6000000.0)), None);
+        let given = load(geotiff_bytes((3,2), &feet, (400000.0, 6000000.0)), Some(ElevationUnits::Ft));
+        // one key after the header: vertical units in US survey feet
+        let keys = [1, 1, 0, 1, 4099, 0, 1, 9003];
+        let detected = load(geotiff_bytes_with_keys((3,2), &feet, (400000.0, 6000000.0), &keys), None);
+        for (x,y) in [(0.5, 0.5), (1.5, 0.5), (2.5, 1.5), (1.0, 1.0)] {
+            let z = metric.get_elevation(x, y);
+            assert!((given.get_elevation(x, y) - z).abs() < 1e-3, "({x},{y})");
+            assert!((detected.get_elevation(x, y) - z).abs() < 1e-3, "({x},{y})");
+        }
+    }
+
+    #[test]
+    fn hemisphere_comes_from_the_projected_crs() {
+        let load = |bytes: Vec<u8>, southern| {
+            let options = LoadOptions { southern, ..test_options("hemisphere") };
+            Region::from_decoder("hemisphere".into(), Decoder::new(Cursor::new(bytes)).unwrap(), &options).coord.northern
+        };
+        // WGS 84 / UTM zone 56S, then 33N
+        let south = [1, 1, 0, 1, 3072, 0, 1, 32756];
+        let north = [1, 1, 0, 1, 3072, 0, 1, 32633];
+        assert!(!load(geotiff_bytes_with_keys((2,2), &[0.0; 4], (334000.0, 6250000.0), &south), false));
+        assert!(load(geotiff_bytes_with_keys((2,2), &[0.0; 4], (500000.0, 5000000.0), &north), true));
+        // without the key, --southern decides
+        assert!(load(geotiff_bytes((2,2), &[0.0; 4], (500000.0, 5000000.0)), false));
+        assert!(!load(geotiff_bytes((2,2), &[0.0; 4], (334000.0, 6250000.0)), true));
+    }
+
+    #[test]
+    fn clamped_terrain_meshes_within_the_range() {
+        // a ramp from 0 to 630m
+        let size = 64;
+        let data: Vec<f32> = (0..size * size).map(|i| (i % size + i / size) as f32 * 5.0).collect();
+        let options = LoadOptions { min_elevation: Some(20.0), max_elevation: Some(300.0), ..test_options("clamp") };
+        let region = Region::from_decoder("clamp".into(), Decoder::new(Cursor::new(geotiff_bytes((size as u32, size as u32), &data, (500000.0, 5000000.0)))).unwrap(), &options);
+        let tile = region.tiles.get(0);
+        assert_eq!((tile.data[0], tile.data[10], tile.data[size * size - 1]), (20.0, 50.0, 300.0));
+        // missing samples stay missing
+        let mut gap = Tile { data: vec![f32::NAN, 500.0], width: 2, height: 1 };
+        gap.clamp_elevation(Some(20.0), Some(300.0));
+        assert!(gap.data[0].is_nan());
+        assert_eq!(gap.data[1], 300.0);
+
+        let (buffer, _) = build_terrain_mesh(&tile.data, size, size, TileNeighbors { next_x: None, next_y: None, corner: None }, &mesh_options(None));
+        let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+        assert_eq!((float(0), float(4)), (20.0, 280.0));
+        // the ceiling is reached but never passed
+        let vertices = mesh_vertices(&buffer);
+        assert!(vertices.iter().all(|v| v.2 <= 300.0 + 0.01), "{vertices:?}");
+        assert!(vertices.iter().any(|v| v.2 > 299.0));
+    }
+
+    #[test]
+    fn bathymetry_fills_water_only() {
+        let mut land = Tile { data: vec![12.0, 0.0, -1.0, f32::NAN], width: 2, height: 2 };
+        let bathymetry = Tile { data: vec![-5.0, -8.0, f32::NAN, -20.0], width: 2, height: 2 };
+        land.merge_bathymetry(&bathymetry);
+        // land kept, sea level and no data take the depth, water without a depth is left alone
+        assert_eq!(land.data[0], 12.0);
+        assert_eq!(land.data[1], -8.0);
+        assert_eq!(land.data[2], -1.0);
+        assert_eq!(land.data[3], -20.0);
+    }
+
+    /// Dequantized vertex positions of a terrain mesh buffer, in meters.
+    fn mesh_vertices(buffer: &crate::Buffer) -> Vec<(f32,f32,f32)> {
+        let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+        let short = |at: usize| u16::from_le_bytes([buffer.bytes[at], buffer.bytes[at + 1]]) as f32;
+        let (min_z, range_z) = (float(0), float(4));
+        let (extent_x, extent_y) = (short(8), short(10));
+        (0..short(12) as usize).map(|v| 14 + v * 9).map(|at| {
+            (short(at) / 65535.0 * extent_x, short(at + 2) / 65535.0 * extent_y, min_z + short(at + 4) / 65535.0 * range_z)
+        }).collect()
+    }
+
+    #[test]
+    fn smoothing_calms_a_noisy_tile() {
+        let noise = |x: i64, y: i64| ((x.max(0) * 7919 + y.max(0) * 104729) % 9) as f32 * 2.0;
+        let size = 150;
+        let noisy = (0..size * size).map(|i| noise((i % size) as i64, (i / size) as i64)).collect::<Vec<_>>();
+        let calm = smooth_tile(&noise, 0, 0, size as u32, size as u32, 2).data;
+
+        let variance = |data: &[f32]| {
+            let mean = data.iter().sum::<f32>() / data.len() as f32;
+            data.iter().map(|z| (z - mean) * (z - mean)).sum::<f32>() / data.len() as f32
+        };
+        let face_count = |data: &[f32]| {
+            let (buffer, _) = build_terrain_mesh(data, size, size, TileNeighbors { next_x: None, next_y: None, corner: None }, &mesh_options(None));
+            let short = |at: usize| u16::from_le_bytes([buffer.bytes[at], buffer.bytes[at + 1]]) as usize;
+            short(14 + short(12) * 9)
+        };
+
+        assert!(variance(&calm) < variance(&noisy) / 4.0, "{} vs {}", variance(&calm), variance(&noisy));
+        assert!(face_count(&calm) < face_count(&noisy), "{} vs {}", face_count(&calm), face_count(&noisy));
+    }
+
+    #[test]
+    fn bathymetry_meshes_continuously_across_tiles() {
+        // a full tile of coast sloping down into the sea, and a narrower tile east of it that is all sea,
+        // with depths only in the bathymetry
+        let height = 4;
+        let coast = |x: usize| 20.0 - x as f32 * 0.1;
+        let mut west = Tile { data: (0..512 * height).map(|i| coast(i % 512)).collect(), width: 512, height: height as u32 };
+        let mut east = Tile { data: vec![f32::NAN; 100 * height], width: 100, height: height as u32 };
+        let depth = |x: usize, y: usize| -30.0 - x as f32 * 0.5 - y as f32;
+        west.merge_bathymetry(&Tile { data: (0..512 * height).map(|i| depth(i % 512, i / 512)).collect(), width: 512, height: height as u32 });
+        east.merge_bathymetry(&Tile { data: (0..100 * height).map(|i| depth(512 + i % 100, i / 100)).collect(), width: 100, height: height as u32 });
+        assert!(east.data.iter().all(|z| *z < -200.0));
+
+        let east = Arc::new(east);
+        let west_mesh = mesh_vertices(&build_terrain_mesh(&west.data, 512, height, TileNeighbors { next_x: Some(east.clone()), next_y: None, corner: None }, &mesh_options(None)).0);
+        let east_mesh = mesh_vertices(&build_terrain_mesh(&east.data, 100, height, TileNeighbors { next_x: None, next_y: None, corner: None }, &mesh_options(None)).0);
+
+        // the west tile's last column is the east tile's first, at the bathymetry depth
+        for y in 0..height {
+            let at_edge = |mesh: &[(f32,f32,f32)], x: f32| mesh.iter().find(|v| (v.0 - x).abs() < 0.01 && (v.1 - y as f32).abs() < 0.01).map(|v| v.2);
+            let (west_z, east_z) = (at_edge(&west_mesh, 512.0).unwrap(), at_edge(&east_mesh, 0.0).unwrap());
+            assert!((west_z - depth(512, y)).abs() < 0.01, "{west_z} at row {y}");
+            assert!((west_z - east_z).abs() < 0.01, "{west_z} vs {east_z} at row {y}");
+        }
+    }
+
+    #[test]
+    fn merged_regions_share_their_border() {
+        // two regions of 2x2 tiles side by side, cut from one rolling surface
+        let size = 512 + 64;
+        let surface = |x: u32, y: u32| x as f32 * 0.05 + y as f32 * 0.02 + (x as f32 / 40.0).sin() * 5.0;
+        let west = Region::from_fn("merge-west", UTMCoord { zone_number: 33, northern: true, easting: 500000.0, northing: 5000000.0 }, (size, size), surface);
+        let east = Region::from_fn("merge-east", UTMCoord { zone_number: 33, northern: true, easting: 500000.0 + size as f64, northing: 5000000.0 }, (size, size), |x,y| surface(x + size, y));
+        let args = CommandArgs::parse_from(["cartographer", "merge-west", "33", "--merge-regions", "merge-east", "--origin", "500000,5000000"]);
+        let regions = [&west, &east];
+        let adjacent = AdjacentRegions::find(&west, &regions);
+        assert!(adjacent.east.is_some_and(|region| region.name == "merge-east") && adjacent.south.is_none());
+        for region in regions {
+            region.ensure_out_dir_exists(&args);
+            let report = region.process_elevation(&args, &AdjacentRegions::find(region, &regions));
+            assert_eq!((report.written, report.failed), (4, 0));
+        }
+
+        let tile = |region: &Region, index: usize| {
+            let mut data = Vec::new();
+            GzDecoder::new(File::open(format!("{}/tile{index}.bin.gz",region.out_dir(&args))).unwrap()).read_to_end(&mut data).unwrap();
+            mesh_vertices(&crate::Buffer { bytes: data, ..Default::default() })
+        };
+        // the west region's last column of tiles is 64 wide, and ends on the east region's first column
+        for (west_index, east_index) in [(1, 0), (3, 2)] {
+            let (west_mesh, east_mesh) = (tile(&west, west_index), tile(&east, east_index));
+            let border = |mesh: &[(f32,f32,f32)], x: f32| {
+                let mut column: Vec<_> = mesh.iter().filter(|v| (v.0 - x).abs() < 0.01).map(|v| (v.1.round() as u32, v.2)).collect();
+                column.sort_by_key(|v| v.0);
+                column
+            };
+            let (west_border, east_border) = (border(&west_mesh, 64.0), border(&east_mesh, 0.0));
+            assert!(!west_border.is_empty(), "tile{west_index} isn't stitched to its neighbor");
+            assert_eq!(west_border.len(), east_border.len());
+            for ((west_y, west_z), (east_y, east_z)) in west_border.iter().zip(&east_border) {
+                assert_eq!(west_y, east_y);
+                assert!((west_z - east_z).abs() < 0.05, "{west_z} vs {east_z} at row {west_y} of tile{west_index}");
+            }
+        }
+        for region in regions {
+            let manifest = std::fs::read_to_string(format!("{}/manifest.json",region.out_dir(&args))).unwrap();
+            let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+            assert_eq!(manifest["origin"], serde_json::json!([500000.0, 5000000.0]));
+            std::fs::remove_dir_all(&region.output_dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn global_z_range_is_shared_by_every_tile() {
+        // 2x2 tiles, each spanning different heights but all with a sample at 50m
+        let size = 8;
+        let tiles: Vec<Tile> = [(40.0, 1.0), (0.0, 8.0), (50.0, 0.5), (-20.0, 10.0)].iter().map(|(low, step)| {
+            let mut data: Vec<f32> = (0..size * size).map(|i| low + (i % size) as f32 * step).collect();
+            data[size * size - 1] = 50.0;
+            Tile { data, width: size as u32, height: size as u32 }
+        }).collect();
+        let region = Region {
+            name: "z-range".into(),
+            coord: UTMCoord { zone_number: 33, northern: true, easting: 0.0, northing: 0.0 },
+            input_dir: test_dir("z-range"),
+            output_dir: test_dir("z-range"),
+            width: 2 * size as u32,
+            height: 2 * size as u32,
+            tiles_x: 2,
+            tiles_y: 2,
+            tiles: Arc::new(TileStore::filled(tiles)),
+            warned_out_of_bounds: AtomicBool::new(false),
+            datum: 0.0
+        };
+        let (min_z, range_z) = region.global_z_range();
+        assert_eq!((min_z, range_z), (-20.0, 76.0));
+
+        let no_neighbors = || TileNeighbors { next_x: None, next_y: None, corner: None };
+        let quantized_50 = |z_range: Option<(f32,f32)>| -> Vec<((f32,f32),u16)> {
+            (0..4).map(|i| {
+                let tile = region.tiles.get(i);
+                let (buffer, _) = build_terrain_mesh(&tile.data, size, size, no_neighbors(), &mesh_options(z_range));
+                let float = |at: usize| f32::from_le_bytes(buffer.bytes[at..at + 4].try_into().unwrap());
+                let short = |at: usize| u16::from_le_bytes([buffer.bytes[at], buffer.bytes[at + 1]]);
+                // the far corner, at 50m
+                let vertices = || (0..short(12) as usize).map(|v| 14 + v * 9);
+                let far = vertices().map(short).max().unwrap();
+                let corner = vertices().find(|at| short(*at) == far && short(*at + 2) == far).unwrap();
+                ((float(0), float(4)), short(corner + 4))
+            }).collect()
+        };
+        let global = quantized_50(Some((min_z, range_z)));
+        assert!(global.iter().all(|tile| *tile == global[0]), "{global:?}");
+        assert_eq!(global[0].0, (-20.0, 76.0));
+        // which they don't when each tile uses its own extents
+        let own = quantized_50(None);
+        assert!(own.iter().any(|tile| tile.1 != own[0].1), "{own:?}");
+    }
+
+    #[test]
+    fn origin_from_tie_points() {
+        assert_eq!(raster_origin(&[0.0, 0.0, 0.0, 500.0, 900.0, 0.0], None), (500.0, 900.0));
+        // raster (2,3) tied to (502,897) with 1m pixels puts the corner back at (500,900)
+        assert_eq!(raster_origin(&[2.0, 3.0, 0.0, 502.0, 897.0, 0.0], Some(&[1.0, 1.0, 0.0])), (500.0, 900.0));
+        // only the first of several is used
+        assert_eq!(raster_origin(&[0.0, 0.0, 0.0, 500.0, 900.0, 0.0, 10.0, 10.0, 0.0, 510.0, 890.0, 0.0], None), (500.0, 900.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "malformed tie point")]
+    fn origin_from_a_malformed_tie_point() {
+        raster_origin(&[0.0, 0.0, 0.0, 500.0, 900.0, 0.0, 1.0], None);
+    }
+
+    /// An in-memory GeoTIFF whose tie point pins raster `(i,j)`, rather than the corner, to `model`.
+    fn offset_tie_point_bytes(dims: (u32,u32), data: &[f32], (i,j): (f64,f64), model: (f64,f64), pixel_scale: Option<&[f64]>) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut out).unwrap();
+        let mut image = encoder.new_image::<colortype::Gray32Float>(dims.0, dims.1).unwrap();
+        image.encoder().write_tag(Tag::ModelTiepointTag, &[i, j, 0.0, model.0, model.1, 0.0][..]).unwrap();
+        if let Some(scale) = pixel_scale {
+            image.encoder().write_tag(Tag::ModelPixelScaleTag, scale).unwrap();
+        }
+        image.write_data(data).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn map_tied_away_from_its_corner() {
+        let dims = (16, 8);
+        let data = vec![5.0f32; 16 * 8];
+        let options = test_options("tie-point");
+        let bytes = offset_tie_point_bytes(dims, &data, (4.0, 2.0), (500004.0, 4999998.0), Some(&[1.0, 1.0, 0.0]));
+        let region = Region::from_decoder("tie-point".to_owned(), Decoder::new(Cursor::new(bytes)).unwrap(), &options);
+        assert_eq!((region.coord.easting, region.coord.northing), (500000.0, 5000000.0));
+
+        // without a pixel scale there's no telling where the corner is
+        let bytes = offset_tie_point_bytes(dims, &data, (4.0, 2.0), (500004.0, 4999998.0), None);
+        let result = std::panic::catch_unwind(|| Region::from_decoder("tie-point".to_owned(), Decoder::new(Cursor::new(bytes)).unwrap(), &options));
+        let message = result.err().unwrap().downcast::<String>().unwrap();
+        assert!(message.contains("tie point is at raster (4,2) rather than (0,0)"), "{message}");
+    }
+
+    #[test]
+    fn stripped_and_tiled_maps_give_the_same_tiles() {
+        // 2x2 tiles, the right and bottom ones cut short
+        let dims = (700, 600);
+        let data: Vec<f32> = (0..dims.0 * dims.1).map(|i| (i % 997) as f32 * 0.5).collect();
+        let read = |bytes: Vec<u8>| read_tiles(Decoder::new(Cursor::new(bytes)).unwrap(), u64::MAX);
+        let (stripped, stripped_origin, stripped_dims) = read(geotiff_bytes(dims, &data, (1000.0, 2000.0)));
+        let tiled_bytes = tiled_geotiff_bytes(dims, &data, (1000.0, 2000.0), 1);
+        assert_eq!(Decoder::new(Cursor::new(&tiled_bytes)).unwrap().get_chunk_type(), ChunkType::Tile);
+        let (tiled, tiled_origin, tiled_dims) = read(tiled_bytes);
+
+        assert_eq!((stripped_dims, tiled_dims), (dims, dims));
+        assert_eq!(stripped_origin, tiled_origin);
+        assert_eq!(stripped_origin, (1000.0, 2000.0));
+        let sizes: Vec<(u32,u32)> = tiled.iter().map(|tile| (tile.width, tile.height)).collect();
+        assert_eq!(sizes, [(512, 512), (188, 512), (512, 88), (188, 88)]);
+        assert_eq!(stripped.len(), tiled.len());
+        for (a,b) in stripped.iter().zip(&tiled) {
+            assert_eq!((a.width, a.height), (b.width, b.height));
+            assert_eq!(a.data, b.data);
+        }
+        assert_eq!(tiled[3].get(187, 87), data[599 * 700 + 699]);
+    }
+
+    #[test]
+    fn deflated_map_decodes() {
+        let dims = (700, 600);
+        let data: Vec<f32> = (0..dims.0 * dims.1).map(|i| (i % 997) as f32 * 0.5).collect();
+        let read = |bytes: Vec<u8>| read_tiles(Decoder::new(Cursor::new(bytes)).unwrap(), u64::MAX).0;
+        let plain = read(tiled_geotiff_bytes(dims, &data, (1000.0, 2000.0), 1));
+        let deflated = read(tiled_geotiff_bytes(dims, &data, (1000.0, 2000.0), 8));
+
+        assert_eq!(plain.len(), deflated.len());
+        for (a,b) in plain.iter().zip(&deflated) {
+            assert_eq!((a.width, a.height), (b.width, b.height));
+            assert_eq!(a.data, b.data);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "unsupported compression ModernJPEG (7)")]
+    fn unsupported_compression_is_named() {
+        let data = vec![0.0; 600 * 600];
+        read_tiles(Decoder::new(Cursor::new(tiled_geotiff_bytes((600, 600), &data, (0.0, 0.0), 7))).unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn oversized_map_is_refused_up_front() {
+        let bytes = geotiff_bytes((20,10), &[0.0; 200], (400000.0, 6000000.0));
+        let options = LoadOptions { max_region_size: 199, ..test_options("oversized") };
+        let result = std::panic::catch_unwind(|| Region::from_decoder("oversized".to_owned(), Decoder::new(Cursor::new(bytes.clone())).unwrap(), &options));
+        let message = result.err().unwrap().downcast::<String>().unwrap();
+        assert_eq!(*message, "DEM too large: 200 pixels (20x10) exceeds cap 199; use --crop or raise --max-region-size");
+
+        // right at the cap is fine
+        let options = LoadOptions { max_region_size: 200, ..test_options("oversized") };
+        let region = Region::from_decoder("oversized".to_owned(), Decoder::new(Cursor::new(bytes)).unwrap(), &options);
+        assert_eq!((region.width, region.height), (20, 10));
+    }
+
+    /// A fresh directory holding the given maps, named in order.
+    fn map_directory(name: &str, maps: &[Vec<u8>]) -> String {
+        let dir = test_dir(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (i,map) in maps.iter().enumerate() {
+            std::fs::write(format!("{dir}/{i}.tif"), map).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn directory_assembles_by_tie_points() {
+        // a 2x2 map, and a 1x1 map to its east that starts a row lower, leaving a gap above it
+        let west = geotiff_bytes((2,2), &[1.0, 2.0, 3.0, 4.0], (1000.0, 5000.0));
+        let east = geotiff_bytes((1,1), &[5.0], (1002.0, 4999.0));
+        let dir = map_directory("assemble", &[east, west]);
+        let region = Region::from_directory("assemble".into(), &dir, &test_options("assemble"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!((region.width, region.height), (3,2));
+        assert_eq!((region.coord.easting, region.coord.northing), (1000.0, 5000.0));
+        assert_eq!(region.try_get_elevation(0.0, 0.0), Some(1.0));
+        assert_eq!(region.try_get_elevation(1.0, 1.0), Some(4.0));
+        assert_eq!(region.try_get_elevation(2.0, 1.0), Some(5.0));
+        assert!(region.get_elevation(2.0, 0.0).is_nan());
+    }
+
+    #[test]
+    #[should_panic(expected = "only 1m maps can be assembled")]
+    fn directory_refuses_other_pixel_sizes() {
+        let map = offset_tie_point_bytes((2,2), &[1.0; 4], (0.0, 0.0), (1000.0, 5000.0), Some(&[30.0, 30.0, 0.0]));
+        let dir = map_directory("scale", &[map]);
+        Region::from_directory("scale".into(), &dir, &test_options("scale"));
+    }
+
+    #[test]
+    #[should_panic(expected = "DEM too large: 204 pixels (102x2) once assembled exceeds cap 100")]
+    fn directory_refuses_assembling_too_much() {
+        let west = geotiff_bytes((2,2), &[1.0; 4], (1000.0, 5000.0));
+        let east = geotiff_bytes((2,2), &[1.0; 4], (1100.0, 5000.0));
+        let dir = map_directory("cap", &[west, east]);
+        Region::from_directory("cap".into(), &dir, &LoadOptions { max_region_size: 100, ..test_options("cap") });
+    }
+
+    #[test]
+    fn checked_tile_access_outside_is_none() {
+        let tile = Tile { data: (0..6).map(|i| i as f32).collect(), width: 3, height: 2 };
+        assert_eq!(tile.get_checked(2, 1), Some(5.0));
+        // past the end of a row, rather than the start of the next
+        assert_eq!(tile.get_checked(3, 0), None);
+        assert_eq!(tile.get_checked(0, 2), None);
+        assert_eq!(tile.get_checked(usize::MAX, usize::MAX), None);
+    }
+
+    /// A flat region with its top-left corner this many meters west and north of (lat, lon).
+    fn region_around(lat: f64, lon: f64, zone_number: u8, offset: f64, dims: (u32,u32)) -> Region {
+        let (northing,easting,_) = utm::to_utm_wgs84(lat, lon, zone_number);
+        Region::from_fn("bounds", UTMCoord { zone_number, northern: lat >= 0.0, easting: easting - offset, northing: northing + offset }, dims, |_,_| 0.0)
+    }
+
+    #[test]
+    fn bounds_split_at_the_date_line() {
+        let args = CommandArgs::parse_from(["cartographer", "test", "1"]);
+        // Fiji, straddling 180°
+        let region = region_around(-16.5, -180.0, 1, 2000.0, (4000,4000));
+        let bounds = region.get_bounds(&args);
+        assert!(bounds.crosses_date_line());
+        assert!(bounds.west > 179.9 && bounds.west < 180.0, "west {}",bounds.west);
+        assert!(bounds.east > -180.0 && bounds.east < -179.9, "east {}",bounds.east);
+        assert!(bounds.south < -16.5 && bounds.north > -16.5);
+        // a node just west of the date line lands in the region, next to its east-of-the-line neighbor
+        let (x,_) = coords::latlon_to_local(&region, -16.5, 179.999);
+        let (next_x,_) = coords::latlon_to_local(&region, -16.5, -179.999);
+        assert!(region.contains(x, 2000.0) && (next_x - x - 213.0).abs() < 1.0, "{x} {next_x}");
+
+        let bboxes = bounds.bboxes();
+        assert_eq!(bboxes.len(), 2);
+        assert!(bboxes[0].ends_with(",180"), "{}",bboxes[0]);
+        assert!(bboxes[1].contains(",-180,"), "{}",bboxes[1]);
+    }
+
+    #[test]
+    fn bounds_near_the_poles() {
+        let args = CommandArgs::parse_from(["cartographer", "test", "33"]);
+        for lat in [83.9, -79.9] {
+            let region = region_around(lat, 15.0, 33, 1000.0, (2048,2048));
+            let bounds = region.get_bounds(&args);
+            assert!(!bounds.crosses_date_line());
+            assert!(bounds.south < lat && lat < bounds.north, "{lat} not in {}..{}",bounds.south,bounds.north);
+            assert!(bounds.west < 15.0 && 15.0 < bounds.east, "15 not in {}..{}",bounds.west,bounds.east);
+            assert!(bounds.north <= 90.0 && bounds.south >= -90.0);
+        }
+
+        // corners past the pole or the antimeridian are clamped and wrapped
+        let bounds = Bounds::from_corners(&[(90.5, 10.0), (89.0, 370.5)]);
+        assert_eq!(bounds.north, 90.0);
+        assert_eq!(bounds.south, 89.0);
+        assert_eq!(bounds.west, 10.0);
+        assert_eq!(bounds.east, 10.5);
+    }
+
+    #[test]
+    fn tile_store_waits_for_a_slow_reader() {
+        let store = Arc::new(TileStore::empty(2));
+        let reader = store.clone();
+        std::thread::spawn(move || {
+            for i in 0..2 {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                reader.put(i, Tile { data: vec![i as f32], width: 1, height: 1 });
+            }
+        });
+        // asks for the last tile first, before the reader has got to it
+        assert_eq!(store.get(1).data, vec![1.0]);
+        assert_eq!(store.get(0).data, vec![0.0]);
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn first_tile_is_usable_before_the_rest_are_read() {
+        let store = Arc::new(TileStore::empty(2));
+        let reader = store.clone();
+        let (used, first_used) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            reader.put(0, Tile { data: vec![0.0], width: 1, height: 1 });
+            // holds the second tile back until the first has been picked up
+            if first_used.recv_timeout(std::time::Duration::from_secs(10)).is_err() {
+                reader.fail();
+                return;
+            }
+            reader.put(1, Tile { data: vec![1.0], width: 1, height: 1 });
+        });
+        assert_eq!(store.get(0).data, vec![0.0]);
+        used.send(()).unwrap();
+        assert_eq!(store.get(1).data, vec![1.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "never read")]
+    fn tile_store_gives_up_when_the_reader_fails() {
+        let store = Arc::new(TileStore::empty(1));
+        let reader = store.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            reader.fail();
+        });
+        store.get(0);
+    }
+
+    /// An in-memory GeoTIFF in EPSG:4326, its top-left corner at `(lon, lat)` and `scale` degrees per sample.
+    fn geographic_bytes(dims: (u32,u32), data: &[f32], (lon,lat): (f64,f64), scale: f64) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut out).unwrap();
+        let mut image = encoder.new_image::<colortype::Gray32Float>(dims.0, dims.1).unwrap();
+        image.encoder().write_tag(Tag::ModelTiepointTag, &[0.0, 0.0, 0.0, lon, lat, 0.0][..]).unwrap();
+        image.encoder().write_tag(Tag::ModelPixelScaleTag, &[scale, scale, 0.0][..]).unwrap();
+        // geographic model type, WGS 84
+        image.encoder().write_tag(Tag::GeoKeyDirectoryTag, &[1u16, 1, 0, 2, 1024, 0, 1, 2, 2048, 0, 1, 4326][..]).unwrap();
+        image.write_data(data).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn geographic_map_is_sampled_where_it_says() {
+        // a plane rising one meter per sample east and per sample south, straddling zone 33's meridian
+        let (lon0, lat0, scale) = (14.99, 45.01, 0.0001);
+        let data: Vec<f32> = (0..30 * 40).map(|i| (i % 40 + i / 40) as f32).collect();
+        let mut tiff = Decoder::new(Cursor::new(geographic_bytes((40,30), &data, (lon0, lat0), scale))).unwrap();
+        assert!(is_geographic(&mut tiff));
+        let region = Region::from_geographic("geographic".into(), tiff, &test_options("geographic"));
+
+        let (col, row) = (10.5, 20.25);
+        let (northing, easting, _) = utm::to_utm_wgs84(lat0 - row * scale, lon0 + col * scale, 33);
+        let x = (easting - region.coord.easting) as f32;
+        let y = (region.coord.northing - northing) as f32;
+        // the nearest meter sample, which is within a small fraction of a source sample
+        let z = region.try_get_elevation(x, y).unwrap();
+        assert!((z - (col + row) as f32).abs() < 0.15, "{z} at {x} {y}");
+    }
+
+    #[test]
+    fn geographic_map_takes_geographic_bathymetry() {
+        // sea level in the west half, land in the east, and depths everywhere in the bathymetry
+        let (lon0, lat0, scale) = (14.99, 45.01, 0.0001);
+        let land: Vec<f32> = (0..30 * 40).map(|i| if i % 40 < 20 { 0.0 } else { 10.0 }).collect();
+        let depths = vec![-5.0f32; 30 * 40];
+        let dir = map_directory("geographic-bathymetry", &[geographic_bytes((40,30), &depths, (lon0, lat0), scale)]);
+        let options = LoadOptions { bathymetry: Some("0".into()), ..test_options("geographic-bathymetry") };
+        let tiff = Decoder::new(Cursor::new(geographic_bytes((40,30), &land, (lon0, lat0), scale))).unwrap();
+        let region = Region::from_geographic("geographic-bathymetry".into(), tiff, &options);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let at = |col: f64, row: f64| {
+            let (northing, easting, _) = utm::to_utm_wgs84(lat0 - row * scale, lon0 + col * scale, 33);
+            region.try_get_elevation((easting - region.coord.easting) as f32, (region.coord.northing - northing) as f32).unwrap()
+        };
+        assert_eq!(at(5.0, 15.0), -5.0);
+        assert_eq!(at(35.0, 15.0), 10.0);
     }
 }
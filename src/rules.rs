@@ -0,0 +1,98 @@
+use serde::Deserialize;
+
+use crate::BuildingKind;
+
+/// User-supplied tag mappings from `--rules`, checked before the built-in classification.
+///
+/// ```toml
+/// default_building_height = 4.0
+///
+/// [[building]]
+/// key = "amenity"
+/// value = "parking"
+/// kind = "parking"
+/// height = 9.0
+///
+/// [[road]]
+/// key = "highway"
+/// value = "bridleway"
+/// kind = "footpath"
+///
+/// [[area]]
+/// key = "amenity"
+/// value = "parking"
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    /// Used for buildings with no height, levels or rule height
+    pub default_building_height: Option<f32>,
+    #[serde(default)]
+    pub building: Vec<BuildingRule>,
+    #[serde(default)]
+    pub road: Vec<RoadRule>,
+    /// Closed ways matching these are written as paved areas
+    #[serde(default)]
+    pub area: Vec<TagMatch>,
+}
+
+/// Matches a tag by key, and by value if one is given.
+#[derive(Deserialize)]
+pub struct TagMatch {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct BuildingRule {
+    pub key: String,
+    pub value: Option<String>,
+    /// Overrides the kind inferred from size and height
+    pub kind: Option<BuildingKind>,
+    /// Height when the way has none of its own
+    pub height: Option<f32>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RoadClass {
+    Road,
+    Footpath,
+    Bikepath
+}
+
+#[derive(Deserialize)]
+pub struct RoadRule {
+    pub key: String,
+    pub value: Option<String>,
+    pub kind: RoadClass,
+    /// Full width in meters, overriding the width from lanes / path settings
+    pub width: Option<f32>,
+}
+
+fn tag_matches<'a>(key: &str, value: &Option<String>, tag: impl Fn(&str) -> Option<&'a str>) -> bool {
+    match (tag(key), value) {
+        (Some(found), Some(value)) => found == value,
+        (Some(_), None) => true,
+        (None, _) => false
+    }
+}
+
+impl Rules {
+    pub fn load(path: &str) -> Self {
+        let text = std::fs::read_to_string(path).unwrap_or_else(|err| panic!("failed to read rules {}: {}",path,err));
+        toml::from_str(&text).unwrap_or_else(|err| panic!("bad rules file {}: {}",path,err))
+    }
+
+    pub fn building<'a>(&self, tag: impl Fn(&str) -> Option<&'a str> + Copy) -> Option<&BuildingRule> {
+        self.building.iter().find(|rule| tag_matches(&rule.key, &rule.value, tag))
+    }
+
+    pub fn road<'a>(&self, tag: impl Fn(&str) -> Option<&'a str> + Copy) -> Option<&RoadRule> {
+        self.road.iter().find(|rule| tag_matches(&rule.key, &rule.value, tag))
+    }
+
+    pub fn is_area<'a>(&self, tag: impl Fn(&str) -> Option<&'a str> + Copy) -> bool {
+        self.area.iter().any(|rule| tag_matches(&rule.key, &rule.value, tag))
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::Instant;
+
+use serde_json::{json, Value};
+
+use crate::{region::ElevationReport, OsmStats};
+
+/// Machine-readable report of a run, written by --summary-json.
+pub struct Summary {
+    started: Instant,
+    /// Wall-clock seconds of each stage, in the order they ran
+    stages: Vec<(String, f64)>,
+    regions: Vec<Value>,
+}
+
+impl Summary {
+    /// Starts the clock for the whole run.
+    pub fn start() -> Self {
+        Summary { started: Instant::now(), stages: Vec::new(), regions: Vec::new() }
+    }
+
+    /// Runs `f`, noting how long it took under `stage`.
+    pub fn time<T>(&mut self, stage: String, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.stages.push((stage, started.elapsed().as_secs_f64()));
+        result
+    }
+
+    pub fn add_region(&mut self, name: &str, out_dir: &str, elevation: Option<&ElevationReport>, osm: Option<&OsmStats>) {
+        let elevation = elevation.map(|report| {
+            let tiles: Vec<Value> = report.seconds.iter().map(|(index,seconds)| {
+                let mesh = report.mesh_stats.iter().find(|(i,_)| i == index).map(|(_,stats)| stats);
+                json!({
+                    "index": index,
+                    "seconds": seconds,
+                    "vertices": mesh.map(|stats| stats.vertices),
+                    "faces": mesh.map(|stats| stats.faces),
+                    "max_error": mesh.map(|stats| stats.max_error),
+                })
+            }).collect();
+            json!({ "written": report.written, "failed": report.failed, "tiles": tiles })
+        });
+        self.regions.push(json!({
+            "name": name,
+            "elevation": elevation,
+            "osm": osm.map(|stats| serde_json::to_value(stats).unwrap()),
+            "bytes_written": dir_bytes(out_dir),
+        }));
+    }
+
+    pub fn save(&self, path: &str) {
+        let stages: serde_json::Map<String, Value> = self.stages.iter().map(|(stage,seconds)| (stage.clone(), json!(seconds))).collect();
+        let json = json!({
+            "seconds": self.started.elapsed().as_secs_f64(),
+            "stages": stages,
+            "regions": self.regions,
+        });
+        std::fs::write(path, serde_json::to_string_pretty(&json).unwrap()).unwrap_or_else(|err| panic!("failed to write {}: {}",path,err));
+        println!("> wrote {}",path);
+    }
+}
+
+/// Total size of the files directly in a directory.
+fn dir_bytes(dir: &str) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries.filter_map(|entry| entry.ok()?.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
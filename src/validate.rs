@@ -0,0 +1,332 @@
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+
+use crate::{elevation::ElevationFormat, Endian, MAP_FLAG_BORDER, MAP_FLAG_GROUND, MAP_FLAG_METRICS, MAP_FLAG_NORMALS, MAP_FLAG_PALETTE, MAP_FLAG_WALLS, MAP_MAGIC, MAP_VERSION, OBJ_AREA, OBJ_BUILDING, OBJ_INDOOR, OBJ_JUNCTION, OBJ_PEAK, OBJ_PORTAL, OBJ_ROAD, OBJ_TREE};
+
+/// What kind of buffer an output file should contain.
+#[derive(Clone, Copy)]
+pub enum OutputKind {
+    Terrain(ElevationFormat),
+    Map
+}
+
+/// Reads back the values a `Buffer` writes, failing instead of running off the end.
+pub struct BufferReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    endian: Endian,
+}
+
+impl<'a> BufferReader<'a> {
+    pub fn new(bytes: &'a [u8], endian: Endian) -> Self {
+        BufferReader { bytes, pos: 0, endian }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    pub fn skip(&mut self, n: usize) -> Result<(), String> {
+        if n > self.remaining() {
+            return Err(format!("truncated at byte {}, wanted {} more",self.pos,n));
+        }
+        self.pos += n;
+        Ok(())
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let start = self.pos;
+        self.skip(N)?;
+        Ok(self.bytes[start..start + N].try_into().unwrap())
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8, String> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn read_short(&mut self) -> Result<u16, String> {
+        let bytes = self.take()?;
+        Ok(match self.endian {
+            Endian::Le => u16::from_le_bytes(bytes),
+            Endian::Be => u16::from_be_bytes(bytes)
+        })
+    }
+
+    pub fn read_int(&mut self) -> Result<u32, String> {
+        let bytes = self.take()?;
+        Ok(match self.endian {
+            Endian::Le => u32::from_le_bytes(bytes),
+            Endian::Be => u32::from_be_bytes(bytes)
+        })
+    }
+
+    pub fn read_float(&mut self) -> Result<f32, String> {
+        Ok(f32::from_bits(self.read_int()?))
+    }
+
+    pub fn read_string(&mut self) -> Result<String, String> {
+        let len = self.read_short()? as usize;
+        let start = self.pos;
+        self.skip(len)?;
+        String::from_utf8(self.bytes[start..start + len].to_vec()).map_err(|_| "string is not utf-8".to_owned())
+    }
+}
+
+/// Re-opens a written `.bin.gz` and checks that it decompresses and its contents hang together.
+pub fn validate_file(path: &str, kind: OutputKind, endian: Endian) -> Result<(), String> {
+    let file = std::fs::File::open(path).map_err(|err| err.to_string())?;
+    let mut data = Vec::new();
+    GzDecoder::new(file).read_to_end(&mut data).map_err(|err| format!("bad gzip: {}",err))?;
+    validate_bytes(&data, kind, endian)
+}
+
+pub fn validate_bytes(data: &[u8], kind: OutputKind, endian: Endian) -> Result<(), String> {
+    let mut reader = BufferReader::new(data, endian);
+    match kind {
+        OutputKind::Terrain(ElevationFormat::Mesh) => validate_mesh(&mut reader)?,
+        OutputKind::Terrain(ElevationFormat::Raster) => validate_raster(&mut reader)?,
+        OutputKind::Map => validate_map(&mut reader)?
+    }
+    if reader.remaining() != 0 {
+        return Err(format!("{} trailing bytes",reader.remaining()));
+    }
+    Ok(())
+}
+
+fn validate_mesh(reader: &mut BufferReader) -> Result<(), String> {
+    reader.read_float()?;
+    reader.read_float()?;
+    // extent
+    reader.skip(2 * 2)?;
+    let vertex_count = reader.read_short()?;
+    // quantized position + normal
+    reader.skip(vertex_count as usize * 9)?;
+    let face_count = reader.read_short()?;
+    for _ in 0..face_count as usize * 3 {
+        let index = reader.read_short()?;
+        if index >= vertex_count {
+            return Err(format!("face references vertex {} of {}",index,vertex_count));
+        }
+    }
+    Ok(())
+}
+
+fn validate_raster(reader: &mut BufferReader) -> Result<(), String> {
+    reader.read_float()?;
+    reader.read_float()?;
+    let width = reader.read_short()? as usize;
+    let height = reader.read_short()? as usize;
+    reader.skip(width * height * 2)
+}
+
+fn validate_map(reader: &mut BufferReader) -> Result<(), String> {
+    if reader.take::<4>()? != *MAP_MAGIC {
+        return Err("not a map file".to_owned());
+    }
+    let endian = reader.read_byte()?;
+    if endian != reader.endian as u8 {
+        return Err(format!("map byte order {}, expected {}",endian,reader.endian.name()));
+    }
+    let version = reader.read_short()?;
+    if version != MAP_VERSION {
+        return Err(format!("map version {}, expected {}",version,MAP_VERSION));
+    }
+    // extent
+    reader.skip(4 * 4)?;
+    let declared = reader.read_int()?;
+    let flags = reader.read_int()?;
+    if flags & !(MAP_FLAG_METRICS | MAP_FLAG_PALETTE | MAP_FLAG_NORMALS | MAP_FLAG_GROUND | MAP_FLAG_BORDER | MAP_FLAG_WALLS) != 0 {
+        return Err(format!("unknown flags {:#x}",flags));
+    }
+    let metrics = flags & MAP_FLAG_METRICS != 0;
+    let palette = flags & MAP_FLAG_PALETTE != 0;
+    let normals = flags & MAP_FLAG_NORMALS != 0;
+    let ground = flags & MAP_FLAG_GROUND != 0;
+    let border = flags & MAP_FLAG_BORDER != 0;
+    let walls = flags & MAP_FLAG_WALLS != 0;
+
+    let mut count = 0;
+    while reader.remaining() > 0 {
+        let id = reader.read_byte()?;
+        match id {
+            OBJ_BUILDING => {
+                reader.skip(5 * 4 + 3 + 4)?;
+                if palette {
+                    reader.skip(1)?;
+                }
+                if metrics {
+                    reader.skip(4 + 2)?;
+                }
+                let points = reader.read_short()?;
+                let point_size = if ground { 3 * 4 } else { 2 * 4 };
+                reader.skip(points as usize * point_size)?;
+                if normals {
+                    reader.skip(points as usize * 2 + 3)?;
+                }
+                if walls {
+                    let triangles = reader.read_short()?;
+                    // three corners + quantized normal
+                    reader.skip(triangles as usize * (9 * 4 + 2))?;
+                }
+            }
+            OBJ_ROAD => {
+                reader.skip(3 * 4 + 4)?;
+                let nodes = reader.read_short()?;
+                reader.skip(nodes as usize * 12 * 4)?;
+            }
+            OBJ_AREA => {
+                reader.skip(3 * 4 + 2)?;
+                if border {
+                    reader.skip(4)?;
+                }
+                let points = reader.read_short()?;
+                reader.skip(points as usize * 3 * 4)?;
+            }
+            OBJ_PEAK => {
+                reader.skip(3 * 4)?;
+                reader.read_string()?;
+            }
+            OBJ_PORTAL => reader.skip(3 * 4 + 1 + 3 * 4)?,
+            OBJ_JUNCTION => {
+                reader.skip(3 * 4)?;
+                let roads = reader.read_short()?;
+                reader.skip(roads as usize * 4)?;
+            }
+            OBJ_TREE => reader.skip(5 * 4)?,
+            OBJ_INDOOR => {
+                reader.skip(4 * 4 + 1)?;
+                let points = reader.read_short()?;
+                reader.skip(points as usize * 2 * 4)?;
+            }
+            other => return Err(format!("unknown object id {} in record {}",other,count))
+        }
+        count += 1;
+    }
+    if count != declared {
+        return Err(format!("header declares {} features, found {}",declared,count));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Buffer;
+
+    /// A mesh buffer with one triangle per entry of `faces`.
+    fn mesh(vertex_count: u16, faces: &[[u16; 3]]) -> Vec<u8> {
+        let mut buffer = Buffer::default();
+        buffer.write_float(0.0);
+        buffer.write_float(10.0);
+        buffer.write_short(512);
+        buffer.write_short(512);
+        buffer.write_short(vertex_count);
+        for _ in 0..vertex_count as usize * 9 {
+            buffer.write_byte(0);
+        }
+        buffer.write_short(faces.len() as u16);
+        for face in faces {
+            for index in face {
+                buffer.write_short(*index);
+            }
+        }
+        buffer.bytes
+    }
+
+    /// A map holding a single tree.
+    fn map(magic: &[u8; 4], version: u16, endian: Endian) -> Vec<u8> {
+        let mut buffer = Buffer::new(endian);
+        buffer.bytes.extend_from_slice(magic);
+        buffer.write_byte(endian as u8);
+        buffer.write_short(version);
+        for _ in 0..4 {
+            buffer.write_float(0.0);
+        }
+        buffer.write_int(1);
+        buffer.write_int(0);
+        buffer.write_byte(OBJ_TREE);
+        for _ in 0..5 {
+            buffer.write_float(1.0);
+        }
+        buffer.bytes
+    }
+
+    #[test]
+    fn accepts_a_valid_mesh() {
+        let data = mesh(3, &[[0,1,2]]);
+        assert_eq!(validate_bytes(&data, OutputKind::Terrain(ElevationFormat::Mesh), Endian::Le), Ok(()));
+    }
+
+    #[test]
+    fn counts_past_u16_faces() {
+        // more faces than fit in u16 once multiplied by 3
+        let faces = vec![[0,1,2]; 30_000];
+        let data = mesh(3, &faces);
+        assert_eq!(validate_bytes(&data, OutputKind::Terrain(ElevationFormat::Mesh), Endian::Le), Ok(()));
+    }
+
+    #[test]
+    fn rejects_a_truncated_mesh() {
+        let data = mesh(3, &[[0,1,2]]);
+        let err = validate_bytes(&data[..data.len()-1], OutputKind::Terrain(ElevationFormat::Mesh), Endian::Le).unwrap_err();
+        assert!(err.starts_with("truncated"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_a_bad_index() {
+        let data = mesh(3, &[[0,1,3]]);
+        assert_eq!(validate_bytes(&data, OutputKind::Terrain(ElevationFormat::Mesh), Endian::Le), Err("face references vertex 3 of 3".to_owned()));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut data = mesh(3, &[[0,1,2]]);
+        data.push(0);
+        assert_eq!(validate_bytes(&data, OutputKind::Terrain(ElevationFormat::Mesh), Endian::Le), Err("1 trailing bytes".to_owned()));
+    }
+
+    #[test]
+    fn checks_the_map_header() {
+        assert_eq!(validate_bytes(&map(MAP_MAGIC, MAP_VERSION, Endian::Le), OutputKind::Map, Endian::Le), Ok(()));
+        assert_eq!(validate_bytes(&map(b"GIF8", MAP_VERSION, Endian::Le), OutputKind::Map, Endian::Le), Err("not a map file".to_owned()));
+        assert_eq!(validate_bytes(&map(MAP_MAGIC, MAP_VERSION + 1, Endian::Le), OutputKind::Map, Endian::Le), Err(format!("map version {}, expected {}",MAP_VERSION + 1,MAP_VERSION)));
+        let mut data = map(MAP_MAGIC, MAP_VERSION, Endian::Le);
+        assert!(validate_bytes(&data[..data.len()-1], OutputKind::Map, Endian::Le).unwrap_err().starts_with("truncated"));
+        // flags this version doesn't know about
+        data[27] = 0x40;
+        assert_eq!(validate_bytes(&data, OutputKind::Map, Endian::Le), Err("unknown flags 0x40".to_owned()));
+    }
+
+    #[test]
+    fn big_endian_map_reads_back_big_endian() {
+        let data = map(MAP_MAGIC, MAP_VERSION, Endian::Be);
+        assert_eq!(data[4], 1);
+        assert_eq!(data[5..7], MAP_VERSION.to_be_bytes());
+        assert_eq!(validate_bytes(&data, OutputKind::Map, Endian::Be), Ok(()));
+        assert_eq!(validate_bytes(&data, OutputKind::Map, Endian::Le), Err("map byte order 1, expected le".to_owned()));
+
+        let mut reader = BufferReader::new(&data[27..], Endian::Be);
+        assert_eq!(reader.read_int(), Ok(0));
+        assert_eq!(reader.read_byte(), Ok(OBJ_TREE));
+        assert_eq!(reader.read_float(), Ok(1.0));
+    }
+
+    #[test]
+    fn truncated_file_is_invalid() {
+        let path = std::env::temp_dir().join(format!("cartographer-truncated-{}.bin.gz",std::process::id()));
+        let buffer = Buffer { bytes: mesh(3, &[[0,1,2]]), ..Buffer::default() };
+        let dir = path.parent().unwrap().to_str().unwrap();
+        let name = format!("cartographer-truncated-{}",std::process::id());
+        buffer.save(dir, &name, 6);
+        let path = path.to_str().unwrap();
+        assert_eq!(validate_file(path, OutputKind::Terrain(ElevationFormat::Mesh), Endian::Le), Ok(()));
+
+        // cut short partway through the gzip stream
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::write(path, &bytes[..bytes.len() / 2]).unwrap();
+        let err = validate_file(path, OutputKind::Terrain(ElevationFormat::Mesh), Endian::Le).unwrap_err();
+        std::fs::remove_file(path).unwrap();
+        assert!(err.starts_with("bad gzip"), "{}", err);
+    }
+}
@@ -0,0 +1,23 @@
+use std::{path::PathBuf, process::Command};
+
+/// A fresh, empty working directory for one run.
+fn work_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cartographer-{}-{}",test,std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("input")).unwrap();
+    dir
+}
+
+#[test]
+fn fatal_error_exits_1() {
+    let dir = work_dir("exit-fatal");
+    // no input/missing.tif
+    let status = Command::new(env!("CARGO_BIN_EXE_cartographer"))
+        .args(["missing", "33", "--elevation"])
+        .current_dir(&dir)
+        .status()
+        .unwrap();
+
+    assert_eq!(status.code(), Some(1));
+    std::fs::remove_dir_all(&dir).unwrap();
+}